@@ -0,0 +1,97 @@
+//! Downloads a large file from test_resources over and over, with a few clients at once, and
+//! prints the throughput of each way of serving it. Run with `cargo bench`.
+
+extern crate rust_https_server;
+extern crate openssl;
+
+use rust_https_server::{ServerBuilder, ServerHandle, TlsConfig};
+use openssl::ssl::{SslConnectorBuilder, SslMethod, SSL_VERIFY_NONE};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROOT: &str = "test_resources/bench";
+const FILE: &str = "large.bin";
+const SIZE: usize = 256 * 1024 * 1024;
+const CLIENTS: usize = 4;
+const ROUNDS: usize = 4;
+
+fn main() {
+    let root = Path::new(ROOT);
+    create_file(&root.join(FILE)).unwrap();
+    let tls = || TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key");
+
+    run("event loop, plain", start(ServerBuilder::new(root)), false);
+    run("thread per connection, plain", start(ServerBuilder::new(root).thread_per_connection()), false);
+    run("event loop, TLS", start(ServerBuilder::new(root).tls(tls())), true);
+    run("thread per connection, TLS", start(ServerBuilder::new(root).tls(tls()).thread_per_connection()), true);
+}
+
+/// Writes the file once, and keeps it for later runs.
+fn create_file(path: &Path) -> io::Result<()> {
+    if fs::metadata(path).map(|metadata| metadata.len() == SIZE as u64).unwrap_or(false) {
+        return Ok(());
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+    let block: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let mut file = fs::File::create(path)?;
+    for _ in 0..SIZE / block.len() {
+        file.write_all(&block)?;
+    }
+    Ok(())
+}
+
+fn start(builder: ServerBuilder) -> ServerHandle {
+    builder.listen("127.0.0.1:0".parse().unwrap()).start().unwrap()
+}
+
+fn run(name: &str, handle: ServerHandle, tls: bool) {
+    let port = handle.port().unwrap();
+    // warms the page cache and the connection paths up
+    download(port, tls);
+    let started = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS).map(|_| thread::spawn(move || {
+        (0..ROUNDS).map(|_| download(port, tls)).sum::<u64>()
+    })).collect();
+    let bytes: u64 = clients.into_iter().map(|client| client.join().unwrap()).sum();
+    let elapsed = started.elapsed().as_secs_f64();
+    println!("{:<30} {:>8.1} MB/s", name, bytes as f64 / elapsed / 1e6);
+    handle.shutdown(Duration::from_secs(5));
+}
+
+/// Fetches the file and returns the size of its body.
+fn download(port: u16, tls: bool) -> u64 {
+    let request = format!("GET /{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", FILE);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let received = if tls {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+        builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+        let mut stream = builder.build().connect("localhost", stream).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        discard(stream)
+    } else {
+        let mut stream = stream;
+        stream.write_all(request.as_bytes()).unwrap();
+        discard(stream)
+    };
+    assert!(received >= SIZE as u64, "only {} bytes received", received);
+    SIZE as u64
+}
+
+/// Reads the response to the end, keeping none of it.
+fn discard<R: Read>(mut stream: R) -> u64 {
+    let mut buf = vec![0; 256 * 1024];
+    let mut received = 0;
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return received,
+            Ok(read) => received += read as u64,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            // a TLS client may see the connection closed without a close_notify
+            Err(_) => return received
+        }
+    }
+}
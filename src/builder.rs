@@ -0,0 +1,853 @@
+use std::env;
+use std::io;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use net2::TcpBuilder;
+use error::ServerError;
+use server;
+use server::{Options, Site};
+use server::content_manager::Handler;
+use server::control::{Control, Event};
+use server::locations::{Location, Locations};
+use server::file_cache::FileCache;
+use server::file_map::MappedFiles;
+use server::file_system::{FileHandle, FileSystemAdapter};
+use server::hosts::{HostName, VirtualHosts};
+use server::https::{Hsts, HttpsRedirect};
+use server::http::Limits;
+use server::listener::{Listener, UnixSocket};
+use server::log::{AccessLog, LogTarget};
+use server::pool::{Overload, PoolMetrics, PoolMonitor, ThreadPool};
+use tls::{ReloadableAcceptor, TlsConfig};
+#[cfg(unix)]
+use privileges;
+#[cfg(unix)]
+use systemd;
+#[cfg(unix)]
+use upgrade;
+
+pub struct ServerHandle {
+    /// Every address the server is listening on, in the order they were given.
+    pub addrs: Vec<SocketAddr>,
+    /// Every Unix socket the server is listening on.
+    pub unix_sockets: Vec<UnixSocket>,
+    pub handle: JoinHandle<()>,
+    /// The address of the plain HTTP listener redirecting to HTTPS, if there is one.
+    pub redirect_addr: Option<SocketAddr>,
+    redirect: Option<RedirectServer>,
+    events: Sender<Event<FileHandle>>,
+    tls: Mutex<Option<Arc<ReloadableAcceptor>>>,
+    log: Mutex<Arc<AccessLog>>,
+    systemd: bool,
+    /// Whether a new process took over, and is now the one systemd watches.
+    upgraded: AtomicBool,
+    control: Arc<Control>,
+    pool: PoolMonitor,
+    jail: Option<Jail>,
+    /// The listening sockets by the names they are handed over with in an upgrade.
+    #[cfg(unix)]
+    fds: Vec<(String, RawFd)>
+}
+
+/// The root directory a server was chrooted into, as named from outside.
+struct Jail {
+    root: PathBuf,
+    /// The working directory before the chroot, which relative paths were relative to.
+    started_in: PathBuf
+}
+
+impl Jail {
+    /// `path` as seen from inside, or an error when it is outside. Paths named after the chroot
+    /// can no longer be resolved, so symbolic links and `..` in them are not followed.
+    fn inside(&self, path: &Path) -> Result<PathBuf, ServerError> {
+        let path: PathBuf = self.started_in.join(path).components()
+            .filter(|component| *component != Component::CurDir)
+            .collect();
+        match path.strip_prefix(&self.root) {
+            Ok(relative) => Ok(Path::new("/").join(relative)),
+            Err(_) => Err(ServerError::Privileges(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{} is outside the chroot {}", path.display(), self.root.display()))))
+        }
+    }
+}
+
+/// The plain HTTP listener redirecting to HTTPS.
+struct RedirectServer {
+    handle: JoinHandle<()>,
+    events: Sender<Event<FileHandle>>,
+    redirect: Mutex<HttpsRedirect>
+}
+
+impl ServerHandle {
+    /// The port of the first address, for when the system picked it. None when the server
+    /// listens on Unix sockets only.
+    pub fn port(&self) -> Option<u16> {
+        self.addrs.first().map(|addr| addr.port())
+    }
+
+    /// Tells systemd the server is shutting down, when it was started with `systemd`. Not after an
+    /// upgrade, as the service carries on in the new process.
+    pub fn notify_stopping(&self) {
+        if self.systemd && !self.upgraded.load(Ordering::SeqCst) {
+            notify_systemd("STOPPING=1");
+        }
+    }
+
+    /// Stops accepting connections and gives the open ones up to `timeout` to finish their
+    /// requests, before cutting them off. `true` when they all finished in time.
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        self.notify_stopping();
+        let ServerHandle { handle, redirect, events, control, .. } = self;
+        control.stop();
+        let deadline = Instant::now() + timeout;
+        while control.open_connections() > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(50));
+        }
+        let drained = control.open_connections() == 0;
+        if !drained {
+            control.close_all();
+        }
+        drop(events);
+        let redirect_handle = redirect.map(|redirect| redirect.handle);
+        for handle in Some(handle).into_iter().chain(redirect_handle) {
+            if handle.join().is_err() {
+                eprintln!("Error in shutdown");
+            }
+        }
+        drained
+    }
+
+    /// Serves new connections with the sites, handlers, limits, timeouts, overload policy, access
+    /// log and certificates of `builder`. The addresses, the sockets, the thread counts, the
+    /// queue size, the certificate reload interval and the session ticket keys and their rotation
+    /// stay as they were, and TLS cannot be turned on or off. On failure nothing changes. After a
+    /// chroot, the roots are looked for inside it and other files can no longer be read, and the
+    /// access log stays the file open before the chroot.
+    pub fn reload(&self, builder: ServerBuilder) -> Result<(), ServerError> {
+        let builder = match self.jail {
+            Some(ref jail) => builder.jailed(jail)?,
+            None => builder
+        };
+        let mut tls = self.tls.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if builder.tls.is_some() != tls.is_some() {
+            return Err(ServerError::Tls("TLS cannot be turned on or off by a reload".to_string()));
+        }
+        let mut current_log = self.log.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let log = match self.jail {
+            Some(_) if builder.access_log == *current_log.target() => current_log.clone(),
+            Some(_) => return Err(ServerError::Privileges(io::Error::other("the access log cannot be changed after a chroot"))),
+            None => Arc::new(builder.open_log()?)
+        };
+        let acceptor = builder.load(tls.as_ref())?;
+        *current_log = log.clone();
+        let options = builder.options(log, self.control.clone());
+        if let Some(ref server) = self.redirect {
+            let mut redirect = server.redirect.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some((_, ref new_redirect)) = builder.http_redirect {
+                let (default_host, port) = redirect_target(&self.addrs);
+                *redirect = new_redirect.clone().to(&default_host, port);
+            }
+            let _ = server.events.send(Event::Reload(builder.redirect_sites(&redirect), options.clone(), None));
+        }
+        let _ = self.events.send(Event::Reload(builder.sites(), options, acceptor.clone()));
+        *tls = acceptor;
+        Ok(())
+    }
+
+    /// The load on the workers serving content, leaving out the redirect listener's.
+    pub fn pool_metrics(&self) -> PoolMetrics {
+        self.pool.metrics()
+    }
+
+    /// Opens the access log file again, for after it was rotated.
+    pub fn reopen_logs(&self) {
+        let _ = self.events.send(Event::ReopenLogs);
+    }
+
+    /// Starts a new copy of this program with the same arguments, handing it the listening
+    /// sockets, and waits up to `timeout` for it to be serving. The new copy must be built with
+    /// `upgrades`. Shutting this server down afterwards finishes the upgrade without refusing a
+    /// connection; if the new copy fails, this server carries on. Returns the new process id.
+    /// Under systemd, the new process is made the service's main process.
+    #[cfg(unix)]
+    pub fn upgrade(&self, timeout: Duration) -> Result<u32, ServerError> {
+        if self.jail.is_some() {
+            return Err(ServerError::Privileges(io::Error::other("a chrooted server cannot be upgraded")));
+        }
+        let pid = upgrade::spawn(&self.fds, timeout)?.id();
+        if self.systemd {
+            notify_systemd(&format!("MAINPID={}", pid));
+        }
+        self.upgraded.store(true, Ordering::SeqCst);
+        Ok(pid)
+    }
+
+    /// Reloads the TLS certificates and keys from their files for new connections. If they
+    /// fail to load, the error is returned and the current ones stay in service.
+    pub fn reload_tls(&self) -> Result<(), ServerError> {
+        let tls = self.tls.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+        match tls {
+            Some(ref acceptor) => acceptor.reload(),
+            None => Ok(())
+        }
+    }
+}
+
+/// Collects the settings for a server. Nothing is bound or loaded until `start`.
+pub struct ServerBuilder {
+    root: PathBuf,
+    addrs: Vec<SocketAddr>,
+    unix_sockets: Vec<UnixSocket>,
+    ipv6_only: Option<bool>,
+    systemd: bool,
+    upgrades: bool,
+    user: Option<String>,
+    group: Option<String>,
+    chroot: bool,
+    tls: Option<TlsConfig>,
+    tls_reload_interval: Option<Duration>,
+    http_redirect: Option<(SocketAddr, HttpsRedirect)>,
+    hsts: Option<Hsts>,
+    threads: usize,
+    max_threads: usize,
+    idle_timeout: Duration,
+    queue_size: usize,
+    overload: Overload,
+    thread_per_connection: bool,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    limits: Limits,
+    file_cache: Option<Arc<FileCache>>,
+    mapped_files: Option<Arc<MappedFiles>>,
+    handlers: Vec<Arc<dyn Handler>>,
+    locations: Vec<Location>,
+    virtual_hosts: Vec<VirtualHost>,
+    access_log: LogTarget
+}
+
+/// A site with its own root directory and handlers, chosen by the host name a request is for.
+#[derive(Clone)]
+pub struct VirtualHost {
+    names: Vec<String>,
+    root: PathBuf,
+    handlers: Vec<Arc<dyn Handler>>,
+    locations: Vec<Location>,
+    tls: Option<TlsConfig>
+}
+
+impl VirtualHost {
+    pub fn new<P: AsRef<Path>>(root: P) -> VirtualHost {
+        VirtualHost {
+            names: vec![],
+            root: root.as_ref().to_path_buf(),
+            handlers: vec![],
+            locations: vec![],
+            tls: None
+        }
+    }
+
+    /// Adds a host name to answer to, such as `example.com`, or `*.example.com` for any subdomain.
+    pub fn name(mut self, name: &str) -> VirtualHost {
+        self.names.push(name.to_string());
+        self
+    }
+
+    pub fn handler<T: Handler + 'static>(mut self, handler: T) -> VirtualHost {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    pub fn location(mut self, location: Location) -> VirtualHost {
+        self.locations.push(location);
+        self
+    }
+
+    /// A certificate for this host's names, offered to clients that ask for one of them by SNI.
+    /// Hosts without one must be covered by the server's certificate.
+    pub fn tls(mut self, config: TlsConfig) -> VirtualHost {
+        self.tls = Some(config);
+        self
+    }
+}
+
+impl ServerBuilder {
+    /// A plain HTTP server for `root` on 127.0.0.1:8080 with four worker threads, growing to 64
+    /// while requests wait, and room for 128 requests to wait for them.
+    pub fn new<P: AsRef<Path>>(root: P) -> ServerBuilder {
+        ServerBuilder {
+            root: root.as_ref().to_path_buf(),
+            addrs: vec![SocketAddr::from(([127, 0, 0, 1], 8080))],
+            unix_sockets: vec![],
+            ipv6_only: None,
+            systemd: false,
+            upgrades: false,
+            user: None,
+            group: None,
+            chroot: false,
+            tls: None,
+            tls_reload_interval: None,
+            http_redirect: None,
+            hsts: None,
+            threads: 4,
+            max_threads: 64,
+            idle_timeout: Duration::from_secs(60),
+            queue_size: 128,
+            overload: Overload::Block,
+            thread_per_connection: !cfg!(unix),
+            read_timeout: None,
+            write_timeout: None,
+            limits: Limits::default(),
+            file_cache: None,
+            mapped_files: None,
+            handlers: vec![],
+            locations: vec![],
+            virtual_hosts: vec![],
+            access_log: LogTarget::Off
+        }
+    }
+
+    /// Listens on `addr` alone, in place of any addresses and sockets given before.
+    pub fn listen(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.addrs = vec![addr];
+        self.unix_sockets.clear();
+        self
+    }
+
+    /// Listens on `addr` as well as the addresses given before.
+    pub fn also_listen(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Listens on the Unix socket alone, in place of any addresses and sockets given before.
+    pub fn listen_unix(mut self, socket: UnixSocket) -> ServerBuilder {
+        self.addrs.clear();
+        self.unix_sockets = vec![socket];
+        self
+    }
+
+    /// Listens on the Unix socket as well as the addresses and sockets given before.
+    pub fn also_listen_unix(mut self, socket: UnixSocket) -> ServerBuilder {
+        self.unix_sockets.push(socket);
+        self
+    }
+
+    /// Whether IPv6 listeners refuse IPv4 connections. Left to the system when not set; binding
+    /// `[::]` and `0.0.0.0` on the same port needs `true`.
+    pub fn ipv6_only(mut self, ipv6_only: bool) -> ServerBuilder {
+        self.ipv6_only = Some(ipv6_only);
+        self
+    }
+
+    /// Serves on the sockets systemd passed by socket activation instead of binding, when it
+    /// passed any. One named `redirect` with `FileDescriptorName=` is the plain HTTP listener of
+    /// `redirect_http`. systemd is also told when the server is ready, and kept from timing out
+    /// when `WatchdogSec=` is set. With `upgrades`, the service needs `NotifyAccess=all`: the new
+    /// process reports that it is ready before the old one hands it the main PID.
+    pub fn systemd(mut self) -> ServerBuilder {
+        self.systemd = true;
+        self
+    }
+
+    /// Takes over the listening sockets of the server upgrading to this one with
+    /// `ServerHandle::upgrade` instead of binding, and tells it once serving.
+    pub fn upgrades(mut self) -> ServerBuilder {
+        self.upgrades = true;
+        self
+    }
+
+    /// Switches to `user`, by name or id, once the listeners are bound and the certificates and
+    /// access log opened, so that binding port 443 is all root is needed for. Starting fails
+    /// when the switch does not succeed.
+    pub fn user(mut self, user: &str) -> ServerBuilder {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Switches to `group`, by name or id, in place of the user's own group.
+    pub fn group(mut self, group: &str) -> ServerBuilder {
+        self.group = Some(group.to_string());
+        self
+    }
+
+    /// Confines the server to its root directory once the listeners are bound and the
+    /// certificates and access log opened. The roots of virtual hosts must be inside it. Files
+    /// outside cannot be read again, so certificates are not reloaded, the access log cannot be
+    /// reopened and the server cannot be upgraded.
+    pub fn chroot(mut self) -> ServerBuilder {
+        self.chroot = true;
+        self
+    }
+
+    pub fn tls(mut self, config: TlsConfig) -> ServerBuilder {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Checks the certificate and key files this often and reloads them when they change.
+    pub fn tls_reload_interval(mut self, interval: Duration) -> ServerBuilder {
+        self.tls_reload_interval = Some(interval);
+        self
+    }
+
+    /// Also listens for plain HTTP on `addr`, redirecting requests to the TLS listener.
+    pub fn redirect_http(mut self, addr: SocketAddr, redirect: HttpsRedirect) -> ServerBuilder {
+        self.http_redirect = Some((addr, redirect));
+        self
+    }
+
+    /// Sends a `Strict-Transport-Security` header with every response of the TLS listener.
+    pub fn hsts(mut self, hsts: Hsts) -> ServerBuilder {
+        self.hsts = Some(hsts);
+        self
+    }
+
+    /// How many workers are always running.
+    pub fn threads(mut self, threads: usize) -> ServerBuilder {
+        self.threads = threads;
+        self
+    }
+
+    /// How many workers may run while requests wait for one, never fewer than `threads`. With a
+    /// thread per connection, each keep-alive connection holds on to a worker until it closes.
+    pub fn max_threads(mut self, threads: usize) -> ServerBuilder {
+        self.max_threads = threads;
+        self
+    }
+
+    /// How long workers above `threads` wait for a connection before they stop.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// How many requests, or accepted connections with a thread per connection, may wait for a worker.
+    pub fn queue_size(mut self, size: usize) -> ServerBuilder {
+        self.queue_size = size;
+        self
+    }
+
+    /// What happens to new requests once `queue_size` are waiting. Blocks by default.
+    pub fn overload(mut self, overload: Overload) -> ServerBuilder {
+        self.overload = overload;
+        self
+    }
+
+    /// Keeps small files in memory, along with their ETags and media types, up to `max_bytes` in
+    /// all across sites. A file is checked for changes at most once a second while it is served
+    /// from memory.
+    pub fn file_cache(mut self, max_bytes: usize) -> ServerBuilder {
+        self.file_cache = Some(Arc::new(FileCache::new(max_bytes)));
+        self
+    }
+
+    /// Serves files of at least `min_bytes` from memory maps, one per file shared by every request
+    /// for it, instead of reading them. Files found to have changed since they were mapped are
+    /// read after all. Unix only; ignored elsewhere.
+    pub fn mmap_threshold(mut self, min_bytes: u64) -> ServerBuilder {
+        self.mapped_files = Some(Arc::new(MappedFiles::new(min_bytes)));
+        self
+    }
+
+    /// Serves each connection on a worker of its own for as long as it stays open, rather than
+    /// from the event loop, which only takes a worker while a request is answered. Always the
+    /// case on platforms without the event loop.
+    pub fn thread_per_connection(mut self) -> ServerBuilder {
+        self.thread_per_connection = true;
+        self
+    }
+
+    /// How long a connection may sit idle, including between keep-alive requests.
+    pub fn read_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> ServerBuilder {
+        self.limits = limits;
+        self
+    }
+
+    /// Adds a handler to the default host. Handlers are consulted in the order they were added,
+    /// before the root directory.
+    pub fn handler<T: Handler + 'static>(mut self, handler: T) -> ServerBuilder {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// Adds per-path rules to the default host. They are checked before any other handler.
+    pub fn location(mut self, location: Location) -> ServerBuilder {
+        self.locations.push(location);
+        self
+    }
+
+    /// Serves `host` for requests naming one of its host names. Everything else goes to the
+    /// default host made of the builder's own root, handlers and locations.
+    pub fn virtual_host(mut self, host: VirtualHost) -> ServerBuilder {
+        self.virtual_hosts.push(host);
+        self
+    }
+
+    pub fn access_log(mut self, target: LogTarget) -> ServerBuilder {
+        self.access_log = target;
+        self
+    }
+
+    pub fn start(mut self) -> Result<ServerHandle, ServerError> {
+        let acceptor = self.load(None)?;
+        let log = self.open_log()?;
+
+        let mut activated = self.activated_listeners()?;
+        let activated_redirect = match self.http_redirect {
+            Some(_) => activated.iter().position(|(name, _)| name == "redirect").map(|i| activated.remove(i).1),
+            None => None
+        };
+        let mut listeners = vec![];
+        let mut addrs = vec![];
+        let mut unix_sockets = vec![];
+        if activated.is_empty() {
+            if self.addrs.is_empty() && self.unix_sockets.is_empty() {
+                return Err(ServerError::Io(io::Error::new(io::ErrorKind::InvalidInput, "No address to listen on")));
+            }
+            for &addr in &self.addrs {
+                let (listener, bound) = bind(addr, self.ipv6_only)?;
+                listeners.push(Listener::Tcp(listener));
+                addrs.push(bound);
+            }
+            for socket in &self.unix_sockets {
+                listeners.push(socket.bind().map_err(|e| ServerError::BindUnix(socket.to_string(), e))?);
+            }
+            unix_sockets = self.unix_sockets.clone();
+        } else {
+            for (_, listener) in activated {
+                match listener.local_addr()? {
+                    Ok(addr) => addrs.push(addr),
+                    Err(socket) => unix_sockets.push(socket)
+                }
+                listeners.push(listener);
+            }
+        }
+        let redirect_listener = match (activated_redirect, &self.http_redirect) {
+            (Some(listener), _) => match listener.local_addr()? {
+                Ok(addr) => Some((listener, addr)),
+                Err(socket) => {
+                    let e = io::Error::new(io::ErrorKind::InvalidInput, "a redirecting listener must be TCP");
+                    return Err(ServerError::BindUnix(socket.to_string(), e));
+                }
+            },
+            (None, &Some((addr, _))) => {
+                let (listener, bound) = bind(addr, self.ipv6_only)?;
+                Some((Listener::Tcp(listener), bound))
+            },
+            (None, &None) => None
+        };
+
+        if self.systemd {
+            connect_systemd();
+        }
+        let jail = self.drop_privileges()?;
+        let log = match jail {
+            Some(ref jail) => {
+                self = self.jailed(jail)?;
+                log.keep_open()
+            },
+            None => log
+        };
+
+        let log = Arc::new(log);
+        let options = self.options(log.clone(), Arc::new(Control::new()));
+        #[cfg(unix)]
+        let fds = listeners.iter().map(|listener| ("content".to_string(), listener.as_raw_fd()))
+            .chain(redirect_listener.as_ref().map(|(listener, _)| ("redirect".to_string(), listener.as_raw_fd())))
+            .collect();
+        let (redirect_addr, redirect) = match (redirect_listener, &self.http_redirect) {
+            (Some((redirect_listener, redirect_addr)), &Some((_, ref redirect))) => {
+                let (default_host, port) = redirect_target(&addrs);
+                let redirect = redirect.clone().to(&default_host, port);
+                let hosts = self.redirect_sites(&redirect);
+                let options = options.clone();
+                let (handle, events) = self.serve(vec![redirect_listener], self.pool(), hosts, options, None);
+                (Some(redirect_addr), Some(RedirectServer { handle, events, redirect: Mutex::new(redirect) }))
+            },
+            _ => (None, None)
+        };
+
+        let hosts = self.sites();
+        let control = options.control.clone();
+        let tls = Mutex::new(acceptor.clone());
+        let pool = self.pool();
+        let monitor = pool.monitor();
+        let (handle, events) = self.serve(listeners, pool, hosts, options, acceptor);
+        if self.systemd {
+            notify_systemd("READY=1");
+            #[cfg(unix)]
+            systemd::start_watchdog();
+        }
+        #[cfg(unix)]
+        {
+            if self.upgrades {
+                if let Err(e) = upgrade::notify_ready() {
+                    eprintln!("Error telling the previous server it can stop:{}", e);
+                }
+            }
+        }
+        Ok(ServerHandle {
+            addrs, unix_sockets, handle, redirect_addr, redirect, events, tls, log: Mutex::new(log), systemd: self.systemd,
+            upgraded: AtomicBool::new(false), control, pool: monitor, jail,
+            #[cfg(unix)]
+            fds
+        })
+    }
+
+    /// Checks the sizes and roots and loads the certificates: everything but the access log that
+    /// can fail before a server is started or reloaded. A reload passes the `current` acceptor,
+    /// which takes the new certificates last, so the threads watching its files and rotating its
+    /// ticket keys carry on; a start creates the acceptor and those threads.
+    fn load(&self, current: Option<&Arc<ReloadableAcceptor>>) -> Result<Option<Arc<ReloadableAcceptor>>, ServerError> {
+        let sizes = [
+            ("threads", self.threads),
+            ("max_threads", self.max_threads),
+            ("queue_size", self.queue_size),
+            ("file_cache", self.file_cache.as_ref().map_or(1, |cache| cache.max_bytes()))
+        ];
+        if let Some(&(name, _)) = sizes.iter().find(|&&(_, size)| size == 0) {
+            return Err(ServerError::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("{} must be at least 1", name))));
+        }
+        if !self.root.exists() {
+            return Err(ServerError::RootNotFound(self.root.clone()));
+        }
+        if let Some(host) = self.virtual_hosts.iter().find(|host| !host.root.exists()) {
+            return Err(ServerError::RootNotFound(host.root.clone()));
+        }
+        let acceptor = match self.tls {
+            Some(ref config) => {
+                let hosts = self.virtual_hosts.iter()
+                    .map(|host| (host.names.clone(), host.tls.clone()))
+                    .collect();
+                if let Some(acceptor) = current {
+                    acceptor.replace(config.clone(), hosts)?;
+                    return Ok(Some(acceptor.clone()));
+                }
+                let acceptor = Arc::new(ReloadableAcceptor::new(config.clone(), hosts)?);
+                if let Some(interval) = self.tls_reload_interval {
+                    ReloadableAcceptor::watch(&acceptor, interval);
+                }
+                ReloadableAcceptor::rotate_tickets(&acceptor);
+                Some(acceptor)
+            },
+            None => {
+                if let Some(host) = self.virtual_hosts.iter().find(|host| host.tls.is_some()) {
+                    let name = host.names.first().map(|name| name.as_str()).unwrap_or("");
+                    return Err(ServerError::Tls(format!("Host {} has a cert but the server does not use TLS", name)));
+                }
+                if self.http_redirect.is_some() || self.hsts.is_some() {
+                    return Err(ServerError::Tls("Redirecting to HTTPS and HSTS need the server to use TLS".to_string()));
+                }
+                None
+            }
+        };
+        Ok(acceptor)
+    }
+
+    fn open_log(&self) -> Result<AccessLog, ServerError> {
+        AccessLog::open(self.access_log.clone()).map_err(|e| match self.access_log {
+            LogTarget::File(ref path) => ServerError::Log(path.clone(), e),
+            _ => ServerError::Io(e)
+        })
+    }
+
+    fn options(&self, log: Arc<AccessLog>, control: Arc<Control>) -> Options {
+        Options {
+            overload: self.overload,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            limits: self.limits,
+            log,
+            control
+        }
+    }
+
+    /// Serves `listeners` on a thread of their own, returning it with where to send it events.
+    fn serve(&self, listeners: Vec<Listener>, pool: ThreadPool, hosts: VirtualHosts<FileHandle>, options: Options,
+             acceptor: Option<Arc<ReloadableAcceptor>>) -> (JoinHandle<()>, Sender<Event<FileHandle>>) {
+        let (events, received) = mpsc::channel();
+        #[cfg(unix)]
+        {
+            if !self.thread_per_connection {
+                let handle = spawn(move || server::event_loop::serve(listeners, pool, received, hosts, options, acceptor));
+                return (handle, events);
+            }
+        }
+        let sender = events.clone();
+        let handle = spawn(move || server::serve(listeners, pool, sender, received, hosts, options, acceptor));
+        (handle, events)
+    }
+
+    fn pool(&self) -> ThreadPool {
+        ThreadPool::elastic(self.threads, self.max_threads.max(self.threads), self.queue_size, self.idle_timeout)
+    }
+
+    /// The sites of the listeners serving content.
+    fn sites(&self) -> VirtualHosts<FileHandle> {
+        let hsts = self.hsts.clone().map(|hsts| -> Arc<dyn Handler> { Arc::new(hsts) });
+        virtual_hosts(&self.root, &self.handlers, &self.locations, &self.virtual_hosts, self.file_cache.as_ref(), self.mapped_files.as_ref(), |site| {
+            site.handlers.extend(hsts.clone());
+        })
+    }
+
+    /// The sites of the plain HTTP listener, where `redirect` comes before any other handler.
+    fn redirect_sites(&self, redirect: &HttpsRedirect) -> VirtualHosts<FileHandle> {
+        let redirect: Arc<dyn Handler> = Arc::new(redirect.clone());
+        virtual_hosts(&self.root, &self.handlers, &self.locations, &self.virtual_hosts, self.file_cache.as_ref(), self.mapped_files.as_ref(), |site| {
+            site.handlers.insert(0, redirect.clone());
+        })
+    }
+
+    /// Chroots and switches user and group as asked, returning where to when chrooted.
+    #[cfg(unix)]
+    fn drop_privileges(&mut self) -> Result<Option<Jail>, ServerError> {
+        let ids = privileges::lookup(self.user.as_deref(), self.group.as_deref()).map_err(ServerError::Privileges)?;
+        let jail = if self.chroot {
+            self.root = self.root.canonicalize()?;
+            for host in &mut self.virtual_hosts {
+                host.root = host.root.canonicalize()?;
+            }
+            let jail = Jail { root: self.root.clone(), started_in: env::current_dir()? };
+            privileges::chroot(&jail.root).map_err(ServerError::Privileges)?;
+            Some(jail)
+        } else {
+            None
+        };
+        privileges::switch_to(ids).map_err(ServerError::Privileges)?;
+        Ok(jail)
+    }
+
+    #[cfg(not(unix))]
+    fn drop_privileges(&mut self) -> Result<Option<Jail>, ServerError> {
+        if self.user.is_some() || self.group.is_some() || self.chroot {
+            return Err(ServerError::Privileges(io::Error::other("dropping privileges is only supported on Unix")));
+        }
+        Ok(None)
+    }
+
+    /// The same settings with the roots as seen from inside `jail`.
+    fn jailed(mut self, jail: &Jail) -> Result<ServerBuilder, ServerError> {
+        self.root = jail.inside(&self.root)?;
+        for host in &mut self.virtual_hosts {
+            host.root = jail.inside(&host.root)?;
+        }
+        Ok(self)
+    }
+
+    /// The listening sockets handed over in an upgrade, or else by systemd, when enabled. Notes
+    /// where this program was started from for the next upgrade.
+    #[cfg(unix)]
+    fn activated_listeners(&self) -> Result<Vec<(String, Listener)>, ServerError> {
+        if self.upgrades {
+            upgrade::remember_executable();
+        }
+        let inherited = if self.upgrades { upgrade::inherited_listeners()? } else { vec![] };
+        if inherited.is_empty() && self.systemd {
+            Ok(systemd::listen_fds()?)
+        } else {
+            Ok(inherited)
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn activated_listeners(&self) -> Result<Vec<(String, Listener)>, ServerError> {
+        Ok(vec![])
+    }
+}
+
+/// Failing to notify is reported but does not stop the server.
+#[cfg(unix)]
+fn notify_systemd(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        eprintln!("Error notifying systemd of {}:{}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify_systemd(_state: &str) {}
+
+/// Connects to systemd's socket ahead of time, so that it can still be notified after a chroot.
+#[cfg(unix)]
+fn connect_systemd() {
+    if let Err(e) = systemd::connect() {
+        eprintln!("Error connecting to systemd:{}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn connect_systemd() {}
+
+/// The host and port the redirect listener sends requests to: the first address serving content.
+fn redirect_target(addrs: &[SocketAddr]) -> (String, u16) {
+    match addrs.first() {
+        Some(&SocketAddr::V4(addr)) => (addr.ip().to_string(), addr.port()),
+        Some(&SocketAddr::V6(addr)) => (format!("[{}]", addr.ip()), addr.port()),
+        None => ("localhost".to_string(), 443)
+    }
+}
+
+/// Binds `addr`, returning the listener with the address it really got. IPv6 only is set before
+/// binding, as it has to be.
+fn bind(addr: SocketAddr, ipv6_only: Option<bool>) -> Result<(TcpListener, SocketAddr), ServerError> {
+    let listener = match (addr, ipv6_only) {
+        (SocketAddr::V6(_), Some(ipv6_only)) => TcpBuilder::new_v6()
+            .and_then(|builder| {
+                builder.only_v6(ipv6_only)?;
+                builder.reuse_address(true)?;
+                builder.bind(addr)?;
+                builder.listen(128)
+            }),
+        _ => TcpListener::bind(addr)
+    }.map_err(|e| ServerError::Bind(addr, e))?;
+    let bound = listener.local_addr().map_err(|e| ServerError::Bind(addr, e))?;
+    Ok((listener, bound))
+}
+
+/// The default site and one per virtual host, each passed through `adjust` once built.
+fn virtual_hosts<F>(root: &Path, handlers: &[Arc<dyn Handler>], locations: &[Location], virtual_hosts: &[VirtualHost], cache: Option<&Arc<FileCache>>, mapped: Option<&Arc<MappedFiles>>, adjust: F) -> VirtualHosts<FileHandle>
+    where F: Fn(&mut Site<FileHandle>) {
+    let mut default = site(root, handlers.to_vec(), locations.to_vec(), cache, mapped);
+    adjust(&mut default);
+    let mut hosts = VirtualHosts::new(default);
+    for host in virtual_hosts {
+        let names = host.names.iter().map(|name| HostName::parse(name)).collect();
+        let mut site = site(&host.root, host.handlers.clone(), host.locations.clone(), cache, mapped);
+        adjust(&mut site);
+        hosts.add(names, site);
+    }
+    hosts
+}
+
+fn site(root: &Path, mut handlers: Vec<Arc<dyn Handler>>, locations: Vec<Location>, cache: Option<&Arc<FileCache>>, mapped: Option<&Arc<MappedFiles>>) -> Site<FileHandle> {
+    if !locations.is_empty() {
+        handlers.insert(0, Arc::new(Locations::new(locations)));
+    }
+    let mut content = FileSystemAdapter::new(root);
+    if let Some(cache) = cache {
+        content = content.cache(cache.clone());
+    }
+    if let Some(mapped) = mapped {
+        content = content.map_large_files(mapped.clone());
+    }
+    Site {
+        content: Box::new(content),
+        handlers
+    }
+}
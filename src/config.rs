@@ -0,0 +1,1210 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml;
+use toml::Spanned;
+use builder::{ServerBuilder, VirtualHost};
+use server::http::Limits;
+use server::https::{Hsts, HttpsRedirect, RedirectStatus};
+use server::listener::UnixSocket;
+use server::locations::Location;
+use server::log::LogTarget;
+use server::pool::Overload;
+use tls::{create_acceptor, ClientAuth, ClientAuthMode, HostTls, TlsConfig, TlsProfile, TlsVersion};
+
+/// A problem found in a configuration file, with the line it was found on when known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub message: String
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message)
+        }
+    }
+}
+
+/// A validated configuration file. Relative paths are resolved against the file's directory.
+pub struct Config {
+    root: Option<PathBuf>,
+    listen: Vec<SocketAddr>,
+    unix_sockets: Vec<UnixSocket>,
+    ipv6_only: Option<bool>,
+    user: Option<String>,
+    group: Option<String>,
+    chroot: Option<bool>,
+    tls: Option<TlsConfig>,
+    tls_reload_interval: Option<Duration>,
+    http_redirect: Option<(SocketAddr, HttpsRedirect)>,
+    hsts: Option<Hsts>,
+    threads: Option<usize>,
+    max_threads: Option<usize>,
+    idle_timeout: Option<Duration>,
+    queue_size: Option<usize>,
+    overload: Option<Overload>,
+    file_cache: Option<usize>,
+    mmap_threshold: Option<u64>,
+    access_log: Option<LogTarget>,
+    limits: Limits,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    locations: Vec<Location>,
+    hosts: Vec<VirtualHost>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    root: Option<Spanned<String>>,
+    threads: Option<Spanned<i64>>,
+    /// How many workers may run while connections wait for one.
+    max_threads: Option<Spanned<i64>>,
+    /// Seconds workers above `threads` wait for a connection before they stop.
+    idle_timeout: Option<u64>,
+    /// How many connections may wait for a worker.
+    queue_size: Option<Spanned<i64>>,
+    /// `block`, `reject` or `shed-oldest`, for when the queue is full.
+    overload: Option<Spanned<String>>,
+    /// Seconds rejected clients are asked to wait.
+    retry_after: Option<Spanned<u64>>,
+    /// Bytes of small files to keep in memory.
+    file_cache: Option<Spanned<i64>>,
+    /// Bytes from which files are served from memory maps.
+    mmap_threshold: Option<u64>,
+    access_log: Option<String>,
+    /// Whether IPv6 listeners refuse IPv4 connections; left to the system when not set.
+    ipv6_only: Option<bool>,
+    /// The user and group to switch to after binding, by name or id.
+    user: Option<String>,
+    group: Option<String>,
+    /// Whether to chroot into the root directory after binding.
+    chroot: Option<bool>,
+    #[serde(default)]
+    listener: Vec<RawListener>,
+    tls: Option<RawTls>,
+    limits: Option<RawLimits>,
+    #[serde(default)]
+    location: Vec<RawLocation>,
+    #[serde(default)]
+    host: Vec<RawHost>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawListener {
+    /// An IP address and port, or `unix:/path` or `unix:@name` for a Unix socket.
+    address: Spanned<String>,
+    /// Octal permission bits of a Unix socket file, such as "660".
+    mode: Option<Spanned<String>>,
+    #[serde(default)]
+    tls: bool,
+    /// Makes this a plain HTTP listener redirecting to the TLS one.
+    redirect: Option<RawHttpsRedirect>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHttpsRedirect {
+    /// 301 or 308; 301 when left out.
+    status: Option<Spanned<u16>>,
+    /// Path prefixes served over plain HTTP instead.
+    #[serde(default)]
+    except: Vec<String>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHost {
+    names: Spanned<Vec<String>>,
+    root: Spanned<String>,
+    cert: Option<Spanned<String>>,
+    key: Option<Spanned<String>>,
+    pkcs12: Option<Spanned<String>>,
+    passphrase_file: Option<Spanned<String>>,
+    ocsp: Option<Spanned<String>>,
+    client_auth: Option<RawClientAuth>,
+    #[serde(default)]
+    location: Vec<RawLocation>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTls {
+    cert: Option<Spanned<String>>,
+    key: Option<Spanned<String>>,
+    pkcs12: Option<Spanned<String>>,
+    passphrase_file: Option<Spanned<String>>,
+    /// A DER encoded OCSP response to staple.
+    ocsp: Option<Spanned<String>>,
+    client_auth: Option<RawClientAuth>,
+    /// `modern` or `intermediate`.
+    profile: Option<Spanned<String>>,
+    ciphers: Option<Spanned<String>>,
+    /// `1.0` to `1.3`.
+    min_version: Option<Spanned<String>>,
+    curves: Option<Spanned<String>>,
+    /// Seconds between session ticket key rotations. Tickets are off when left out.
+    ticket_rotation: Option<Spanned<u64>>,
+    session_cache_size: Option<usize>,
+    /// Seconds a cached session can be resumed for.
+    session_timeout: Option<u64>,
+    /// Seconds between checks of the certificate and key files for changes.
+    reload_interval: Option<Spanned<u64>>,
+    hsts: Option<RawHsts>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHsts {
+    /// Seconds browsers hold on to the policy.
+    max_age: u64,
+    #[serde(default)]
+    include_subdomains: bool,
+    #[serde(default)]
+    preload: bool
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawClientAuth {
+    /// `required` or `optional`; required when left out.
+    mode: Option<Spanned<String>>,
+    ca: Spanned<String>,
+    crl: Option<Spanned<String>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLimits {
+    max_request_line: Option<usize>,
+    max_header_bytes: Option<usize>,
+    max_headers: Option<usize>,
+    read_timeout: Option<u64>,
+    write_timeout: Option<u64>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLocation {
+    path: Spanned<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    cache: Option<String>,
+    auth: Option<RawAuth>,
+    redirect: Option<RawRedirect>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAuth {
+    realm: String,
+    users: Spanned<BTreeMap<String, String>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRedirect {
+    to: String,
+    status: Option<Spanned<u16>>
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Vec<ConfigError>> {
+        let path = path.as_ref();
+        let mut source = String::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+            return Err(vec![ConfigError { line: None, message: format!("Error reading {}:{}", path.display(), e) }]);
+        }
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        Config::parse(&source, base_dir)
+    }
+
+    pub fn parse(source: &str, base_dir: &Path) -> Result<Config, Vec<ConfigError>> {
+        let raw: RawConfig = toml::from_str(source).map_err(|e| {
+            vec![ConfigError { line: e.line_col().map(|(line, _)| line + 1), message: e.to_string() }]
+        })?;
+        let mut validator = Validator { source, base_dir, errors: vec![] };
+        let config = validator.validate(raw);
+        if validator.errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(validator.errors)
+        }
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// The addresses serving content, empty when the file has no listener for them.
+    pub fn listen(&self) -> &[SocketAddr] {
+        &self.listen
+    }
+
+    /// The Unix sockets serving content.
+    pub fn unix_sockets(&self) -> &[UnixSocket] {
+        &self.unix_sockets
+    }
+
+    /// Applies everything the file sets to `builder`. Settings the file leaves out keep the builder's values.
+    pub fn configure(&self, mut builder: ServerBuilder) -> ServerBuilder {
+        for (i, &addr) in self.listen.iter().enumerate() {
+            builder = if i == 0 { builder.listen(addr) } else { builder.also_listen(addr) };
+        }
+        for (i, socket) in self.unix_sockets.iter().enumerate() {
+            builder = if i == 0 && self.listen.is_empty() {
+                builder.listen_unix(socket.clone())
+            } else {
+                builder.also_listen_unix(socket.clone())
+            };
+        }
+        if let Some(ipv6_only) = self.ipv6_only {
+            builder = builder.ipv6_only(ipv6_only);
+        }
+        if let Some(ref user) = self.user {
+            builder = builder.user(user);
+        }
+        if let Some(ref group) = self.group {
+            builder = builder.group(group);
+        }
+        if self.chroot == Some(true) {
+            builder = builder.chroot();
+        }
+        if let Some(ref tls) = self.tls {
+            builder = builder.tls(tls.clone());
+        }
+        if let Some(interval) = self.tls_reload_interval {
+            builder = builder.tls_reload_interval(interval);
+        }
+        if let Some((addr, ref redirect)) = self.http_redirect {
+            builder = builder.redirect_http(addr, redirect.clone());
+        }
+        if let Some(ref hsts) = self.hsts {
+            builder = builder.hsts(hsts.clone());
+        }
+        if let Some(threads) = self.threads {
+            builder = builder.threads(threads);
+        }
+        if let Some(threads) = self.max_threads {
+            builder = builder.max_threads(threads);
+        }
+        if let Some(timeout) = self.idle_timeout {
+            builder = builder.idle_timeout(timeout);
+        }
+        if let Some(size) = self.queue_size {
+            builder = builder.queue_size(size);
+        }
+        if let Some(overload) = self.overload {
+            builder = builder.overload(overload);
+        }
+        if let Some(bytes) = self.file_cache {
+            builder = builder.file_cache(bytes);
+        }
+        if let Some(bytes) = self.mmap_threshold {
+            builder = builder.mmap_threshold(bytes);
+        }
+        if let Some(ref target) = self.access_log {
+            builder = builder.access_log(target.clone());
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = self.write_timeout {
+            builder = builder.write_timeout(timeout);
+        }
+        builder = builder.limits(self.limits);
+        for location in &self.locations {
+            builder = builder.location(location.clone());
+        }
+        for host in &self.hosts {
+            builder = builder.virtual_host(host.clone());
+        }
+        builder
+    }
+}
+
+struct Validator<'a> {
+    source: &'a str,
+    base_dir: &'a Path,
+    errors: Vec<ConfigError>
+}
+
+impl<'a> Validator<'a> {
+    fn error<T>(&mut self, spanned: &Spanned<T>, message: String) {
+        let line = self.source[..spanned.start()].matches('\n').count() + 1;
+        self.errors.push(ConfigError { line: Some(line), message });
+    }
+
+    fn path(&self, value: &str) -> PathBuf {
+        self.base_dir.join(value)
+    }
+
+    fn existing_path(&mut self, value: &Spanned<String>, what: &str) -> PathBuf {
+        let path = self.path(value.get_ref());
+        if !path.exists() {
+            self.error(value, format!("{} does not exist: {}", what, path.display()));
+        }
+        path
+    }
+
+    fn validate(&mut self, raw: RawConfig) -> Config {
+        let root = raw.root.as_ref().map(|root| self.existing_path(root, "Root directory"));
+
+        let threads = self.count(raw.threads.as_ref(), "threads");
+        let max_threads = self.count(raw.max_threads.as_ref(), "max_threads");
+        if let (Some(threads), Some(max), Some(raw_max)) = (threads, max_threads, raw.max_threads.as_ref()) {
+            if max < threads {
+                self.error(raw_max, "max_threads must be at least threads".to_string());
+            }
+        }
+        let idle_timeout = raw.idle_timeout.map(Duration::from_secs);
+        let queue_size = self.count(raw.queue_size.as_ref(), "queue_size");
+        let overload = self.overload(raw.overload.as_ref(), raw.retry_after.as_ref());
+        let file_cache = self.count(raw.file_cache.as_ref(), "file_cache");
+        let mmap_threshold = raw.mmap_threshold;
+
+        let access_log = raw.access_log.map(|log| match log.as_str() {
+            "-" => LogTarget::Stdout,
+            "off" => LogTarget::Off,
+            path => LogTarget::File(self.path(path))
+        });
+
+        let tls = raw.tls.as_ref().and_then(|tls| self.tls(tls));
+        let tls_reload_interval = match raw.tls.as_ref().and_then(|tls| tls.reload_interval.as_ref()) {
+            Some(interval) if *interval.get_ref() == 0 => {
+                self.error(interval, "reload_interval must be at least 1 second".to_string());
+                None
+            },
+            Some(interval) => Some(Duration::from_secs(*interval.get_ref())),
+            None => None
+        };
+        let (listen, unix_sockets, http_redirect) = self.listeners(&raw.listener, raw.tls.is_some());
+        let hsts = raw.tls.as_ref().and_then(|tls| tls.hsts.as_ref()).map(|raw| {
+            let mut hsts = Hsts::new(Duration::from_secs(raw.max_age));
+            if raw.include_subdomains {
+                hsts = hsts.include_subdomains();
+            }
+            if raw.preload {
+                hsts = hsts.preload();
+            }
+            hsts
+        });
+
+        let mut limits = Limits::default();
+        let (mut read_timeout, mut write_timeout) = (None, None);
+        if let Some(raw_limits) = raw.limits {
+            limits.max_request_line = raw_limits.max_request_line.unwrap_or(limits.max_request_line);
+            limits.max_header_bytes = raw_limits.max_header_bytes.unwrap_or(limits.max_header_bytes);
+            limits.max_headers = raw_limits.max_headers.unwrap_or(limits.max_headers);
+            read_timeout = raw_limits.read_timeout.map(Duration::from_secs);
+            write_timeout = raw_limits.write_timeout.map(Duration::from_secs);
+        }
+
+        let locations = raw.location.iter().map(|location| self.location(location)).collect();
+        let host_tls: Vec<Option<TlsConfig>> = raw.host.iter().map(|host| self.host_tls(host)).collect();
+        let hosts = raw.host.iter().zip(&host_tls).map(|(host, config)| self.host(host, config.clone())).collect();
+        match tls {
+            Some(ref tls) => self.host_certificates(tls, &raw.host, &host_tls),
+            None if raw.tls.is_none() => {
+                for cert in raw.host.iter().filter_map(|host| host.cert.as_ref().or(host.pkcs12.as_ref())) {
+                    self.error(cert, "host has a cert but there is no [tls] section".to_string());
+                }
+            },
+            None => {}
+        }
+
+        let RawConfig { ipv6_only, user, group, chroot, .. } = raw;
+        Config { root, listen, unix_sockets, ipv6_only, user, group, chroot, tls, tls_reload_interval, http_redirect, hsts, threads, max_threads, idle_timeout, queue_size, overload, file_cache, mmap_threshold, access_log, limits, read_timeout, write_timeout, locations, hosts }
+    }
+
+    /// A number of things that there must be at least one of.
+    fn count(&mut self, value: Option<&Spanned<i64>>, name: &str) -> Option<usize> {
+        match value {
+            Some(value) if *value.get_ref() < 1 => {
+                self.error(value, format!("{} must be at least 1", name));
+                None
+            },
+            Some(value) => Some(*value.get_ref() as usize),
+            None => None
+        }
+    }
+
+    fn overload(&mut self, overload: Option<&Spanned<String>>, retry_after: Option<&Spanned<u64>>) -> Option<Overload> {
+        let retry = retry_after.map(|seconds| Duration::from_secs(*seconds.get_ref()));
+        let parsed = overload.and_then(|name| match Overload::parse(name.get_ref(), retry) {
+            Some(overload) => Some(overload),
+            None => {
+                self.error(name, format!("Unknown overload policy: {}", name.get_ref()));
+                None
+            }
+        });
+        if let Some(retry_after) = retry_after {
+            if overload.map(|name| name.get_ref().as_str()) != Some("reject") {
+                self.error(retry_after, "retry_after needs overload = \"reject\"".to_string());
+            }
+        }
+        parsed
+    }
+
+    fn host(&mut self, raw: &RawHost, tls: Option<TlsConfig>) -> VirtualHost {
+        let mut host = VirtualHost::new(self.existing_path(&raw.root, "Root directory"));
+        if let Some(tls) = tls {
+            host = host.tls(tls);
+        }
+        if raw.names.get_ref().is_empty() {
+            self.error(&raw.names, "host needs at least one name".to_string());
+        }
+        for name in raw.names.get_ref() {
+            let pattern = name.strip_prefix("*.").unwrap_or(name);
+            if name.is_empty() || pattern.contains('*') || pattern.contains(':') || pattern.contains('/') {
+                self.error(&raw.names, format!("invalid host name {}", name));
+            }
+            host = host.name(name);
+        }
+        for location in &raw.location {
+            host = host.location(self.location(location));
+        }
+        host
+    }
+
+    /// `None` when the host has no certificate of its own or its files are missing.
+    fn host_tls(&mut self, raw: &RawHost) -> Option<TlsConfig> {
+        if raw.cert.is_none() && raw.key.is_none() && raw.pkcs12.is_none() {
+            return None;
+        }
+        let config = self.certificate(raw.cert.as_ref(), raw.key.as_ref(), raw.pkcs12.as_ref(), raw.passphrase_file.as_ref())?;
+        let config = self.with_ocsp(config, raw.ocsp.as_ref())?;
+        self.with_client_auth(config, raw.client_auth.as_ref())
+    }
+
+    /// Either a PEM cert and key, or a PKCS#12 archive, each optionally with a passphrase file.
+    /// `None` when they are missing or conflicting; the errors have been recorded.
+    fn certificate(&mut self, cert: Option<&Spanned<String>>, key: Option<&Spanned<String>>,
+                   pkcs12: Option<&Spanned<String>>, passphrase_file: Option<&Spanned<String>>) -> Option<TlsConfig> {
+        let config = match (cert, key, pkcs12) {
+            (Some(cert), Some(key), None) => {
+                let cert = self.existing_path(cert, "Certificate");
+                let key = self.existing_path(key, "Private key");
+                TlsConfig::new(cert, key)
+            },
+            (None, None, Some(pkcs12)) => TlsConfig::pkcs12(self.existing_path(pkcs12, "PKCS#12 archive")),
+            (_, _, Some(pkcs12)) => {
+                self.error(pkcs12, "pkcs12 cannot be combined with cert or key".to_string());
+                return None;
+            },
+            (Some(cert), None, None) => {
+                self.error(cert, "cert is set without a key".to_string());
+                return None;
+            },
+            (None, Some(key), None) => {
+                self.error(key, "key is set without a cert".to_string());
+                return None;
+            },
+            (None, None, None) => {
+                self.errors.push(ConfigError { line: None, message: "[tls] needs a cert and key, or a pkcs12 archive".to_string() });
+                return None;
+            }
+        };
+        let config = match passphrase_file {
+            Some(passphrase_file) => config.passphrase_file(self.existing_path(passphrase_file, "Passphrase file")),
+            None => config
+        };
+        if config.files().iter().all(|file| file.exists()) {
+            Some(config)
+        } else {
+            None
+        }
+    }
+
+    /// `None` when the OCSP response file is missing; the error has been recorded.
+    fn with_ocsp(&mut self, config: TlsConfig, ocsp: Option<&Spanned<String>>) -> Option<TlsConfig> {
+        match ocsp {
+            Some(ocsp) => {
+                let path = self.existing_path(ocsp, "OCSP response");
+                if path.exists() {
+                    Some(config.ocsp_response(path))
+                } else {
+                    None
+                }
+            },
+            None => Some(config)
+        }
+    }
+
+    /// `None` when the client auth settings are invalid; the errors have been recorded.
+    fn with_client_auth(&mut self, config: TlsConfig, raw: Option<&RawClientAuth>) -> Option<TlsConfig> {
+        let raw = match raw {
+            Some(raw) => raw,
+            None => return Some(config)
+        };
+        let mode = match raw.mode.as_ref().map(|mode| (mode, mode.get_ref().as_str())) {
+            None | Some((_, "required")) => ClientAuthMode::Required,
+            Some((_, "optional")) => ClientAuthMode::Optional,
+            Some((mode, other)) => {
+                self.error(mode, format!("client_auth mode must be required or optional, not {}", other));
+                return None;
+            }
+        };
+        let mut client_auth = ClientAuth::new(mode, self.existing_path(&raw.ca, "Client CA file"));
+        if let Some(ref crl) = raw.crl {
+            client_auth = client_auth.crl_file(self.existing_path(crl, "CRL file"));
+        }
+        let config = config.client_auth(client_auth);
+        if config.files().iter().all(|file| file.exists()) {
+            Some(config)
+        } else {
+            None
+        }
+    }
+
+    /// Checks that every host name is covered by the host's own certificate, or else by the
+    /// default one or another host's.
+    fn host_certificates(&mut self, tls: &TlsConfig, raw_hosts: &[RawHost], host_tls: &[Option<TlsConfig>]) {
+        let mut with_certs = vec![];
+        for (raw, config) in raw_hosts.iter().zip(host_tls) {
+            if let Some(ref config) = *config {
+                let host = HostTls { names: raw.names.get_ref(), config: Some(config) };
+                match create_acceptor(tls, &[host]) {
+                    Ok(_) => with_certs.push(host),
+                    Err(e) => self.error(&raw.names, e.to_string())
+                }
+            }
+        }
+        for raw in raw_hosts.iter().zip(host_tls).filter(|&(_, config)| config.is_none()).map(|(raw, _)| raw) {
+            let mut hosts = with_certs.clone();
+            hosts.push(HostTls { names: raw.names.get_ref(), config: None });
+            if let Err(e) = create_acceptor(tls, &hosts) {
+                self.error(&raw.names, e.to_string());
+            }
+        }
+    }
+
+    /// The addresses and Unix sockets serving content, and the address redirecting to them over
+    /// plain HTTP if any.
+    fn listeners(&mut self, listeners: &[RawListener], has_tls: bool) -> (Vec<SocketAddr>, Vec<UnixSocket>, Option<(SocketAddr, HttpsRedirect)>) {
+        let (redirects, content): (Vec<&RawListener>, Vec<&RawListener>) = listeners.iter().partition(|listener| listener.redirect.is_some());
+        if redirects.len() > 1 {
+            self.error(&redirects[1].address, "only one redirecting listener is supported".to_string());
+        }
+
+        let mut listen = vec![];
+        let mut unix_sockets = vec![];
+        for listener in content {
+            if listener.tls && !has_tls {
+                self.error(&listener.address, "listener uses TLS but there is no [tls] section".to_string());
+            }
+            if !listener.tls && has_tls {
+                self.error(&listener.address, "[tls] is configured but the listener does not set tls = true".to_string());
+            }
+            match UnixSocket::from_address(listener.address.get_ref()) {
+                Some(socket) => unix_sockets.push(self.unix_socket(socket, listener.mode.as_ref())),
+                None => {
+                    if let Some(ref mode) = listener.mode {
+                        self.error(mode, "mode is only for Unix socket listeners".to_string());
+                    }
+                    listen.extend(self.address(listener));
+                }
+            }
+        }
+        let http_redirect = redirects.first().and_then(|listener| {
+            if listener.tls {
+                self.error(&listener.address, "a redirecting listener cannot use TLS".to_string());
+            }
+            if !has_tls {
+                self.error(&listener.address, "redirecting to HTTPS needs a [tls] section".to_string());
+            }
+            if UnixSocket::from_address(listener.address.get_ref()).is_some() {
+                self.error(&listener.address, "a redirecting listener cannot be a Unix socket".to_string());
+                return None;
+            }
+            let raw = listener.redirect.as_ref()?;
+            let mut redirect = HttpsRedirect::new();
+            if let Some(ref status) = raw.status {
+                match *status.get_ref() {
+                    301 => redirect = redirect.status(RedirectStatus::MovedPermanently),
+                    308 => redirect = redirect.status(RedirectStatus::PermanentRedirect),
+                    other => self.error(status, format!("redirect status must be 301 or 308, not {}", other))
+                }
+            }
+            for prefix in &raw.except {
+                if !prefix.starts_with('/') {
+                    self.error(&listener.address, format!("redirect exception must start with /: {}", prefix));
+                }
+                redirect = redirect.except(prefix);
+            }
+            self.address(listener).map(|addr| (addr, redirect))
+        });
+        (listen, unix_sockets, http_redirect)
+    }
+
+    /// Resolves a socket file against the file's directory and applies the octal `mode`.
+    fn unix_socket(&mut self, socket: UnixSocket, mode: Option<&Spanned<String>>) -> UnixSocket {
+        let mut socket = match socket.file() {
+            Some(path) => UnixSocket::path(self.base_dir.join(path)),
+            None => socket
+        };
+        if let Some(mode) = mode {
+            match u32::from_str_radix(mode.get_ref(), 8) {
+                Ok(_) if socket.file().is_none() => self.error(mode, "an abstract socket has no file mode".to_string()),
+                Ok(bits) if bits <= 0o777 => socket = socket.mode(bits),
+                _ => self.error(mode, format!("mode must be octal permission bits such as \"660\", not {}", mode.get_ref()))
+            }
+        }
+        socket
+    }
+
+    fn address(&mut self, listener: &RawListener) -> Option<SocketAddr> {
+        match listener.address.get_ref().parse::<SocketAddr>() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                let message = format!("invalid listener address {}:{}", listener.address.get_ref(), e);
+                self.error(&listener.address, message);
+                None
+            }
+        }
+    }
+
+    /// `None` when the files are missing or unusable; the errors have been recorded.
+    fn tls(&mut self, tls: &RawTls) -> Option<TlsConfig> {
+        let config = self.certificate(tls.cert.as_ref(), tls.key.as_ref(), tls.pkcs12.as_ref(), tls.passphrase_file.as_ref())?;
+        let config = self.with_ocsp(config, tls.ocsp.as_ref())?;
+        let config = self.with_client_auth(config, tls.client_auth.as_ref())?;
+        let config = self.protocol(config, tls)?;
+        match create_acceptor(&config, &[]) {
+            Ok(_) => Some(config),
+            Err(e) => {
+                let message = e.to_string();
+                let at = if message.contains("cipher list") {
+                    tls.ciphers.as_ref()
+                } else if message.contains("curve list") {
+                    tls.curves.as_ref()
+                } else if message.contains("OCSP response") {
+                    tls.ocsp.as_ref()
+                } else {
+                    tls.cert.as_ref().or(tls.pkcs12.as_ref())
+                };
+                match at {
+                    Some(at) => self.error(at, message),
+                    None => self.errors.push(ConfigError { line: None, message })
+                }
+                None
+            }
+        }
+    }
+
+    /// `None` when a protocol setting is invalid; the errors have been recorded.
+    fn protocol(&mut self, mut config: TlsConfig, tls: &RawTls) -> Option<TlsConfig> {
+        let mut valid = true;
+        if let Some(ref profile) = tls.profile {
+            match profile.get_ref().as_str() {
+                "modern" => config = config.profile(TlsProfile::Modern),
+                "intermediate" => config = config.profile(TlsProfile::Intermediate),
+                other => {
+                    self.error(profile, format!("profile must be modern or intermediate, not {}", other));
+                    valid = false;
+                }
+            }
+        }
+        if let Some(ref min_version) = tls.min_version {
+            let version = match min_version.get_ref().as_str() {
+                "1.0" => Some(TlsVersion::Tls10),
+                "1.1" => Some(TlsVersion::Tls11),
+                "1.2" => Some(TlsVersion::Tls12),
+                "1.3" => Some(TlsVersion::Tls13),
+                _ => None
+            };
+            match version {
+                Some(version) => config = config.min_version(version),
+                None => {
+                    self.error(min_version, format!("min_version must be one of 1.0, 1.1, 1.2 or 1.3, not {}", min_version.get_ref()));
+                    valid = false;
+                }
+            }
+        }
+        if let Some(ref ciphers) = tls.ciphers {
+            config = config.ciphers(ciphers.get_ref());
+        }
+        if let Some(ref curves) = tls.curves {
+            config = config.curves(curves.get_ref());
+        }
+        match tls.ticket_rotation {
+            Some(ref rotation) if *rotation.get_ref() == 0 => {
+                self.error(rotation, "ticket_rotation must be at least 1 second".to_string());
+                valid = false;
+            },
+            Some(ref rotation) => config = config.session_tickets(Duration::from_secs(*rotation.get_ref())),
+            None => {}
+        }
+        if tls.session_cache_size.is_some() || tls.session_timeout.is_some() {
+            config = config.session_cache(tls.session_cache_size.unwrap_or(20480), Duration::from_secs(tls.session_timeout.unwrap_or(300)));
+        }
+        if valid {
+            Some(config)
+        } else {
+            None
+        }
+    }
+
+    fn location(&mut self, raw: &RawLocation) -> Location {
+        if !raw.path.get_ref().starts_with('/') {
+            self.error(&raw.path, format!("location path must start with /: {}", raw.path.get_ref()));
+        }
+        let mut location = Location::new(raw.path.get_ref());
+        for (name, value) in &raw.headers {
+            location = location.header(name, value);
+        }
+        if let Some(ref cache) = raw.cache {
+            location = location.cache_control(cache);
+        }
+        if let Some(ref auth) = raw.auth {
+            let mut users = vec![];
+            for (user, digest) in auth.users.get_ref() {
+                match parse_digest(digest) {
+                    Some(digest) => users.push((user.clone(), digest)),
+                    None => self.error(&auth.users, format!("password for {} is not a hex SHA-256 digest", user))
+                }
+            }
+            location = location.basic_auth(&auth.realm, users);
+        }
+        if let Some(ref redirect) = raw.redirect {
+            let status = match redirect.status {
+                Some(ref status) => {
+                    if ![301, 302, 303, 307, 308].contains(status.get_ref()) {
+                        self.error(status, format!("{} is not a redirect status", status.get_ref()));
+                    }
+                    *status.get_ref()
+                },
+                None => 301
+            };
+            location = location.redirect(status, &redirect.to);
+        }
+        location
+    }
+}
+
+fn parse_digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError};
+    use server::listener::UnixSocket;
+    use server::pool::Overload;
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn errors(source: &str) -> Vec<ConfigError> {
+        match Config::parse(source, Path::new(".")) {
+            Ok(_) => vec![],
+            Err(errors) => errors
+        }
+    }
+
+    #[test]
+    fn parses_full_config() {
+        let config = Config::parse(r#"
+root = "test_resources"
+threads = 8
+access_log = "-"
+
+[[listener]]
+address = "127.0.0.1:8080"
+
+[limits]
+max_headers = 20
+read_timeout = 30
+
+[[location]]
+path = "/private/"
+headers = { X-Frame-Options = "DENY" }
+cache = "no-store"
+auth = { realm = "Private", users = { alice = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b" } }
+"#, Path::new(".")).ok().unwrap();
+
+        assert_eq!(Some(Path::new("./test_resources")), config.root());
+        assert_eq!(&["127.0.0.1:8080".parse::<SocketAddr>().unwrap()], config.listen());
+        assert_eq!(Some(8), config.threads);
+        assert_eq!(20, config.limits.max_headers);
+        assert_eq!(1, config.locations.len());
+    }
+
+    #[test]
+    fn parses_virtual_hosts() {
+        let config = Config::parse(r#"
+root = "test_resources"
+
+[[host]]
+names = ["example.com", "*.example.com"]
+root = "test_resources"
+
+[[host.location]]
+path = "/"
+cache = "no-cache"
+
+[[host]]
+names = ["*.*.example.org", "ok.example.org"]
+root = "test_resources"
+"#, Path::new("."));
+
+        match config {
+            Err(errors) => {
+                assert_eq!(1, errors.len());
+                assert_eq!(Some(13), errors[0].line);
+            },
+            Ok(_) => panic!("Invalid host name accepted")
+        }
+    }
+
+    #[test]
+    fn checks_host_certificates() {
+        let errors = errors(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/localhost.pem"
+key = "test_resources/tls/localhost.key"
+
+[[host]]
+names = ["example.com", "www.example.com"]
+root = "test_resources"
+cert = "test_resources/tls/example.pem"
+key = "test_resources/tls/example.key"
+
+[[host]]
+names = ["blog.example.com"]
+root = "test_resources"
+
+[[host]]
+names = ["vhost.test"]
+root = "test_resources"
+"#);
+
+        assert_eq!(1, errors.len());
+        assert_eq!(Some(19), errors[0].line);
+        assert!(errors[0].message.contains("vhost.test"));
+    }
+
+    #[test]
+    fn reads_pkcs12_archives_with_passphrase() {
+        let config = Config::parse(r#"
+root = "test_resources"
+
+[tls]
+pkcs12 = "test_resources/tls/chain.p12"
+passphrase_file = "test_resources/tls/chain.pass"
+"#, Path::new("."));
+        assert!(config.is_ok());
+
+        let errors = errors(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/chain.pem"
+pkcs12 = "test_resources/tls/chain.p12"
+"#);
+        assert_eq!(1, errors.len());
+        assert_eq!(Some(6), errors[0].line);
+    }
+
+    #[test]
+    fn reads_client_auth() {
+        let config = Config::parse(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/localhost.pem"
+key = "test_resources/tls/localhost.key"
+client_auth = { mode = "optional", ca = "test_resources/tls/client-ca.pem", crl = "test_resources/tls/client-ca.crl" }
+"#, Path::new("."));
+        assert!(config.is_ok());
+
+        let errors = errors(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/localhost.pem"
+key = "test_resources/tls/localhost.key"
+
+[tls.client_auth]
+mode = "sometimes"
+ca = "test_resources/tls/client-ca.pem"
+"#);
+        assert_eq!(1, errors.len());
+        assert_eq!(Some(9), errors[0].line);
+    }
+
+    #[test]
+    fn reads_several_listeners() {
+        let config = Config::parse(r#"
+root = "test_resources"
+ipv6_only = true
+
+[[listener]]
+address = "0.0.0.0:8080"
+
+[[listener]]
+address = "[::]:8080"
+"#, Path::new(".")).ok().unwrap();
+        let listen: Vec<SocketAddr> = vec!["0.0.0.0:8080".parse().unwrap(), "[::]:8080".parse().unwrap()];
+        assert_eq!(&listen[..], config.listen());
+        assert_eq!(Some(true), config.ipv6_only);
+
+        let errors = errors(r#"
+root = "test_resources"
+
+[[listener]]
+address = "127.0.0.1:8080"
+
+[[listener]]
+address = "::1:8080"
+"#);
+        assert_eq!(1, errors.len());
+        assert_eq!(Some(8), errors[0].line);
+    }
+
+    #[test]
+    fn reads_unix_socket_listeners() {
+        let config = Config::parse(r#"
+root = "test_resources"
+
+[[listener]]
+address = "unix:run/http.sock"
+mode = "660"
+
+[[listener]]
+address = "unix:@http"
+"#, Path::new(".")).ok().unwrap();
+        assert!(config.listen().is_empty());
+        assert_eq!(&[UnixSocket::path("./run/http.sock").mode(0o660), UnixSocket::abstract_name("http")], config.unix_sockets());
+
+        let errors = errors(r#"
+root = "test_resources"
+
+[[listener]]
+address = "unix:/run/http.sock"
+mode = "rw-rw----"
+
+[[listener]]
+address = "127.0.0.1:8080"
+mode = "660"
+"#);
+        let lines: Vec<Option<usize>> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(vec![Some(6), Some(10)], lines);
+    }
+
+    #[test]
+    fn reads_redirecting_listener_and_hsts() {
+        let config = Config::parse(r#"
+root = "test_resources"
+
+[[listener]]
+address = "127.0.0.1:8443"
+tls = true
+
+[[listener]]
+address = "127.0.0.1:8080"
+redirect = { status = 308, except = ["/.well-known/acme-challenge/"] }
+
+[tls]
+cert = "test_resources/tls/localhost.pem"
+key = "test_resources/tls/localhost.key"
+hsts = { max_age = 31536000, include_subdomains = true }
+"#, Path::new(".")).ok().unwrap();
+        assert_eq!(&["127.0.0.1:8443".parse::<SocketAddr>().unwrap()], config.listen());
+        assert_eq!(Some("127.0.0.1:8080".parse().unwrap()), config.http_redirect.as_ref().map(|&(addr, _)| addr));
+        assert!(config.hsts.is_some());
+
+        let errors = errors(r#"
+root = "test_resources"
+
+[[listener]]
+address = "127.0.0.1:8080"
+redirect = { status = 302 }
+"#);
+        let lines: Vec<Option<usize>> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(vec![Some(5), Some(6)], lines);
+    }
+
+    #[test]
+    fn reads_ocsp_responses() {
+        let config = Config::parse(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/chain.pem"
+key = "test_resources/tls/chain.key"
+passphrase_file = "test_resources/tls/chain.pass"
+ocsp = "test_resources/tls/chain.ocsp"
+"#, Path::new("."));
+        assert!(config.is_ok());
+
+        let errors = errors(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/chain.pem"
+key = "test_resources/tls/chain.key"
+passphrase_file = "test_resources/tls/chain.pass"
+ocsp = "test_resources/tls/other.ocsp"
+"#);
+        assert_eq!(vec![Some(8)], errors.iter().map(|e| e.line).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reads_protocol_settings() {
+        let config = Config::parse(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/localhost.pem"
+key = "test_resources/tls/localhost.key"
+profile = "modern"
+min_version = "1.2"
+curves = "X25519:P-256"
+ticket_rotation = 3600
+session_cache_size = 1000
+"#, Path::new("."));
+        assert!(config.is_ok());
+
+        let version = errors(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/localhost.pem"
+key = "test_resources/tls/localhost.key"
+min_version = "1.4"
+"#);
+        assert_eq!(vec![Some(7)], version.iter().map(|e| e.line).collect::<Vec<_>>());
+
+        let ciphers = errors(r#"
+root = "test_resources"
+
+[tls]
+cert = "test_resources/tls/localhost.pem"
+key = "test_resources/tls/localhost.key"
+ciphers = "NO-SUCH-CIPHER"
+"#);
+        assert_eq!(vec![Some(7)], ciphers.iter().map(|e| e.line).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_line() {
+        let errors = errors("root = \"test_resources\"\nthreads = \n");
+
+        assert_eq!(1, errors.len());
+        assert_eq!(Some(2), errors[0].line);
+    }
+
+    #[test]
+    fn reports_unknown_keys() {
+        let errors = errors("root = \"test_resources\"\nthread = 4\n");
+
+        assert_eq!(1, errors.len());
+        assert!(errors[0].message.contains("thread"));
+    }
+
+    #[test]
+    fn reports_every_invalid_value_with_its_line() {
+        let errors = errors(r#"root = "no_such_directory"
+
+[[listener]]
+address = "localhost:http"
+
+[[location]]
+path = "private"
+redirect = { to = "/", status = 200 }
+"#);
+
+        let lines: Vec<Option<usize>> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(vec![Some(1), Some(4), Some(7), Some(8)], lines);
+    }
+
+    #[test]
+    fn reads_privileges_to_drop() {
+        let config = Config::parse(r#"
+root = "test_resources"
+user = "www-data"
+group = "33"
+chroot = true
+"#, Path::new(".")).ok().unwrap();
+        assert_eq!(Some("www-data"), config.user.as_deref());
+        assert_eq!(Some("33"), config.group.as_deref());
+        assert_eq!(Some(true), config.chroot);
+    }
+
+    #[test]
+    fn reads_the_overload_policy() {
+        let config = Config::parse(r#"
+root = "test_resources"
+queue_size = 32
+overload = "reject"
+retry_after = 10
+"#, Path::new(".")).ok().unwrap();
+        assert_eq!(Some(32), config.queue_size);
+        assert_eq!(Some(Overload::Reject(Duration::from_secs(10))), config.overload);
+
+        let errors = errors(r#"
+root = "test_resources"
+queue_size = 0
+overload = "drop"
+retry_after = 10
+"#);
+        let lines: Vec<Option<usize>> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(vec![Some(3), Some(4), Some(5)], lines);
+    }
+
+    #[test]
+    fn reads_the_file_cache_and_mmap_sizes() {
+        let config = Config::parse(r#"
+root = "test_resources"
+file_cache = 16777216
+mmap_threshold = 1048576
+"#, Path::new(".")).ok().unwrap();
+        assert_eq!(Some(16 * 1024 * 1024), config.file_cache);
+        assert_eq!(Some(1024 * 1024), config.mmap_threshold);
+
+        let errors = errors(r#"
+root = "test_resources"
+file_cache = 0
+"#);
+        let lines: Vec<Option<usize>> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(vec![Some(3)], lines);
+    }
+
+    #[test]
+    fn reads_the_worker_limits() {
+        let config = Config::parse(r#"
+root = "test_resources"
+threads = 2
+max_threads = 16
+idle_timeout = 30
+"#, Path::new(".")).ok().unwrap();
+        assert_eq!((Some(2), Some(16)), (config.threads, config.max_threads));
+        assert_eq!(Some(Duration::from_secs(30)), config.idle_timeout);
+
+        let errors = errors(r#"
+root = "test_resources"
+threads = 8
+max_threads = 4
+"#);
+        assert_eq!(1, errors.len());
+        assert_eq!(Some(4), errors[0].line);
+    }
+}
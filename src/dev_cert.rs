@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MSB_MAYBE_ZERO};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid;
+use openssl::pkey::PKey;
+use openssl::x509::{X509, X509Builder, X509NameBuilder, X509NameRef};
+use openssl::x509::extension::{AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage,
+                               SubjectAlternativeName, SubjectKeyIdentifier};
+use error::ServerError;
+use tls::TlsConfig;
+
+/// A throwaway CA and a certificate it issued, for serving HTTPS during development. Clients
+/// connect without warnings only if they are told to trust the CA.
+pub struct DevCertificates {
+    /// The CA certificate in PEM.
+    pub ca_cert: Vec<u8>,
+    /// The certificate for the names in PEM.
+    pub cert: Vec<u8>,
+    /// The certificate's private key in PEM.
+    pub key: Vec<u8>
+}
+
+impl DevCertificates {
+    /// Issues a certificate for `names`, which may be host names or IP addresses. The first name
+    /// is also its common name. Both certificates are valid for a year.
+    pub fn generate(names: &[&str]) -> Result<DevCertificates, ServerError> {
+        if names.is_empty() {
+            return Err(ServerError::Tls("A development certificate needs at least one name".to_string()));
+        }
+        issue(names).map_err(|e| ServerError::Tls(format!("Error generating development certificate:{}", e)))
+    }
+
+    /// A config serving the certificate straight from memory.
+    pub fn tls_config(&self) -> TlsConfig {
+        TlsConfig::from_pem(&self.cert, &self.key)
+    }
+}
+
+const VALID_DAYS: u32 = 365;
+
+fn issue(names: &[&str]) -> Result<DevCertificates, ErrorStack> {
+    let ca_key = generate_key()?;
+    let mut ca = certificate_builder("rust-http2-server development CA", None, &ca_key)?;
+    ca.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    ca.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+    let key_id = SubjectKeyIdentifier::new().build(&ca.x509v3_context(None, None))?;
+    ca.append_extension(key_id)?;
+    ca.sign(&ca_key, MessageDigest::sha256())?;
+    let ca = ca.build();
+
+    let key = generate_key()?;
+    let mut cert = certificate_builder(names[0], Some(ca.subject_name()), &key)?;
+    cert.append_extension(BasicConstraints::new().critical().build()?)?;
+    cert.append_extension(KeyUsage::new().critical().digital_signature().key_encipherment().build()?)?;
+    cert.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+    let alt_names = {
+        let mut alt_names = SubjectAlternativeName::new();
+        for name in names {
+            match name.parse::<IpAddr>() {
+                Ok(_) => alt_names.ip(name),
+                Err(_) => alt_names.dns(name)
+            };
+        }
+        alt_names.build(&cert.x509v3_context(Some(&ca), None))?
+    };
+    cert.append_extension(alt_names)?;
+    let key_id = SubjectKeyIdentifier::new().build(&cert.x509v3_context(Some(&ca), None))?;
+    cert.append_extension(key_id)?;
+    let authority_key_id = AuthorityKeyIdentifier::new().keyid(true).build(&cert.x509v3_context(Some(&ca), None))?;
+    cert.append_extension(authority_key_id)?;
+    cert.sign(&ca_key, MessageDigest::sha256())?;
+    let cert = cert.build();
+
+    Ok(DevCertificates {
+        ca_cert: ca.to_pem()?,
+        cert: cert.to_pem()?,
+        key: key.private_key_to_pem()?
+    })
+}
+
+fn generate_key() -> Result<PKey, ErrorStack> {
+    let group = EcGroup::from_curve_name(nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// A version 3 certificate for `common_name` with a random serial and a year of validity, still
+/// to be given its extensions and signature. Without an issuer it is self-issued.
+fn certificate_builder(common_name: &str, issuer: Option<&X509NameRef>, key: &PKey) -> Result<X509Builder, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(nid::COMMONNAME, common_name)?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MSB_MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(VALID_DAYS)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(issuer.unwrap_or(&name))?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DevCertificates;
+    use openssl::x509::X509;
+
+    #[test]
+    fn addresses_become_ip_alt_names() {
+        let certificates = DevCertificates::generate(&["localhost", "127.0.0.1", "::1"]).ok().unwrap();
+        let cert = X509::from_pem(&certificates.cert).unwrap();
+        let alt_names = cert.subject_alt_names().unwrap();
+        let dns: Vec<&str> = alt_names.iter().filter_map(|name| name.dnsname()).collect();
+        let ips: Vec<&[u8]> = alt_names.iter().filter_map(|name| name.ipaddress()).collect();
+        assert_eq!(vec!["localhost"], dns);
+        assert_eq!(vec![&[127, 0, 0, 1][..], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1][..]], ips);
+    }
+}
@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Everything that can stop a server from starting.
+#[derive(Debug)]
+pub enum ServerError {
+    RootNotFound(PathBuf),
+    Bind(SocketAddr, io::Error),
+    /// Binding a Unix socket, named as in `unix:/path` or `unix:@name`.
+    BindUnix(String, io::Error),
+    Tls(String),
+    Log(PathBuf, io::Error),
+    /// Chrooting or switching user and group after binding.
+    Privileges(io::Error),
+    Io(io::Error)
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::RootNotFound(ref path) => write!(f, "Root path does not exist: {}", path.display()),
+            ServerError::Bind(ref addr, ref e) => write!(f, "Error on bind to {}:{}", addr, e),
+            ServerError::BindUnix(ref socket, ref e) => write!(f, "Error on bind to {}:{}", socket, e),
+            ServerError::Tls(ref message) => write!(f, "TLS configuration error:{}", message),
+            ServerError::Log(ref path, ref e) => write!(f, "Error opening log {}:{}", path.display(), e),
+            ServerError::Privileges(ref e) => write!(f, "Error dropping privileges:{}", e),
+            ServerError::Io(ref e) => write!(f, "I/O error:{}", e)
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ServerError::Bind(_, ref e) | ServerError::BindUnix(_, ref e) | ServerError::Log(_, ref e) | ServerError::Privileges(ref e) | ServerError::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> ServerError {
+        ServerError::Io(e)
+    }
+}
@@ -1,57 +1,509 @@
-use clap::{Arg, App};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 extern crate clap;
+#[cfg(unix)]
+extern crate libc;
 extern crate rust_https_server;
 
-use rust_https_server::start_server;
+use rust_https_server::{Config, DevCertificates, Hsts, HttpsRedirect, LogTarget, Overload, ServerBuilder, ServerHandle, TlsConfig, UnixSocket};
 
 fn main() {
     println!("start");
     let matches = App::new("http-server")
         .version("1.0")
         .about("A simple web server")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("address")
             .short("a")
             .value_name("BIND_ADDRESS")
+            .help("Address to listen on, or unix:PATH or unix:@NAME for a Unix socket. May be given more than once")
             .default_value("127.0.0.1")
+            .multiple(true)
+            .number_of_values(1)
             .takes_value(true))
+        .arg(Arg::with_name("ipv6_only")
+            .long("ipv6-only")
+            .help("Keeps IPv6 listeners from accepting IPv4 connections, so [::] and 0.0.0.0 can share a port"))
         .arg(Arg::with_name("port")
             .short("p")
             .value_name("PORT_NUMBER")
             .default_value("8080")
             .takes_value(true))
+        .arg(Arg::with_name("socket_mode")
+            .long("socket-mode").value_name("OCTAL_MODE")
+            .help("Permissions of Unix socket files, such as 660")
+            .takes_value(true))
+        .arg(Arg::with_name("user")
+            .long("user").value_name("USER")
+            .help("User to switch to once listening, by name or id")
+            .takes_value(true))
+        .arg(Arg::with_name("group")
+            .long("group").value_name("GROUP")
+            .help("Group to switch to once listening, instead of the user's own")
+            .takes_value(true))
+        .arg(Arg::with_name("chroot")
+            .long("chroot")
+            .help("Confines the server to the root directory once listening"))
         .arg(Arg::with_name("cert")
             .long("cert").value_name("CERT_FILE")
             .takes_value(true))
         .arg(Arg::with_name("pk")
             .long("pk").value_name("PRIVATE_KEY_FILE")
             .takes_value(true))
+        .arg(Arg::with_name("pkcs12")
+            .long("pkcs12").value_name("PKCS12_FILE")
+            .help("PKCS#12 archive with the private key and certificates, instead of --cert and --pk")
+            .conflicts_with_all(&["cert", "pk"])
+            .takes_value(true))
+        .arg(Arg::with_name("dev_tls")
+            .long("dev-tls")
+            .help("Serves HTTPS with a certificate for localhost generated at startup")
+            .conflicts_with_all(&["cert", "pk", "pkcs12"]))
+        .arg(Arg::with_name("passphrase_file")
+            .long("passphrase-file").value_name("PASSPHRASE_FILE")
+            .help("Passphrase of an encrypted private key or PKCS#12 archive")
+            .takes_value(true))
+        .arg(Arg::with_name("ocsp_response")
+            .long("ocsp-response").value_name("OCSP_FILE")
+            .help("DER encoded OCSP response to staple to handshakes")
+            .takes_value(true))
+        .arg(Arg::with_name("redirect_port")
+            .long("redirect-port").value_name("PORT_NUMBER")
+            .help("Also listens for plain HTTP on this port, redirecting to HTTPS")
+            .takes_value(true))
+        .arg(Arg::with_name("hsts")
+            .long("hsts").value_name("MAX_AGE_SECONDS")
+            .help("Sends Strict-Transport-Security with HTTPS responses")
+            .takes_value(true))
+        .arg(Arg::with_name("threads")
+            .short("t")
+            .long("threads").value_name("THREAD_COUNT")
+            .default_value("4")
+            .takes_value(true))
+        .arg(Arg::with_name("max_threads")
+            .long("max-threads").value_name("THREAD_COUNT")
+            .help("How many workers may run while connections wait for one [default: 64]")
+            .takes_value(true))
+        .arg(Arg::with_name("idle_timeout")
+            .long("idle-timeout").value_name("SECONDS")
+            .help("How long workers beyond --threads wait for a connection before they stop [default: 60]")
+            .takes_value(true))
+        .arg(Arg::with_name("queue_size")
+            .long("queue-size").value_name("CONNECTIONS")
+            .help("How many connections may wait for a worker [default: 128]")
+            .takes_value(true))
+        .arg(Arg::with_name("overload")
+            .long("overload").value_name("POLICY")
+            .help("What to do with new connections when the queue is full [default: block]")
+            .possible_values(&["block", "reject", "shed-oldest"])
+            .takes_value(true))
+        .arg(Arg::with_name("retry_after")
+            .long("retry-after").value_name("SECONDS")
+            .help("Retry-After of 503 responses to rejected connections [default: 5]")
+            .requires("overload")
+            .takes_value(true))
+        .arg(Arg::with_name("file_cache")
+            .long("file-cache").value_name("BYTES")
+            .help("Keeps small files in memory, up to this many bytes in all")
+            .takes_value(true))
+        .arg(Arg::with_name("mmap_threshold")
+            .long("mmap-threshold").value_name("BYTES")
+            .help("Serves files of at least this many bytes from memory maps")
+            .takes_value(true))
+        .arg(Arg::with_name("access_log")
+            .long("access-log").value_name("LOG_FILE")
+            .help("Access log file, or - for stdout")
+            .takes_value(true))
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config").value_name("CONFIG_FILE")
+            .help("TOML configuration file. Command line options override its values")
+            .takes_value(true))
+        .arg(Arg::with_name("check_config")
+            .long("check-config")
+            .requires("config")
+            .help("Validates the configuration file and exits"))
         .arg(Arg::with_name("root_directory")
-            .required(true)
+            .required_unless("config")
             .value_name("ROOT_DIRECTORY"))
+        .subcommand(SubCommand::with_name("gen-cert")
+            .about("Writes a development CA and a certificate it issued to ca.pem, cert.pem and key.pem")
+            .arg(Arg::with_name("out")
+                .long("out").value_name("DIRECTORY")
+                .default_value(".")
+                .takes_value(true))
+            .arg(Arg::with_name("names")
+                .value_name("NAME")
+                .help("Host names and IP addresses to issue the certificate for. Defaults to localhost, 127.0.0.1 and ::1")
+                .multiple(true)))
         .get_matches();
 
-    let cert_matches = (matches.value_of("cert"), matches.value_of("pk"));
-    let address = matches.value_of("address").unwrap();
-    let port = matches.value_of("port").unwrap();
-    let root_directory = matches.value_of("root_directory");
+    if let Some(gen_cert_matches) = matches.subcommand_matches("gen-cert") {
+        match gen_cert(gen_cert_matches) {
+            Ok(()) => return,
+            Err(e) => exit_with(&e)
+        }
+    }
 
-    let cert_info = match cert_matches {
-        (Some(cert_path),Some(private_key_path)) => Some((cert_path, private_key_path)),
-        (Some(_), None) => panic!("If the cert is specified, so must the private key"),
-        (None, Some(_)) => panic!("If the private key is specified, so must the cert"),
-        (None, None) => None
+    let config = match matches.value_of("config") {
+        Some(path) => match Config::load(path) {
+            Ok(config) => Some(config),
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("{}: {}", path, error);
+                }
+                process::exit(1)
+            }
+        },
+        None => None
     };
-    match root_directory {
-        Some(root_dir) => {
-            match start_server(root_dir, address, port, cert_info) {
+    if matches.is_present("check_config") {
+        println!("{}: configuration OK", matches.value_of("config").unwrap());
+        return;
+    }
+
+    match build_server(&matches, config) {
+        Ok(builder) => {
+            #[cfg(unix)]
+            block_signals(SIGNALS);
+            match builder.start() {
                 Ok(handle) => {
-                    println!("listening on {}:{}", handle.ip, handle.port);
-                    handle.handle.join().expect("Join failed");
+                    for addr in &handle.addrs {
+                        println!("listening on {}", addr);
+                    }
+                    for socket in &handle.unix_sockets {
+                        println!("listening on {}", socket);
+                    }
+                    if let Some(addr) = handle.redirect_addr {
+                        println!("redirecting to HTTPS from {}", addr);
+                    }
+                    run(handle, &matches);
                 },
-                Err(e) => eprintln!("{}", e)
+                Err(e) => exit_with(&e.to_string())
             }
         },
-        None => eprintln!("The root directory is required")
+        Err(e) => exit_with(&e)
+    }
+}
+
+/// How long a live upgrade waits for the new process to serve, and how long open connections
+/// are given to finish when stopping.
+#[cfg(unix)]
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(unix)]
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The signals `run` handles.
+#[cfg(unix)]
+const SIGNALS: &[libc::c_int] = &[libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2];
+
+/// Serves until stopped by a signal:
+/// - SIGTERM and SIGINT stop accepting connections and let open ones finish
+/// - SIGHUP reads the configuration and certificates again
+/// - SIGUSR1 opens the access log again, after it was rotated
+/// - SIGUSR2 upgrades the program to a new copy of itself, typically after its binary was replaced;
+///   under systemd the unit needs `NotifyAccess=all` for the new copy to take over as main process
+#[cfg(unix)]
+fn run(handle: ServerHandle, matches: &ArgMatches) {
+    loop {
+        match wait_for_signal(SIGNALS) {
+            libc::SIGTERM | libc::SIGINT => {
+                println!("stopping, draining connections");
+                break;
+            },
+            libc::SIGHUP => {
+                println!("reloading");
+                if let Err(e) = reload(&handle, matches) {
+                    eprintln!("Reload failed:{}", e);
+                }
+            },
+            libc::SIGUSR1 => handle.reopen_logs(),
+            _ => {
+                println!("upgrading");
+                match handle.upgrade(UPGRADE_TIMEOUT) {
+                    Ok(pid) => {
+                        println!("process {} took over, draining connections", pid);
+                        break;
+                    },
+                    Err(e) => eprintln!("Upgrade failed:{}", e)
+                }
+            }
+        }
+    }
+    if !handle.shutdown(DRAIN_TIMEOUT) {
+        eprintln!("Cut off connections still open after {} seconds", DRAIN_TIMEOUT.as_secs());
+    }
+}
+
+#[cfg(not(unix))]
+fn run(handle: ServerHandle, _matches: &ArgMatches) {
+    handle.handle.join().expect("Join failed");
+}
+
+/// Builds the server again from the arguments and the configuration file, and has the running
+/// one serve with it.
+#[cfg(unix)]
+fn reload(handle: &ServerHandle, matches: &ArgMatches) -> Result<(), String> {
+    let config = match matches.value_of("config") {
+        Some(path) => Some(Config::load(path).map_err(|errors| {
+            errors.iter().map(|error| format!("\n{}: {}", path, error)).collect::<String>()
+        })?),
+        None => None
+    };
+    let builder = build_server(matches, config)?;
+    handle.reload(builder).map_err(|e| e.to_string())
+}
+
+/// Blocks `signals` in this thread and in every thread started after, so that they are only
+/// received by `wait_for_signal`.
+#[cfg(unix)]
+fn block_signals(signals: &[libc::c_int]) {
+    unsafe {
+        let set = signal_set(signals);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+}
+
+#[cfg(unix)]
+fn wait_for_signal(signals: &[libc::c_int]) -> libc::c_int {
+    let mut signal = 0;
+    unsafe {
+        let set = signal_set(signals);
+        libc::sigwait(&set, &mut signal);
+    }
+    signal
+}
+
+#[cfg(unix)]
+unsafe fn signal_set(signals: &[libc::c_int]) -> libc::sigset_t {
+    let mut set = std::mem::zeroed();
+    libc::sigemptyset(&mut set);
+    for &signal in signals {
+        libc::sigaddset(&mut set, signal);
+    }
+    set
+}
+
+fn build_server(matches: &ArgMatches, config: Option<Config>) -> Result<ServerBuilder, String> {
+    let root_dir = match (matches.value_of("root_directory"), config.as_ref().and_then(|config| config.root())) {
+        (Some(root_dir), _) => PathBuf::from(root_dir),
+        (None, Some(root_dir)) => root_dir.to_path_buf(),
+        (None, None) => return Err("The root directory is required".to_string())
+    };
+    let mut builder = ServerBuilder::new(root_dir).systemd().upgrades();
+    let port = port_number(matches.value_of("port").unwrap())?;
+    let socket_mode = match matches.value_of("socket_mode") {
+        Some(mode) => match u32::from_str_radix(mode, 8) {
+            Ok(mode) if mode <= 0o777 => Some(mode),
+            _ => return Err(format!("Invalid socket mode: {}", mode))
+        },
+        None => None
+    };
+    let mut listen = vec![];
+    let mut unix_sockets = vec![];
+    for address in matches.values_of("address").unwrap() {
+        match UnixSocket::from_address(address) {
+            Some(socket) => unix_sockets.push(match socket_mode {
+                Some(mode) => socket.mode(mode),
+                None => socket
+            }),
+            None => listen.push(bind_address(address, port)?)
+        }
+    }
+    if let Some(ref config) = config {
+        builder = config.configure(builder);
+        let config_listens = !config.listen().is_empty() || !config.unix_sockets().is_empty();
+        if matches.occurrences_of("address") == 0 && config_listens {
+            listen = config.listen().to_vec();
+            unix_sockets = config.unix_sockets().to_vec();
+            if matches.occurrences_of("port") > 0 {
+                for addr in &mut listen {
+                    addr.set_port(port);
+                }
+            }
+        } else if let (0, Some(config_listen)) = (matches.occurrences_of("port"), config.listen().first()) {
+            for addr in &mut listen {
+                addr.set_port(config_listen.port());
+            }
+        }
+    }
+    for (i, &addr) in listen.iter().enumerate() {
+        builder = if i == 0 { builder.listen(addr) } else { builder.also_listen(addr) };
+    }
+    for (i, socket) in unix_sockets.into_iter().enumerate() {
+        builder = if i == 0 && listen.is_empty() { builder.listen_unix(socket) } else { builder.also_listen_unix(socket) };
+    }
+    if matches.is_present("ipv6_only") {
+        builder = builder.ipv6_only(true);
+    }
+    if let Some(user) = matches.value_of("user") {
+        builder = builder.user(user);
+    }
+    if let Some(group) = matches.value_of("group") {
+        builder = builder.group(group);
+    }
+    if matches.is_present("chroot") {
+        builder = builder.chroot();
+    }
+
+    if let Some(port) = matches.value_of("redirect_port") {
+        let ip = listen.first().map(|addr| addr.ip()).unwrap_or_else(|| IpAddr::from([127, 0, 0, 1]));
+        builder = builder.redirect_http(SocketAddr::new(ip, port_number(port)?), HttpsRedirect::new());
+    }
+    if let Some(max_age) = matches.value_of("hsts") {
+        let max_age = max_age.parse::<u64>().map_err(|_| format!("Invalid HSTS max-age: {}", max_age))?;
+        builder = builder.hsts(Hsts::new(Duration::from_secs(max_age)));
+    }
+
+    if config.is_none() || matches.occurrences_of("threads") > 0 {
+        match matches.value_of("threads").unwrap().parse::<usize>() {
+            Ok(threads) if threads > 0 => builder = builder.threads(threads),
+            _ => return Err("The thread count must be a positive number".to_string())
+        }
+    }
+    if let Some(threads) = matches.value_of("max_threads") {
+        match threads.parse::<usize>() {
+            Ok(threads) if threads > 0 => builder = builder.max_threads(threads),
+            _ => return Err("The maximum thread count must be a positive number".to_string())
+        }
+    }
+    if let Some(seconds) = matches.value_of("idle_timeout") {
+        let seconds = seconds.parse::<u64>().map_err(|_| format!("Invalid idle timeout: {}", seconds))?;
+        builder = builder.idle_timeout(Duration::from_secs(seconds));
+    }
+    if let Some(size) = matches.value_of("queue_size") {
+        match size.parse::<usize>() {
+            Ok(size) if size > 0 => builder = builder.queue_size(size),
+            _ => return Err("The queue size must be a positive number".to_string())
+        }
+    }
+    if let Some(overload) = matches.value_of("overload") {
+        let retry_after = match matches.value_of("retry_after") {
+            Some(seconds) => Some(Duration::from_secs(seconds.parse::<u64>().map_err(|_| format!("Invalid retry delay: {}", seconds))?)),
+            None => None
+        };
+        if retry_after.is_some() && overload != "reject" {
+            return Err("--retry-after needs --overload reject".to_string());
+        }
+        let policy = Overload::parse(overload, retry_after).ok_or_else(|| format!("Unknown overload policy: {}", overload))?;
+        builder = builder.overload(policy);
+    }
+    if let Some(bytes) = matches.value_of("file_cache") {
+        match bytes.parse::<usize>() {
+            Ok(bytes) if bytes > 0 => builder = builder.file_cache(bytes),
+            _ => return Err("The file cache size must be a positive number of bytes".to_string())
+        }
+    }
+    if let Some(bytes) = matches.value_of("mmap_threshold") {
+        let bytes = bytes.parse::<u64>().map_err(|_| format!("Invalid mmap threshold: {}", bytes))?;
+        builder = builder.mmap_threshold(bytes);
     }
-}
\ No newline at end of file
+
+    let tls = match (matches.value_of("cert"), matches.value_of("pk"), matches.value_of("pkcs12")) {
+        (_, _, Some(pkcs12_path)) => Some(TlsConfig::pkcs12(pkcs12_path)),
+        (Some(cert_path), Some(private_key_path), None) => Some(TlsConfig::new(cert_path, private_key_path)),
+        (Some(_), None, None) => return Err("If the cert is specified, so must the private key".to_string()),
+        (None, Some(_), None) => return Err("If the private key is specified, so must the cert".to_string()),
+        (None, None, None) if matches.is_present("dev_tls") => Some(dev_tls(&listen)?),
+        (None, None, None) => None
+    };
+    let tls = match (tls, matches.value_of("passphrase_file")) {
+        (Some(tls), Some(passphrase_path)) => Some(tls.passphrase_file(passphrase_path)),
+        (None, Some(_)) => return Err("A passphrase file needs a private key or PKCS#12 archive".to_string()),
+        (tls, None) => tls
+    };
+    match (tls, matches.value_of("ocsp_response")) {
+        (Some(tls), Some(ocsp_path)) => builder = builder.tls(tls.ocsp_response(ocsp_path)),
+        (Some(tls), None) => builder = builder.tls(tls),
+        (None, Some(_)) => return Err("An OCSP response needs a cert".to_string()),
+        (None, None) => {}
+    }
+
+    match matches.value_of("access_log") {
+        Some("-") => builder = builder.access_log(LogTarget::Stdout),
+        Some(path) => builder = builder.access_log(LogTarget::File(PathBuf::from(path))),
+        None => {}
+    }
+
+    Ok(builder)
+}
+
+const DEV_CERT_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// The development certificate, issued at startup and served again after each reload.
+static DEV_TLS: OnceLock<TlsConfig> = OnceLock::new();
+
+/// A config serving a development certificate for localhost and the addresses in `listen`. It
+/// is issued once, so that a reload keeps the CA clients were told to trust; the addresses
+/// cannot change on reload anyway.
+fn dev_tls(listen: &[SocketAddr]) -> Result<TlsConfig, String> {
+    if let Some(tls) = DEV_TLS.get() {
+        return Ok(tls.clone());
+    }
+    let ips: Vec<String> = listen.iter()
+        .filter(|addr| !addr.ip().is_unspecified())
+        .map(|addr| addr.ip().to_string())
+        .collect();
+    let mut names = DEV_CERT_NAMES.to_vec();
+    for ip in &ips {
+        if !names.contains(&ip.as_str()) {
+            names.push(ip);
+        }
+    }
+    let certificates = DevCertificates::generate(&names).map_err(|e| e.to_string())?;
+    println!("Serving a development certificate for {}", names.join(", "));
+    Ok(DEV_TLS.get_or_init(|| certificates.tls_config()).clone())
+}
+
+fn gen_cert(matches: &ArgMatches) -> Result<(), String> {
+    let names: Vec<&str> = match matches.values_of("names") {
+        Some(names) => names.collect(),
+        None => DEV_CERT_NAMES.to_vec()
+    };
+    let certificates = DevCertificates::generate(&names).map_err(|e| e.to_string())?;
+    let out = Path::new(matches.value_of("out").unwrap());
+    let files = [("ca.pem", &certificates.ca_cert, false), ("cert.pem", &certificates.cert, false), ("key.pem", &certificates.key, true)];
+    for &(file, contents, private) in &files {
+        let path = out.join(file);
+        write_new(&path, contents, private).map_err(|e| format!("Error writing {}:{}", path.display(), e))?;
+        println!("wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// Refuses to overwrite an existing file. On Unix only the owner may read a private one.
+fn write_new(path: &Path, contents: &[u8], private: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    options.open(path)?.write_all(contents)
+}
+
+fn port_number(port: &str) -> Result<u16, String> {
+    port.parse::<u16>().map_err(|_| format!("Invalid port number: {}", port))
+}
+
+fn bind_address(address: &str, port: u16) -> Result<SocketAddr, String> {
+    match (address, port).to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or_else(|| format!("No address found for {}", address)),
+        Err(e) => Err(format!("Invalid bind address {}:{}", address, e))
+    }
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
@@ -0,0 +1,138 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use libc;
+
+/// The user and group to run as, looked up before any chroot hides the system's databases.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ids {
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>
+}
+
+/// Looks up `user` and `group`, by name or number. The group defaults to the user's own, or to
+/// the same number for a user id without an entry.
+pub fn lookup(user: Option<&str>, group: Option<&str>) -> io::Result<Ids> {
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let (uid, gid) = lookup_user(user)?;
+            (Some(uid), Some(gid))
+        },
+        None => (None, None)
+    };
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user_gid
+    };
+    Ok(Ids { uid, gid })
+}
+
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let name = c_string(user.as_bytes())?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { ((*entry).pw_uid, (*entry).pw_gid) });
+    }
+    match user.parse::<libc::uid_t>() {
+        Ok(uid) => Ok((uid, uid)),
+        Err(_) => Err(io::Error::new(io::ErrorKind::NotFound, format!("no such user: {}", user)))
+    }
+}
+
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    let name = c_string(group.as_bytes())?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+    group.parse::<libc::gid_t>()
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("no such group: {}", group)))
+}
+
+/// Confines the process to `root`, which becomes `/`.
+pub fn chroot(root: &Path) -> io::Result<()> {
+    let path = c_string(root.as_os_str().as_bytes())?;
+    if unsafe { libc::chroot(path.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    ::std::env::set_current_dir("/")
+}
+
+/// Switches every thread of the process to `ids` for good, group first while still allowed to.
+/// As root, the supplementary groups are always replaced by the group alone, even when it is
+/// already the primary one. Running as them already is fine, as after an upgrade, as long as no
+/// other group is left. Fails unless the switch is complete and root cannot be got back.
+pub fn switch_to(ids: Ids) -> io::Result<()> {
+    if let Some(gid) = ids.gid {
+        if unsafe { libc::geteuid() } == 0 {
+            if unsafe { libc::setgroups(1, &gid) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        } else if groups()?.iter().any(|&group| group != gid) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "other groups could not be dropped"));
+        }
+        let current = unsafe { libc::getgid() == gid && libc::getegid() == gid };
+        if !current && unsafe { libc::setgid(gid) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(uid) = ids.uid {
+        let current = unsafe { libc::getuid() == uid && libc::geteuid() == uid };
+        if !current && unsafe { libc::setuid(uid) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "root privileges could be regained"));
+        }
+    }
+    let switched = ids.uid.is_none_or(|uid| unsafe { libc::getuid() == uid && libc::geteuid() == uid })
+        && ids.gid.is_none_or(|gid| unsafe { libc::getgid() == gid && libc::getegid() == gid });
+    if switched {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "the user and group did not change"))
+    }
+}
+
+/// The supplementary groups of the process.
+fn groups() -> io::Result<Vec<libc::gid_t>> {
+    let count = unsafe { libc::getgroups(0, ::std::ptr::null_mut()) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut groups = vec![0; count as usize];
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    groups.truncate(count as usize);
+    Ok(groups)
+}
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{groups, lookup, switch_to};
+    use libc;
+
+    #[test]
+    fn looks_up_names_and_numbers() {
+        let root = lookup(Some("root"), None).unwrap();
+        assert_eq!(lookup(Some("0"), Some("0")).unwrap(), root);
+        assert!(lookup(Some("no-such-user-here"), None).is_err());
+        assert!(lookup(None, Some("no-such-group-here")).is_err());
+    }
+
+    #[test]
+    fn switching_to_the_current_ids_changes_nothing() {
+        let current = unsafe { (libc::getuid(), libc::getgid()) };
+        let ids = lookup(Some(&current.0.to_string()), Some(&current.1.to_string())).unwrap();
+        switch_to(ids).unwrap();
+        assert_eq!(current, unsafe { (libc::getuid(), libc::getgid()) });
+        assert!(groups().unwrap().iter().all(|&group| group == current.1));
+    }
+}
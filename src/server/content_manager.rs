@@ -1,9 +1,12 @@
-use std::io::Write;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 use chrono::{DateTime, TimeZone};
 use chrono::offset::Utc;
+use super::http::{Request, Response};
 
 pub trait ContentManager<H: ContentHandle> {
-    fn find_content(&self, url: &String, accepts_gzip: bool) -> Option<H>;
+    fn find_content(&self, url: &str, accepts_gzip: bool) -> Option<H>;
 }
 
 pub trait ContentHandle {
@@ -11,5 +14,55 @@ pub trait ContentHandle {
     fn mod_time(&self) -> &DateTime<Utc>;
     fn content_length(&self) -> u64;
     fn is_gzipped(&self) -> bool;
-    fn write_to(&mut self, writer: &mut Write);
-}
\ No newline at end of file
+
+    /// A tag that changes whenever the content does, for conditional requests.
+    fn etag(&self) -> Option<&str> {
+        None
+    }
+
+    /// The media type of the content, if known.
+    fn content_type(&self) -> Option<&str> {
+        None
+    }
+
+    /// The rest of the body, when it is already in memory and needs no reading.
+    fn bytes(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Reads the next part of the body into `buf`, returning 0 once it is all read. Blocking is
+    /// fine: the event loop calls this from its file readers, one chunk at a time.
+    fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Sends up to `count` bytes of the rest of the body straight to `socket` without copying them
+    /// through the process, returning how many were sent, and 0 once it is all sent. `None` if
+    /// the content cannot be sent that way and has to be read instead. The socket does not block.
+    #[cfg(unix)]
+    fn send_to(&mut self, _socket: RawFd, _count: usize) -> Option<io::Result<usize>> {
+        None
+    }
+
+    /// Copies the rest of the body to `writer`, for serving a connection on its own thread.
+    fn write_to(&mut self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut buf = [0; 8 * 1024];
+        loop {
+            match self.read_body(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(read) => writer.write_all(&buf[..read])?,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+/// A hook consulted for every request before the content manager.
+pub trait Handler: Send + Sync {
+    /// Answers the request, or returns `None` to pass it on to the next handler and finally the content.
+    fn handle(&self, _request: &Request) -> Option<Response> {
+        None
+    }
+
+    /// Adjusts the response about to be sent, whichever handler produced it.
+    fn on_response(&self, _request: &Request, _response: &mut Response) {}
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tls::ReloadableAcceptor;
+use super::Options;
+use super::content_manager::ContentHandle;
+use super::hosts::VirtualHosts;
+use super::listener::Connection;
+
+/// What the accept loop of a server is sent: connections to serve, and changes from outside.
+pub enum Event<H: ContentHandle> {
+    Accepted(io::Result<(Connection, Tracked)>),
+    /// Serves new connections with these sites, options and certificates. Connections already
+    /// being served keep the ones they started with.
+    Reload(VirtualHosts<H>, Options, Option<Arc<ReloadableAcceptor>>),
+    /// Opens the access log again after it was rotated.
+    ReopenLogs
+}
+
+/// Shared between a running server's accept loops, its workers and its handle: whether the
+/// server is stopping, and the connections it still has open.
+pub struct Control {
+    stopping: AtomicBool,
+    next_id: AtomicUsize,
+    connections: Mutex<HashMap<usize, Connection>>
+}
+
+impl Control {
+    pub fn new() -> Control {
+        Control { stopping: AtomicBool::new(false), next_id: AtomicUsize::new(0), connections: Mutex::new(HashMap::new()) }
+    }
+
+    /// Stops accepting connections, and keeps open ones from waiting for another request.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Counts `connection` as open until the returned guard is dropped, so that it can be cut off
+    /// if it outlasts a shutdown.
+    pub fn track(control: &Arc<Control>, connection: &Connection) -> Tracked {
+        let id = control.next_id.fetch_add(1, Ordering::SeqCst);
+        match connection.try_clone() {
+            Ok(clone) => {
+                if let Ok(mut connections) = control.connections.lock() {
+                    connections.insert(id, clone);
+                }
+            },
+            Err(e) => eprintln!("Error tracking connection:{}", e)
+        }
+        Tracked { control: control.clone(), id }
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.connections.lock().map(|connections| connections.len()).unwrap_or(0)
+    }
+
+    /// Shuts down every open connection, which makes the workers serving them give up.
+    pub fn close_all(&self) {
+        if let Ok(connections) = self.connections.lock() {
+            for connection in connections.values() {
+                let _ = connection.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+impl Default for Control {
+    fn default() -> Control {
+        Control::new()
+    }
+}
+
+/// An open connection, forgotten when dropped.
+pub struct Tracked {
+    control: Arc<Control>,
+    id: usize
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.control.connections.lock() {
+            connections.remove(&self.id);
+        }
+    }
+}
@@ -0,0 +1,737 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::unix::EventedFd;
+use openssl::ssl::{HandshakeError, MidHandshakeSslStream, SslStream};
+use tls::{ktls_send, session, ReloadableAcceptor};
+use super::{Options, ACCEPT_POLL};
+use super::content_manager::ContentHandle;
+use super::control::{Control, Event, Tracked};
+use super::handlers::{answer, keep_alive, unavailable, write_head};
+use super::hosts::VirtualHosts;
+use super::http::{parse_buffered, BadRequest, Request, Response, TlsSession};
+use super::listener::{wait_writable, Connection, Listener};
+use super::pool::{Overload, ThreadPool};
+
+/// How many threads read response bodies from files, and how many reads may wait for one.
+const FILE_READERS: usize = 4;
+const FILE_QUEUE: usize = 256;
+/// How much of a body a file reader reads at a time, or sends when the kernel does the copying.
+const CHUNK: usize = 64 * 1024;
+const SEND_SIZE: usize = 4 * 1024 * 1024;
+/// How much is read from a connection at a time.
+const READ_SIZE: usize = 16 * 1024;
+/// Wakes the loop when a worker has finished a job.
+const WAKER: Token = Token(usize::MAX - 1);
+
+/// Serves the connections of `listeners` from one thread that waits for any of them to be ready,
+/// reads and parses requests and writes responses without blocking. Workers of `pool` answer
+/// the requests, and a few file readers read the bodies, a chunk at a time, or have the kernel
+/// send them when the socket is plain TCP or kTLS. Connections waiting for another request cost
+/// no thread. Returns once the server stops and the last connection is closed.
+pub fn serve<H: ContentHandle + Send + 'static>(listeners: Vec<Listener>, pool: ThreadPool, events: Receiver<Event<H>>,
+                                                hosts: VirtualHosts<H>, options: Options, acceptor: Option<Arc<ReloadableAcceptor>>) {
+    match EventLoop::new(listeners, pool, hosts, options, acceptor) {
+        Ok(mut event_loop) => event_loop.run(events),
+        Err(e) => eprintln!("Error starting the event loop:{}", e)
+    }
+}
+
+struct EventLoop<H: ContentHandle> {
+    poll: Poll,
+    listeners: Vec<Listener>,
+    /// Connections are numbered from here on, after the listeners.
+    first_connection: usize,
+    next_id: usize,
+    connections: HashMap<usize, Conn<H>>,
+    pool: ThreadPool,
+    files: ThreadPool,
+    /// Connections with a request waiting for room in `pool`, and with a body to read waiting
+    /// for room in `files`, oldest first.
+    queued_requests: VecDeque<usize>,
+    queued_reads: VecDeque<usize>,
+    done: Receiver<Done<H>>,
+    replies: Sender<Done<H>>,
+    _registration: Registration,
+    waker: SetReadiness,
+    hosts: Arc<VirtualHosts<H>>,
+    options: Arc<Options>,
+    acceptor: Option<Arc<ReloadableAcceptor>>,
+    /// Whether a listener failed to accept, and should be tried again without waiting to be ready.
+    accept_again: bool,
+    last_sweep: Instant
+}
+
+struct Conn<H: ContentHandle> {
+    stream: Stream,
+    fd: RawFd,
+    peer: Option<SocketAddr>,
+    /// Set once the handshake of a TLS connection is done.
+    tls: Option<TlsSession>,
+    /// Whether bodies can be written straight to the socket: when nothing but the kernel stands
+    /// between it and the client.
+    zero_copy: bool,
+    /// What the connection is served with: what was current when it was accepted.
+    hosts: Arc<VirtualHosts<H>>,
+    options: Arc<Options>,
+    /// Read but not yet parsed.
+    input: Vec<u8>,
+    /// To be written, from `written` on.
+    output: Vec<u8>,
+    written: usize,
+    state: State<H>,
+    /// When the connection is closed unless the client reads or writes something.
+    deadline: Option<Instant>,
+    _tracked: Tracked
+}
+
+enum Stream {
+    Plain(Connection),
+    Handshaking(MidHandshakeSslStream<Connection>),
+    Tls(SslStream<Connection>),
+    Closed
+}
+
+enum State<H: ContentHandle> {
+    Handshaking,
+    /// Waiting for the whole head of the next request.
+    Reading,
+    /// Waiting for room in the pool to be answered. Keeps the connection open afterwards or not.
+    Queued(Request, bool),
+    /// A worker is answering.
+    Answering(bool),
+    /// Writing the response. The rest of the body, if any, is read once `output` is written.
+    Sending(Exchange<H>),
+    /// Waiting for a file reader to come back with the next part of the body, or for one to be free.
+    Fetching(Exchange<H>)
+}
+
+/// A response on its way to the client.
+struct Exchange<H: ContentHandle> {
+    /// Logged once the response is sent. Requests too bad to parse are not.
+    request: Option<Request>,
+    response: Response,
+    keep_alive: bool,
+    content: Option<H>
+}
+
+/// What a job sends back to the loop.
+enum Done<H> {
+    Answered(usize, Request, Response, Option<H>),
+    /// The next part of a body, empty at its end.
+    Read(usize, io::Result<Vec<u8>>, H),
+    /// How much more of a body went straight to the socket, 0 at its end.
+    Sent(usize, io::Result<usize>, H),
+    /// The job was shed or panicked, leaving the connection unanswered.
+    Dropped(usize)
+}
+
+/// Sends the result of a job to the loop and wakes it up. Tells it the job came to nothing if
+/// dropped before sending anything.
+struct Reply<H> {
+    id: usize,
+    sender: Sender<Done<H>>,
+    waker: SetReadiness,
+    sent: bool
+}
+
+impl<H> Reply<H> {
+    fn send(mut self, done: Done<H>) {
+        self.deliver(done);
+    }
+
+    fn deliver(&mut self, done: Done<H>) {
+        self.sent = true;
+        let _ = self.sender.send(done);
+        let _ = self.waker.set_readiness(Ready::readable());
+    }
+}
+
+impl<H> Drop for Reply<H> {
+    fn drop(&mut self) {
+        if !self.sent {
+            let id = self.id;
+            self.deliver(Done::Dropped(id));
+        }
+    }
+}
+
+impl<H: ContentHandle + Send + 'static> EventLoop<H> {
+    fn new(listeners: Vec<Listener>, pool: ThreadPool, hosts: VirtualHosts<H>, options: Options,
+           acceptor: Option<Arc<ReloadableAcceptor>>) -> io::Result<EventLoop<H>> {
+        let poll = Poll::new()?;
+        for (index, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            poll.register(&EventedFd(&listener.as_raw_fd()), Token(index), Ready::readable(), PollOpt::edge())?;
+        }
+        let (registration, waker) = Registration::new2();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())?;
+        let (replies, done) = channel();
+        let first_connection = listeners.len();
+        Ok(EventLoop {
+            poll,
+            listeners,
+            first_connection,
+            next_id: first_connection,
+            connections: HashMap::new(),
+            pool,
+            files: ThreadPool::new(FILE_READERS, FILE_QUEUE),
+            queued_requests: VecDeque::new(),
+            queued_reads: VecDeque::new(),
+            done,
+            replies,
+            _registration: registration,
+            waker,
+            hosts: Arc::new(hosts),
+            options: Arc::new(options),
+            acceptor,
+            accept_again: false,
+            last_sweep: Instant::now()
+        })
+    }
+
+    fn run(&mut self, events: Receiver<Event<H>>) {
+        let mut ready = Events::with_capacity(1024);
+        loop {
+            if self.options.control.is_stopping() {
+                self.stop();
+                if self.connections.is_empty() {
+                    break;
+                }
+            }
+            if let Err(e) = self.poll.poll(&mut ready, Some(ACCEPT_POLL)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                eprintln!("Error waiting for connections:{}", e);
+                break;
+            }
+            // before accepting, so that connections made after a reload are served with what it brought
+            while let Ok(event) = events.try_recv() {
+                self.receive(event);
+            }
+            for event in &ready {
+                match event.token() {
+                    WAKER => {
+                        let _ = self.waker.set_readiness(Ready::empty());
+                    },
+                    Token(index) if index < self.first_connection => self.accept(index),
+                    Token(id) => self.drive(id)
+                }
+            }
+            if self.accept_again {
+                self.accept_again = false;
+                for index in 0..self.listeners.len() {
+                    self.accept(index);
+                }
+            }
+            while let Ok(done) = self.done.try_recv() {
+                self.finish(done);
+            }
+            self.dispatch_queued();
+            self.sweep();
+        }
+    }
+
+    /// Stops accepting, and closes connections waiting for another request.
+    fn stop(&mut self) {
+        for listener in self.listeners.drain(..) {
+            let _ = self.poll.deregister(&EventedFd(&listener.as_raw_fd()));
+        }
+        let idle: Vec<usize> = self.connections.iter()
+            .filter(|&(_, conn)| matches!(conn.state, State::Handshaking | State::Reading))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in idle {
+            self.close(id);
+        }
+    }
+
+    fn receive(&mut self, event: Event<H>) {
+        match event {
+            Event::Reload(hosts, options, acceptor) => {
+                self.hosts = Arc::new(hosts);
+                self.options = Arc::new(options);
+                self.acceptor = acceptor;
+            },
+            Event::ReopenLogs => if let Err(e) = self.options.log.reopen() {
+                eprintln!("Error reopening the access log:{}", e);
+            },
+            Event::Accepted(_) => {}
+        }
+    }
+
+    fn accept(&mut self, index: usize) {
+        loop {
+            let accepted = match self.listeners.get(index) {
+                Some(listener) => listener.accept(),
+                None => return
+            };
+            match accepted {
+                Ok(connection) => self.open(connection),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted || e.kind() == ErrorKind::ConnectionAborted => {},
+                Err(e) => {
+                    eprintln!("Error with stream:{}", e);
+                    self.accept_again = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn open(&mut self, connection: Connection) {
+        let tracked = Control::track(&self.options.control, &connection);
+        let peer = match connection.set_nonblocking(true).and_then(|_| connection.peer_addr()) {
+            Ok(peer) => peer,
+            Err(e) => {
+                eprintln!("Error preparing connection:{}", e);
+                return;
+            }
+        };
+        let fd = connection.as_raw_fd();
+        let (stream, state) = match self.acceptor {
+            None => (Stream::Plain(connection), State::Reading),
+            Some(ref acceptor) => match acceptor.current().accept(connection) {
+                Ok(stream) => (Stream::Tls(stream), State::Handshaking),
+                Err(HandshakeError::Interrupted(mid)) => (Stream::Handshaking(mid), State::Handshaking),
+                Err(e) => {
+                    eprintln!("Accept failed:{}", e);
+                    return;
+                }
+            }
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        let registered = self.poll.register(&EventedFd(&fd), Token(id), Ready::readable() | Ready::writable(), PollOpt::edge());
+        if let Err(e) = registered {
+            eprintln!("Error preparing connection:{}", e);
+            return;
+        }
+        self.connections.insert(id, Conn {
+            stream,
+            fd,
+            peer,
+            tls: None,
+            zero_copy: self.acceptor.is_none(),
+            hosts: self.hosts.clone(),
+            options: self.options.clone(),
+            input: Vec::new(),
+            output: Vec::new(),
+            written: 0,
+            state,
+            deadline: None,
+            _tracked: tracked
+        });
+        self.drive(id);
+    }
+
+    /// Takes the connection as far as it will go without waiting, and closes it once done with.
+    fn drive(&mut self, id: usize) {
+        let mut conn = match self.connections.remove(&id) {
+            Some(conn) => conn,
+            None => return
+        };
+        match self.advance(id, &mut conn) {
+            Ok(true) => {
+                self.connections.insert(id, conn);
+            },
+            Ok(false) => self.forget(conn),
+            Err(e) => {
+                eprintln!("Error serving client:{}", e);
+                self.forget(conn);
+            }
+        }
+    }
+
+    /// Whether the connection stays open.
+    fn advance(&mut self, id: usize, conn: &mut Conn<H>) -> io::Result<bool> {
+        loop {
+            match conn.state {
+                State::Handshaking => match handshake(conn) {
+                    Some(true) => conn.state = State::Reading,
+                    Some(false) => {
+                        conn.wait(conn.options.read_timeout);
+                        return Ok(true);
+                    },
+                    None => return Ok(false)
+                },
+                State::Reading => {
+                    if conn.options.control.is_stopping() {
+                        return Ok(false);
+                    }
+                    match parse_buffered(&conn.input, &conn.options.limits) {
+                        Ok(Some((mut request, length))) => {
+                            conn.input.drain(..length);
+                            request.client_cert = conn.tls.as_ref().and_then(|tls| tls.client_cert.clone());
+                            self.submit(id, conn, request);
+                        },
+                        Ok(None) => {
+                            let mut buf = [0; READ_SIZE];
+                            match conn.stream.read(&mut buf) {
+                                Ok(0) => return Ok(false),
+                                Ok(read) => {
+                                    conn.input.extend_from_slice(&buf[..read]);
+                                    conn.deadline = None;
+                                },
+                                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                                    conn.wait(conn.options.read_timeout);
+                                    return Ok(true);
+                                },
+                                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                                Err(e) => return Err(e)
+                            }
+                        },
+                        Err(BadRequest { code, reason }) => {
+                            eprintln!("Error:{}/{}", code, reason);
+                            let response = Response::new(code, reason).with_body("");
+                            conn.respond(None, response, false, None);
+                        }
+                    }
+                },
+                State::Queued(..) | State::Answering(_) | State::Fetching(_) => return Ok(true),
+                State::Sending(_) => {
+                    if let Err(e) = conn.flush() {
+                        conn.log();
+                        return Err(e);
+                    }
+                    if conn.written < conn.output.len() {
+                        conn.wait(conn.options.write_timeout);
+                        return Ok(true);
+                    }
+                    conn.output.clear();
+                    conn.written = 0;
+                    let exchange = match mem::replace(&mut conn.state, State::Reading) {
+                        State::Sending(exchange) => exchange,
+                        _ => unreachable!()
+                    };
+                    if exchange.content.is_some() {
+                        // the file readers would only find the socket full
+                        if conn.zero_copy && !wait_writable(conn.fd, Duration::from_secs(0))? {
+                            conn.state = State::Sending(exchange);
+                            conn.wait(conn.options.write_timeout);
+                            return Ok(true);
+                        }
+                        // a file reader may be handed the socket, which must stay open until it is done
+                        conn.deadline = None;
+                        conn.state = State::Fetching(exchange);
+                        if self.queued_reads.is_empty() && !self.files.is_full() {
+                            self.fetch(id, conn);
+                        } else {
+                            self.queued_reads.push_back(id);
+                        }
+                        return Ok(true);
+                    }
+                    if let Some(ref request) = exchange.request {
+                        conn.options.log.log(conn.peer, request, &exchange.response);
+                    }
+                    if !exchange.keep_alive {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hands the request to a worker, or keeps it back, turns it away or makes room for it,
+    /// whichever the overload policy says, when there is no room.
+    fn submit(&mut self, id: usize, conn: &mut Conn<H>, request: Request) {
+        conn.deadline = None;
+        let keep_alive = keep_alive(&request, &conn.options);
+        if self.queued_requests.is_empty() && !self.pool.is_full() {
+            return self.answer(id, conn, request, keep_alive);
+        }
+        match conn.options.overload {
+            Overload::Block => {
+                conn.state = State::Queued(request, keep_alive);
+                self.queued_requests.push_back(id);
+            },
+            Overload::Reject(retry_after) => {
+                self.pool.count_rejected();
+                conn.respond(Some(request), unavailable(retry_after), false, None);
+            },
+            Overload::ShedOldest => self.answer(id, conn, request, keep_alive)
+        }
+    }
+
+    fn answer(&mut self, id: usize, conn: &mut Conn<H>, request: Request, keep_alive: bool) {
+        conn.state = State::Answering(keep_alive);
+        let hosts = conn.hosts.clone();
+        let tls = conn.tls.clone();
+        let reply = self.reply(id);
+        let job = move || {
+            let (response, content) = answer(&request, tls.as_ref(), &hosts);
+            reply.send(Done::Answered(id, request, response, content));
+        };
+        match conn.options.overload {
+            Overload::ShedOldest => self.pool.execute_shedding(job),
+            _ => self.pool.execute(job)
+        }
+    }
+
+    /// Has a file reader read the next part of the body.
+    fn fetch(&mut self, id: usize, conn: &mut Conn<H>) {
+        let content = match conn.state {
+            State::Fetching(ref mut exchange) => exchange.content.take(),
+            _ => None
+        };
+        if let Some(mut content) = content {
+            let reply = self.reply(id);
+            let socket = if conn.zero_copy { Some(conn.fd) } else { None };
+            self.files.execute(move || {
+                if let Some(sent) = socket.and_then(|socket| content.send_to(socket, SEND_SIZE)) {
+                    return reply.send(Done::Sent(id, sent, content));
+                }
+                let mut buf = vec![0; CHUNK];
+                let read = content.read_body(&mut buf).map(|read| {
+                    buf.truncate(read);
+                    buf
+                });
+                reply.send(Done::Read(id, read, content));
+            });
+        }
+    }
+
+    /// Starts what waited for room in the pools, for as long as there is room.
+    fn dispatch_queued(&mut self) {
+        while !self.files.is_full() {
+            let id = match self.queued_reads.pop_front() {
+                Some(id) => id,
+                None => break
+            };
+            if let Some(mut conn) = self.connections.remove(&id) {
+                self.fetch(id, &mut conn);
+                self.connections.insert(id, conn);
+            }
+        }
+        while !self.pool.is_full() {
+            let id = match self.queued_requests.pop_front() {
+                Some(id) => id,
+                None => break
+            };
+            if let Some(mut conn) = self.connections.remove(&id) {
+                if let State::Queued(request, keep_alive) = mem::replace(&mut conn.state, State::Reading) {
+                    self.answer(id, &mut conn, request, keep_alive);
+                }
+                self.connections.insert(id, conn);
+            }
+        }
+    }
+
+    fn finish(&mut self, done: Done<H>) {
+        let id = match done {
+            Done::Answered(id, request, response, content) => {
+                if let Some(conn) = self.connections.get_mut(&id) {
+                    if let State::Answering(keep_alive) = conn.state {
+                        conn.respond(Some(request), response, keep_alive, content);
+                    }
+                }
+                id
+            },
+            Done::Read(id, read, content) => {
+                if let Some(conn) = self.connections.get_mut(&id) {
+                    match read {
+                        Ok(ref data) if data.is_empty() => conn.resume(None),
+                        Ok(data) => {
+                            conn.output.extend_from_slice(&data);
+                            conn.resume(Some(content));
+                        },
+                        Err(e) => {
+                            eprintln!("Error reading content:{}", e);
+                            conn.log();
+                            self.close(id);
+                        }
+                    }
+                }
+                id
+            },
+            Done::Sent(id, sent, content) => {
+                if let Some(conn) = self.connections.get_mut(&id) {
+                    match sent {
+                        Ok(0) => conn.resume(None),
+                        Ok(_) => {
+                            conn.deadline = None;
+                            conn.resume(Some(content));
+                        },
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                            conn.resume(Some(content));
+                        },
+                        Err(e) => {
+                            eprintln!("Error serving client:{}", e);
+                            conn.log();
+                            self.close(id);
+                        }
+                    }
+                }
+                id
+            },
+            Done::Dropped(id) => {
+                self.close(id);
+                id
+            }
+        };
+        self.drive(id);
+    }
+
+    /// Closes connections that have waited too long on their client. Never one whose socket a
+    /// file reader is writing to, as its number could go to the next connection accepted.
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) < ACCEPT_POLL {
+            return;
+        }
+        self.last_sweep = now;
+        let expired: Vec<usize> = self.connections.iter()
+            .filter(|&(_, conn)| conn.deadline.is_some_and(|deadline| deadline <= now) && !conn.fetching())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            self.close(id);
+        }
+    }
+
+    fn reply(&self, id: usize) -> Reply<H> {
+        Reply { id, sender: self.replies.clone(), waker: self.waker.clone(), sent: false }
+    }
+
+    fn close(&mut self, id: usize) {
+        if let Some(conn) = self.connections.remove(&id) {
+            self.forget(conn);
+        }
+    }
+
+    fn forget(&mut self, conn: Conn<H>) {
+        let _ = self.poll.deregister(&EventedFd(&conn.fd));
+    }
+}
+
+impl<H: ContentHandle> Conn<H> {
+    /// Starts the timeout, unless it is already running.
+    fn wait(&mut self, timeout: Option<Duration>) {
+        if self.deadline.is_none() {
+            self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        }
+    }
+
+    fn respond(&mut self, request: Option<Request>, response: Response, keep_alive: bool, content: Option<H>) {
+        // a Vec takes whatever is written to it
+        let _ = write_head(&mut self.output, &response, keep_alive);
+        // a body already in memory goes out with the head, without a trip to the file readers
+        let content = match content {
+            Some(content) => match content.bytes() {
+                Some(bytes) => {
+                    self.output.extend_from_slice(bytes);
+                    None
+                },
+                None => Some(content)
+            },
+            None => {
+                self.output.extend_from_slice(&response.body);
+                None
+            }
+        };
+        self.state = State::Sending(Exchange { request, response, keep_alive, content });
+    }
+
+    /// Sends the part of the body just read, and reads the rest, if any, after that.
+    fn resume(&mut self, content: Option<H>) {
+        if let State::Fetching(mut exchange) = mem::replace(&mut self.state, State::Reading) {
+            exchange.content = content;
+            self.state = State::Sending(exchange);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "failed to write the response")),
+                Ok(written) => {
+                    self.written += written;
+                    self.deadline = None;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a file reader has the body, and with it maybe the socket.
+    fn fetching(&self) -> bool {
+        matches!(self.state, State::Fetching(ref exchange) if exchange.content.is_none())
+    }
+
+    /// Logs a response that never made it all the way to the client.
+    fn log(&self) {
+        if let State::Sending(ref exchange) | State::Fetching(ref exchange) = self.state {
+            if let Some(ref request) = exchange.request {
+                self.options.log.log(self.peer, request, &exchange.response);
+            }
+        }
+    }
+}
+
+/// Takes the TLS handshake as far as it will go, returning whether it is done, or nothing if it
+/// failed.
+fn handshake<H: ContentHandle>(conn: &mut Conn<H>) -> Option<bool> {
+    let handshaken = match mem::replace(&mut conn.stream, Stream::Closed) {
+        Stream::Handshaking(mid) => mid.handshake(),
+        // done as soon as it was accepted
+        Stream::Tls(stream) => Ok(stream),
+        stream => {
+            conn.stream = stream;
+            return Some(true);
+        }
+    };
+    match handshaken {
+        Ok(stream) => {
+            conn.tls = Some(session(stream.ssl()));
+            conn.zero_copy = ktls_send(stream.ssl());
+            conn.stream = Stream::Tls(stream);
+            Some(true)
+        },
+        Err(HandshakeError::Interrupted(mid)) => {
+            conn.stream = Stream::Handshaking(mid);
+            Some(false)
+        },
+        Err(e) => {
+            eprintln!("Accept failed:{}", e);
+            None
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut connection) => connection.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+            Stream::Handshaking(_) | Stream::Closed => Err(io::Error::from(ErrorKind::WouldBlock))
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut connection) => connection.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+            Stream::Handshaking(_) | Stream::Closed => Err(io::Error::from(ErrorKind::WouldBlock))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut connection) => connection.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+            Stream::Handshaking(_) | Stream::Closed => Ok(())
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long an entry is trusted before the file is looked at again.
+const REVALIDATE_AFTER: Duration = Duration::from_secs(1);
+/// What an entry costs on top of the file's contents.
+const ENTRY_OVERHEAD: usize = 256;
+/// Paths known to have no file kept at most. Requests for them cost a client nothing to make up,
+/// so they are counted apart from files and never push one out.
+const MAX_MISSING: usize = 1024;
+/// Files kept may take up to this share of the cache, so one cannot push everything else out.
+const MAX_SHARE: usize = 16;
+
+/// The modification time and size of a file, which change when it does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stamp {
+    pub mod_time: SystemTime,
+    pub len: u64
+}
+
+impl Stamp {
+    /// Nothing where the platform keeps no modification times.
+    pub fn of(metadata: &Metadata) -> Option<Stamp> {
+        metadata.modified().ok().map(|mod_time| Stamp { mod_time, len: metadata.len() })
+    }
+
+    /// An entity tag that changes whenever the file does.
+    pub fn etag(&self) -> String {
+        let nanos = self.mod_time.duration_since(UNIX_EPOCH).map(|since| since.as_nanos()).unwrap_or(0);
+        format!("\"{:x}-{:x}\"", nanos, self.len)
+    }
+}
+
+/// A file's contents held in memory, with what is served along with them.
+#[derive(Debug, Clone)]
+pub struct Cached {
+    pub body: Arc<Vec<u8>>,
+    pub stamp: Stamp,
+    pub etag: Arc<str>,
+    pub content_type: &'static str
+}
+
+/// Small files kept in memory, keyed by path. The least recently used make room once the total
+/// size would go over the limit. Paths with no file are kept apart, the oldest making room once
+/// there are too many of them. An entry is checked against the file's modification time and size
+/// when it is used, at most once a second, and dropped if the file changed or appeared.
+pub struct FileCache {
+    max_bytes: usize,
+    revalidate_after: Duration,
+    state: Mutex<State>
+}
+
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    /// Paths by when they were last used, least recently first.
+    by_use: BTreeMap<u64, PathBuf>,
+    clock: u64,
+    bytes: usize,
+    /// Paths with no file, and when that was last checked.
+    missing: HashMap<PathBuf, Missing>,
+    /// Paths with no file by when they were added, oldest first.
+    missing_by_age: BTreeMap<u64, PathBuf>
+}
+
+struct Entry {
+    cached: Cached,
+    checked: Instant,
+    used: u64,
+    size: usize
+}
+
+struct Missing {
+    checked: Instant,
+    added: u64
+}
+
+impl FileCache {
+    pub fn new(max_bytes: usize) -> FileCache {
+        FileCache {
+            max_bytes,
+            revalidate_after: REVALIDATE_AFTER,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                by_use: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+                missing: HashMap::new(),
+                missing_by_age: BTreeMap::new()
+            })
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Whether a file of `len` bytes is small enough to keep.
+    pub fn fits(&self, len: u64) -> bool {
+        len <= (self.max_bytes / MAX_SHARE) as u64
+    }
+
+    /// What is known of the file at `path`: the file, or `Some(None)` if there is none. `None`
+    /// when the cache does not know, or the file changed since.
+    pub fn get(&self, path: &Path) -> Option<Option<Cached>> {
+        let (cached, stale) = {
+            let mut state = self.lock();
+            match state.missing.get(path) {
+                Some(missing) => (None, missing.checked.elapsed() >= self.revalidate_after),
+                None => {
+                    let cached = state.touch(path)?;
+                    let stale = state.entries[path].checked.elapsed() >= self.revalidate_after;
+                    (Some(cached), stale)
+                }
+            }
+        };
+        if stale {
+            let current = fs::metadata(path).ok()
+                .filter(|metadata| metadata.is_file())
+                .and_then(|metadata| Stamp::of(&metadata));
+            let mut state = self.lock();
+            if current != cached.as_ref().map(|cached| cached.stamp) {
+                state.remove(path);
+                return None;
+            }
+            if let Some(entry) = state.entries.get_mut(path) {
+                entry.checked = Instant::now();
+            }
+            if let Some(missing) = state.missing.get_mut(path) {
+                missing.checked = Instant::now();
+            }
+        }
+        Some(cached)
+    }
+
+    /// Keeps `cached` as what is at `path`, pushing out whatever has gone unused longest to make
+    /// room.
+    pub fn insert(&self, path: PathBuf, cached: Cached) {
+        let size = ENTRY_OVERHEAD + path.as_os_str().len() + cached.body.len();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut state = self.lock();
+        state.remove(&path);
+        while state.bytes + size > self.max_bytes {
+            let oldest = match state.by_use.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break
+            };
+            state.remove(&oldest);
+        }
+        state.clock += 1;
+        let used = state.clock;
+        state.by_use.insert(used, path.clone());
+        state.bytes += size;
+        state.entries.insert(path, Entry { cached, checked: Instant::now(), used, size });
+    }
+
+    /// Keeps that there is no file at `path`, forgetting the oldest such path if there are too
+    /// many. Files kept are left alone.
+    pub fn insert_missing(&self, path: PathBuf) {
+        let mut state = self.lock();
+        state.remove(&path);
+        while state.missing.len() >= MAX_MISSING {
+            let oldest = match state.missing_by_age.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break
+            };
+            state.remove(&oldest);
+        }
+        state.clock += 1;
+        let added = state.clock;
+        state.missing_by_age.insert(added, path.clone());
+        state.missing.insert(path, Missing { checked: Instant::now(), added });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl State {
+    /// Marks the entry for `path` as just used, and returns what it holds.
+    fn touch(&mut self, path: &Path) -> Option<Cached> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(path)?;
+        let last_used = entry.used;
+        entry.used = clock;
+        let cached = entry.cached.clone();
+        self.by_use.remove(&last_used);
+        self.by_use.insert(clock, path.to_path_buf());
+        Some(cached)
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.by_use.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+        if let Some(missing) = self.missing.remove(path) {
+            self.missing_by_age.remove(&missing.added);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cached, FileCache, Stamp, ENTRY_OVERHEAD, MAX_MISSING};
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn cached(len: usize) -> Cached {
+        let stamp = Stamp { mod_time: UNIX_EPOCH, len: len as u64 };
+        Cached { body: Arc::new(vec![0; len]), stamp, etag: stamp.etag().into(), content_type: "text/plain" }
+    }
+
+    #[test]
+    fn the_least_recently_used_make_room() {
+        let cache = FileCache::new(3 * (ENTRY_OVERHEAD + 2 + 100));
+        for name in &["/a", "/b", "/c"] {
+            cache.insert(PathBuf::from(name), cached(100));
+        }
+        assert!(cache.get(Path::new("/a")).is_some());
+        cache.insert(PathBuf::from("/d"), cached(100));
+
+        assert!(cache.get(Path::new("/b")).is_none());
+        for name in &["/a", "/c", "/d"] {
+            assert!(cache.get(Path::new(name)).is_some());
+        }
+    }
+
+    #[test]
+    fn missing_files_cannot_push_out_files() {
+        let cache = FileCache::new(ENTRY_OVERHEAD + 2 + 100);
+        cache.insert(PathBuf::from("/a"), cached(100));
+        for i in 0..MAX_MISSING + 10 {
+            cache.insert_missing(PathBuf::from(format!("/missing/{}", i)));
+        }
+
+        assert!(cache.get(Path::new("/a")).unwrap().is_some());
+        assert!(cache.get(Path::new("/missing/0")).is_none());
+        assert!(cache.get(Path::new(&format!("/missing/{}", MAX_MISSING + 9))).unwrap().is_none());
+    }
+
+    #[test]
+    fn changed_and_new_files_are_noticed() {
+        let dir = env::temp_dir().join(format!("rust-http2-server-cache-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        fs::write(&path, b"first").unwrap();
+        let mut cache = FileCache::new(64 * 1024);
+        cache.revalidate_after = Duration::from_secs(0);
+
+        let stamp = Stamp::of(&fs::metadata(&path).unwrap()).unwrap();
+        let first = Cached { body: Arc::new(b"first".to_vec()), stamp, etag: stamp.etag().into(), content_type: "text/plain" };
+        cache.insert(path.clone(), first);
+        assert!(cache.get(&path).unwrap().is_some());
+        fs::write(&path, b"second").unwrap();
+        assert!(cache.get(&path).is_none());
+
+        let missing = dir.join("missing.txt");
+        cache.insert_missing(missing.clone());
+        assert!(cache.get(&missing).unwrap().is_none());
+        fs::write(&missing, b"here now").unwrap();
+        assert!(cache.get(&missing).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
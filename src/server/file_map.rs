@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::ptr;
+#[cfg(unix)]
+use libc;
+use super::file_cache::Stamp;
+
+/// The most copied from a mapping before checking that the file is unchanged.
+pub const MAX_COPY: usize = 64 * 1024;
+
+/// Large files mapped into memory, each mapping shared by every request serving the file while
+/// it stays the same.
+pub struct MappedFiles {
+    threshold: u64,
+    mappings: Mutex<HashMap<PathBuf, Weak<Mapping>>>
+}
+
+impl MappedFiles {
+    /// Maps files of at least `threshold` bytes.
+    pub fn new(threshold: u64) -> MappedFiles {
+        MappedFiles {
+            threshold,
+            mappings: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Whether a file of `len` bytes is to be mapped. Never where mapping is not supported.
+    pub fn wants(&self, len: u64) -> bool {
+        cfg!(unix) && len > 0 && len >= self.threshold && len <= usize::MAX as u64
+    }
+
+    /// The mapping of the file at `path`, just opened as `file` with `stamp`: the one requests
+    /// already use if the file has not changed since, or else a new one. Gives the file back if
+    /// it cannot be mapped.
+    pub fn map(&self, path: &Path, file: File, stamp: Stamp) -> Result<Arc<Mapping>, File> {
+        let mut mappings = self.mappings.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(mapping) = mappings.get(path).and_then(Weak::upgrade) {
+            if mapping.stamp == stamp {
+                return Ok(mapping);
+            }
+        }
+        match Mapping::new(file, stamp) {
+            Ok(mapping) => {
+                let mapping = Arc::new(mapping);
+                mappings.retain(|_, mapping| mapping.strong_count() > 0);
+                mappings.insert(path.to_path_buf(), Arc::downgrade(&mapping));
+                Ok(mapping)
+            },
+            Err((file, e)) => {
+                eprintln!("Error mapping {}:{}", path.display(), e);
+                Err(file)
+            }
+        }
+    }
+}
+
+/// A file mapped read-only into memory. Touching a page of it after the file was truncated
+/// raises SIGBUS, so it is never read directly: the kernel copies from it, failing the copy
+/// instead. After each copy the file is checked to still have the size and modification time it
+/// was mapped with, and one found to have changed fails the response rather than send a mix of
+/// old and new bytes.
+pub struct Mapping {
+    file: File,
+    stamp: Stamp,
+    data: *const u8,
+    len: usize
+}
+
+// the mapped memory is only ever read, and unmapped once no request holds it
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    #[cfg(unix)]
+    fn new(file: File, stamp: Stamp) -> Result<Mapping, (File, io::Error)> {
+        let len = stamp.len as usize;
+        let data = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if data == libc::MAP_FAILED {
+            return Err((file, io::Error::last_os_error()));
+        }
+        unsafe { libc::madvise(data, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mapping { file, stamp, data: data as *const u8, len })
+    }
+
+    #[cfg(not(unix))]
+    fn new(file: File, _stamp: Stamp) -> Result<Mapping, (File, io::Error)> {
+        Err((file, io::Error::other("memory mapping is only supported on Unix")))
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Copies the file from `offset` into `buf`, up to `MAX_COPY` bytes at a time, returning how
+    /// many were copied. Fails with `UnexpectedEof` once the file has changed since it was mapped.
+    pub fn copy_to(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = offset.min(self.len as u64) as usize;
+        let len = buf.len().min(MAX_COPY).min(self.len - start);
+        if len == 0 {
+            return Ok(0);
+        }
+        if self.copy_in_kernel(start, &mut buf[..len])? < len {
+            return Err(changed());
+        }
+        self.check()?;
+        Ok(len)
+    }
+
+    /// Has the kernel copy from the mapping, which fails with `EFAULT` where touching it would
+    /// raise SIGBUS. Reads the file instead where the process may not read its own memory that way.
+    #[cfg(target_os = "linux")]
+    fn copy_in_kernel(&self, start: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let local = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        let remote = libc::iovec { iov_base: unsafe { self.data.add(start) } as *mut libc::c_void, iov_len: buf.len() };
+        let copied = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+        if copied >= 0 {
+            return Ok(copied as usize);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EFAULT) => Err(changed()),
+            Some(libc::ENOSYS) | Some(libc::EPERM) => self.file.read_at(buf, start as u64),
+            _ => Err(e)
+        }
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn copy_in_kernel(&self, start: usize, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read_at(buf, start as u64)
+    }
+
+    #[cfg(not(unix))]
+    fn copy_in_kernel(&self, _start: usize, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(changed())
+    }
+
+    /// Fails with `UnexpectedEof` once the file has changed since it was mapped.
+    pub fn check(&self) -> io::Result<()> {
+        let current = self.file.metadata().ok().and_then(|metadata| Stamp::of(&metadata));
+        if current == Some(self.stamp) {
+            Ok(())
+        } else {
+            Err(changed())
+        }
+    }
+}
+
+fn changed() -> io::Error {
+    io::Error::new(ErrorKind::UnexpectedEof, "file changed while it was sent")
+}
+
+impl Drop for Mapping {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.data as *mut libc::c_void, self.len) };
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::MappedFiles;
+    use server::file_cache::Stamp;
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+
+    #[test]
+    fn mappings_are_shared_until_the_file_is_truncated() {
+        let dir = env::temp_dir().join(format!("rust-http2-server-map-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("large.bin");
+        let body: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &body).unwrap();
+        let mapped = MappedFiles::new(1024);
+        let open = || {
+            let file = File::open(&path).unwrap();
+            let stamp = Stamp::of(&file.metadata().unwrap()).unwrap();
+            mapped.map(&path, file, stamp).ok().unwrap()
+        };
+
+        let first = open();
+        let second = open();
+        assert!(Arc::ptr_eq(&first, &second));
+        let mut buf = [0; 100];
+        assert_eq!(100, first.copy_to(1000, &mut buf).unwrap());
+        assert_eq!(&body[1000..1100], &buf[..]);
+
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(100).unwrap();
+        assert_eq!(ErrorKind::UnexpectedEof, first.copy_to(1000, &mut buf).unwrap_err().kind());
+        // past the pages the file still has, where reading the mapping would raise SIGBUS
+        assert!(first.copy_in_kernel(32 * 1024, &mut buf).map_or(true, |copied| copied < buf.len()));
+        assert!(!Arc::ptr_eq(&first, &open()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,106 +1,320 @@
-use std::path::{Path, PathBuf};
-use std::io::Write;
-use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::io::{self, ErrorKind, Read, Write};
 use std::fs::File;
-use std::fs::metadata;
+use std::sync::Arc;
+use std::time::SystemTime;
 use chrono::{DateTime, TimeZone};
 use chrono::offset::Utc;
 use std::cmp::Ordering;
-use std::time::SystemTime;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::ptr;
+#[cfg(target_os = "linux")]
+use libc;
 use super::content_manager::{ContentHandle, ContentManager};
+use super::file_cache::{Cached, FileCache, Stamp};
+use super::file_map::{MappedFiles, Mapping, MAX_COPY};
 
 pub struct FileSystemAdapter {
-    root: PathBuf
+    root: PathBuf,
+    cache: Option<Arc<FileCache>>,
+    mapped: Option<Arc<MappedFiles>>
 }
 
 impl FileSystemAdapter {
     pub fn new(root: &Path) -> FileSystemAdapter {
-        return FileSystemAdapter {
-            root: root.to_path_buf()
+        FileSystemAdapter {
+            root: root.to_path_buf(),
+            cache: None,
+            mapped: None
         }
     }
-}
 
-impl ContentManager<FileHandle> for FileSystemAdapter {
-    fn find_content(&self, url: &String, accepts_gzip: bool) -> Option<FileHandle> {
-        println!("loading {} gzip={}", url, accepts_gzip);
-        let path = if url.starts_with('/') {
-            let mut temp = url.clone();
-            temp.remove(0);
-            temp
-        } else {
-            url.clone()
-        };
+    /// Serves small files from `cache`, which may be shared with other sites.
+    pub fn cache(mut self, cache: Arc<FileCache>) -> FileSystemAdapter {
+        self.cache = Some(cache);
+        self
+    }
 
-        match get_file_stats(&self.root.join(path.clone())) {
-            Some(FileStats(mod_date, raw_len)) => {
-                if accepts_gzip {
-                    let mut gz_path = path.clone();
-                    gz_path.push_str(".gz");
-                    let gz_file_path = self.root.join(gz_path);
-                    if let Some(FileStats(_, gzipped_len)) = get_file_stats(&gz_file_path) {
-                        match File::open(gz_file_path) {
-                            Ok(file) => {
-                                return Some(FileHandle::new(mod_date.unwrap(), gzipped_len, true, file))
-                            },
-                            Err(_) => {}
-                        }
-                    }
-                }
+    /// Serves files large enough for `mapped` from memory maps shared by the requests for them.
+    pub fn map_large_files(mut self, mapped: Arc<MappedFiles>) -> FileSystemAdapter {
+        self.mapped = Some(mapped);
+        self
+    }
 
-                let file_path = self.root.join(path);
-                println!("file_path={}", file_path.display());
-                match File::open(file_path) {
-                    Ok(file) => Some(FileHandle::new(mod_date.unwrap(), raw_len, false, file)),
-                    Err(e) => {
-                        eprintln!("Error opening {}:{}", url, e);
-                        None
-                    }
+    /// The regular file at `path`, from the cache if it has it, and read into it if it is small
+    /// enough, or mapped if it is large enough.
+    fn open(&self, path: &Path, content_type: &'static str) -> Option<Found> {
+        if let Some(ref cache) = self.cache {
+            match cache.get(path) {
+                Some(Some(cached)) => return Some(Found::cached(cached)),
+                Some(None) => return None,
+                None => {}
+            }
+        }
+        let opened = File::open(path).and_then(|file| file.metadata().map(|metadata| (file, metadata)));
+        let (mut file, metadata) = match opened {
+            Ok((file, metadata)) => (file, metadata),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                if let Some(ref cache) = self.cache {
+                    cache.insert_missing(path.to_path_buf());
                 }
+                return None;
             },
-            None => None
+            Err(e) => {
+                eprintln!("Error opening {}:{}", path.display(), e);
+                return None;
+            }
+        };
+        if !metadata.is_file() {
+            return None;
+        }
+        let stamp = Stamp::of(&metadata)?;
+        match self.cache {
+            Some(ref cache) if cache.fits(stamp.len) => {
+                let mut body = Vec::with_capacity(stamp.len as usize);
+                if let Err(e) = file.read_to_end(&mut body) {
+                    eprintln!("Error reading {}:{}", path.display(), e);
+                    return None;
+                }
+                if body.len() as u64 != stamp.len {
+                    // Changed while it was read: serve what was read, but do not keep it.
+                    let stamp = Stamp { len: body.len() as u64, ..stamp };
+                    return Some(Found::cached(Cached { body: Arc::new(body), stamp, etag: stamp.etag().into(), content_type }));
+                }
+                let cached = Cached { body: Arc::new(body), stamp, etag: stamp.etag().into(), content_type };
+                cache.insert(path.to_path_buf(), cached.clone());
+                Some(Found::cached(cached))
+            },
+            _ => {
+                let body = match self.mapped {
+                    Some(ref mapped) if mapped.wants(stamp.len) => match mapped.map(path, file, stamp) {
+                        Ok(mapping) => Body::Mapped(mapping),
+                        Err(file) => Body::File(file)
+                    },
+                    _ => Body::File(file)
+                };
+                Some(Found { body, stamp, etag: stamp.etag().into(), content_type })
+            }
+        }
+    }
+}
+
+impl ContentManager<FileHandle> for FileSystemAdapter {
+    /// `url` is the request's normalized path; one still climbing out of the root finds nothing.
+    fn find_content(&self, url: &str, accepts_gzip: bool) -> Option<FileHandle> {
+        if Path::new(url).components().any(|component| component == Component::ParentDir) {
+            return None;
         }
+        let path = self.root.join(url.trim_start_matches('/'));
+        let content_type = content_type(&path);
+        let raw = self.open(&path, content_type)?;
+        let mod_date = raw.stamp.mod_time;
+
+        if accepts_gzip {
+            let mut gz_path = path.into_os_string();
+            gz_path.push(".gz");
+            if let Some(gzipped) = self.open(Path::new(&gz_path), content_type) {
+                return Some(FileHandle::new(mod_date, true, gzipped));
+            }
+        }
+        Some(FileHandle::new(mod_date, false, raw))
     }
 }
 
-struct FileStats(Option<SystemTime>, u64);
+/// The error for a file that ended before its Content-Length, so that the connection is closed
+/// rather than kept in use out of step with the client.
+fn cut_short() -> io::Error {
+    io::Error::new(ErrorKind::UnexpectedEof, "file ended before its length")
+}
 
-fn get_file_stats(path: &PathBuf) -> Option<FileStats> {
-    match metadata(path) {
-        Ok(md) => Some(FileStats(md.modified().ok(), md.len())),
-        Err(e) => {
-            eprintln!("Error finding file {}:{}", path.display(), e);
-            return None
+/// The media type served for a file, going by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        _ => "application/octet-stream"
+    }
+}
+
+/// A file found for a request, open, in memory or mapped.
+struct Found {
+    body: Body,
+    stamp: Stamp,
+    etag: Arc<str>,
+    content_type: &'static str
+}
+
+impl Found {
+    fn cached(cached: Cached) -> Found {
+        Found {
+            body: Body::Memory(cached.body),
+            stamp: cached.stamp,
+            etag: cached.etag,
+            content_type: cached.content_type
         }
     }
 }
+
+enum Body {
+    File(File),
+    Memory(Arc<Vec<u8>>),
+    Mapped(Arc<Mapping>)
+}
+
 pub struct FileHandle {
     mod_date: DateTime<Utc>,
     content_length: u64,
     gzipped: bool,
-    file: File
+    etag: Arc<str>,
+    content_type: &'static str,
+    body: Body,
+    /// How much of the body is still to be sent, from where the file is positioned or from the end
+    /// of the in-memory or mapped body.
+    remaining: u64
 }
 
 impl FileHandle {
-    fn new(mod_date: SystemTime, content_len: u64, gzipped: bool, file: File) -> FileHandle {
+    fn new(mod_date: SystemTime, gzipped: bool, found: Found) -> FileHandle {
         FileHandle {
             mod_date: DateTime::from(mod_date),
-            content_length: content_len,
-            gzipped: gzipped,
-            file: file,
+            content_length: found.stamp.len,
+            gzipped,
+            etag: found.etag,
+            content_type: found.content_type,
+            body: found.body,
+            remaining: found.stamp.len
         }
     }
+
+    /// Where the rest of an in-memory or mapped body starts.
+    fn offset(&self) -> u64 {
+        self.content_length - self.remaining
+    }
 }
 
 impl ContentHandle for FileHandle {
-    fn write_to(&mut self, writer: &mut Write) {
-        io::copy(&mut self.file, writer).expect("Error while copying file\n");
+    fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        let read = match self.body {
+            Body::File(ref mut file) => match file.read(&mut buf[..len])? {
+                0 if len > 0 => return Err(cut_short()),
+                read => read
+            },
+            Body::Memory(ref data) => {
+                let start = self.offset() as usize;
+                buf[..len].copy_from_slice(&data[start..start + len]);
+                len
+            },
+            Body::Mapped(ref mapping) => mapping.copy_to(self.offset(), &mut buf[..len])?
+        };
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+
+    fn write_to(&mut self, writer: &mut dyn Write) -> io::Result<()> {
+        match self.body {
+            Body::File(ref mut file) => {
+                let copied = io::copy(&mut Read::by_ref(file).take(self.remaining), writer)?;
+                self.remaining -= copied;
+                if self.remaining > 0 {
+                    return Err(cut_short());
+                }
+            },
+            Body::Memory(ref data) => {
+                writer.write_all(&data[self.offset() as usize..])?;
+                self.remaining = 0;
+            },
+            Body::Mapped(ref mapping) => {
+                // copied out first, so that no write to a slow client happens while the mapping
+                // is being read
+                let mut buf = vec![0; MAX_COPY];
+                while self.remaining > 0 {
+                    let len = MAX_COPY.min(self.remaining.min(usize::MAX as u64) as usize);
+                    let copied = mapping.copy_to(self.content_length - self.remaining, &mut buf[..len])?;
+                    writer.write_all(&buf[..copied])?;
+                    self.remaining -= copied as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Has the kernel copy the file to the socket with sendfile, moving the file's position on
+    /// just as a read would.
+    #[cfg(target_os = "linux")]
+    fn send_to(&mut self, socket: RawFd, count: usize) -> Option<io::Result<usize>> {
+        let count = count.min(self.remaining.min(usize::MAX as u64) as usize);
+        if count == 0 {
+            return Some(Ok(0));
+        }
+        let sent = match self.body {
+            Body::File(ref file) => unsafe { libc::sendfile(socket, file.as_raw_fd(), ptr::null_mut(), count) },
+            // from the file at an offset of its own, as the mapping's file is shared
+            Body::Mapped(ref mapping) => {
+                if let Err(e) = mapping.check() {
+                    return Some(Err(e));
+                }
+                let mut offset = self.offset() as libc::off_t;
+                unsafe { libc::sendfile(socket, mapping.file().as_raw_fd(), &mut offset, count) }
+            },
+            Body::Memory(_) => return None
+        };
+        if sent < 0 {
+            return Some(Err(io::Error::last_os_error()));
+        }
+        if sent == 0 {
+            return Some(Err(cut_short()));
+        }
+        self.remaining -= sent as u64;
+        Some(Ok(sent as usize))
     }
 
     fn is_gzipped(&self) -> bool {
         self.gzipped
     }
 
+    fn etag(&self) -> Option<&str> {
+        Some(&self.etag)
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        Some(self.content_type)
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        match self.body {
+            Body::Memory(ref data) => Some(&data[self.offset() as usize..]),
+            Body::File(_) | Body::Mapped(_) => None
+        }
+    }
+
     fn is_mod_since<TZ: TimeZone>(&self, other: &DateTime<TZ>) -> bool {
         let mod_time = self.mod_time();
 
@@ -118,4 +332,54 @@ impl ContentHandle for FileHandle {
     fn content_length(&self) -> u64 {
        self.content_length
     }
-}
\ No newline at end of file
+}
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::FileSystemAdapter;
+    use server::content_manager::{ContentHandle, ContentManager};
+    use server::file_map::MappedFiles;
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{ErrorKind, Read};
+    use std::sync::Arc;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+
+    #[test]
+    fn files_cut_short_fail_instead_of_ending_early() {
+        let dir = env::temp_dir().join(format!("rust-http2-server-short-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("plain.bin"), vec![1; 1024]).unwrap();
+        fs::write(dir.join("mapped.bin"), vec![2; 4096]).unwrap();
+        let adapter = FileSystemAdapter::new(&dir).map_large_files(Arc::new(MappedFiles::new(4096)));
+
+        for name in &["plain.bin", "mapped.bin"] {
+            let mut handle = adapter.find_content(&format!("/{}", name), false).unwrap();
+            OpenOptions::new().write(true).open(dir.join(name)).unwrap().set_len(100).unwrap();
+            let mut sent = vec![];
+            assert_eq!(ErrorKind::UnexpectedEof, handle.write_to(&mut sent).unwrap_err().kind());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_sent_by_the_kernel_up_to_their_length() {
+        let adapter = FileSystemAdapter::new(Path::new("test_resources"));
+        let mut handle = adapter.find_content("/multi.html", false).unwrap();
+        let (sender, mut receiver) = UnixStream::pair().unwrap();
+
+        let mut sent = 0;
+        loop {
+            match handle.send_to(sender.as_raw_fd(), 100).unwrap().unwrap() {
+                0 => break,
+                count => sent += count
+            }
+        }
+        drop(sender);
+        let mut received = vec![];
+        receiver.read_to_end(&mut received).unwrap();
+        assert_eq!(handle.content_length() as usize, sent);
+        assert_eq!(fs::read("test_resources/multi.html").unwrap(), received);
+    }
+}
@@ -1,126 +1,191 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
 use bufstream::BufStream;
-use chrono::{FixedOffset,DateTime, Utc};
+use chrono::Utc;
+use super::{Options, Site};
+use super::hosts::VirtualHosts;
 use super::content_manager::{ContentHandle, ContentManager};
-use super::http::{parse_request, Request, BadRequest};
-
-pub fn handle_client<H: ContentHandle, S: Read + Write>(stream: S, manager: &ContentManager<H>) {
-    println!("in handle_client");
+use super::http::{parse_request, Method, Request, Response, BadRequest, TlsSession};
 
+pub fn handle_client<H: ContentHandle, S: Read + Write>(stream: S, peer: Option<SocketAddr>, tls: Option<TlsSession>,
+                                                         hosts: &VirtualHosts<H>, options: &Options) -> io::Result<()> {
     let mut buffed = BufStream::new(stream);
     loop {
-        let request = parse_request(&mut buffed);
+        let request = parse_request(&mut buffed, &options.limits);
 
         let keep_alive = match request {
-            Ok(Request::EndRequests()) => false,
-            Ok(Request::Get(url, headers)) => {
-                handle_get(url, headers.accept_encoding_gzip(), headers.if_modified_since(), false, headers.connection_keep_alive(),&mut buffed, manager);
-                headers.connection_keep_alive()
-            }
-            Ok(Request::Head(url, headers)) => {
-                handle_get(url, headers.accept_encoding_gzip(), headers.if_modified_since(), true, headers.connection_keep_alive(),&mut buffed, manager);
-                headers.connection_keep_alive()
-            }
-            Ok(Request::Options(url, _)) => {
-                handle_options(url, &mut buffed, manager);
-                false
+            Ok(None) => false,
+            Ok(Some(mut request)) => {
+                request.client_cert = tls.as_ref().and_then(|tls| tls.client_cert.clone());
+                let keep_alive = keep_alive(&request, options);
+                let (response, content) = answer(&request, tls.as_ref(), hosts);
+                // logged even when the client went away before taking all of it
+                let written = write_response(&mut buffed, &response, keep_alive, content);
+                options.log.log(peer, &request, &response);
+                written?;
+                keep_alive
             }
             Err(BadRequest { code, reason }) => {
                 eprintln!("Error:{}/{}", code, reason);
-                let handler: Option<H> = None;
-                write_response(&mut buffed, code, reason, vec![], false, handler);
+                let response = Response::new(code, reason).with_body("");
+                write_response::<H>(&mut buffed, &response, false, None)?;
                 false
             }
         };
 
         if !keep_alive {
             break;
-        } else {
-            println!("Keep-alive");
         }
     }
+    Ok(())
+}
 
-    println!("end handle_client")
+/// Answers the first request with 503 Service Unavailable and closes the connection, for when
+/// every worker is busy and the queue is full.
+pub fn reject_client<H: ContentHandle, S: Read + Write>(stream: S, peer: Option<SocketAddr>, retry_after: Duration, options: &Options) -> io::Result<()> {
+    let mut buffed = BufStream::new(stream);
+    if let Ok(Some(request)) = parse_request(&mut buffed, &options.limits) {
+        let response = unavailable(retry_after);
+        let written = write_response::<H>(&mut buffed, &response, false, None);
+        options.log.log(peer, &request, &response);
+        written?;
+    }
+    Ok(())
 }
 
-fn handle_get<H: ContentHandle>(url: String, gzip_encoding: bool, if_mod_since: Option<DateTime<FixedOffset>>, suppress_entity: bool, keep_alive: bool, buffed: &mut Write, manager: &ContentManager<H>) {
-    match manager.find_content(&url, gzip_encoding) {
-        Some(handle) => {
-            match if_mod_since {
-                Some(dt) => {
-                    if handle.is_mod_since(&dt) {
-                        write!(buffed, "HTTP/1.1 304 Not Modified\n").expect("Error while writing to output\n");
-                        return;
-                    }
-                },
-                None => {}
-            }
-            let content_length = format!("{}", handle.content_length());
-            let last_modified = handle.mod_time().to_rfc2822();
-            let mut headers: Vec<(&str, &str)> = vec![
-                ("Content-Length", content_length.as_ref()),
-                ("Last-Modified", last_modified.as_ref())
-            ];
-            if handle.is_gzipped() {
-                headers.push(("Content-Encoding", "gzip"));
-            }
-            write_response(buffed, "200", "OK", headers, keep_alive,if suppress_entity {None} else { Some(handle) });
+/// The answer to a request turned away because the server is overloaded.
+pub fn unavailable(retry_after: Duration) -> Response {
+    Response::new(503, "Service Unavailable")
+        .with_header("Retry-After", &retry_after.as_secs().to_string())
+        .with_body("")
+}
+
+/// Whether the connection stays open for another request after this one. Request bodies are
+/// never read, so a request that may have one closes the connection rather than have its body
+/// taken for the next request.
+pub fn keep_alive(request: &Request, options: &Options) -> bool {
+    request.keep_alive()
+        && matches!(request.method, Method::Get | Method::Head)
+        && !has_body(request)
+        && !options.control.is_stopping()
+}
+
+fn has_body(request: &Request) -> bool {
+    request.headers.get("Transfer-Encoding").is_some()
+        || request.headers.get("Content-Length").is_some_and(|len| len.trim() != "0")
+}
+
+/// The response to `request` from the site it is for, after every handler has had its say, and
+/// the content to send as its body. Requests on a TLS connection for a site other than the one
+/// the handshake was for are answered with 421 Misdirected Request.
+pub fn answer<H: ContentHandle>(request: &Request, tls: Option<&TlsSession>, hosts: &VirtualHosts<H>) -> (Response, Option<H>) {
+    let site = match tls {
+        Some(tls) => match hosts.select_tls(request.authority(), tls.server_name.as_deref()) {
+            Some(site) => site,
+            None => return (Response::new(421, "Misdirected Request").with_body(""), None)
+        },
+        None => hosts.select(request.authority(), None)
+    };
+    let (mut response, content) = respond(request, site);
+    for handler in &site.handlers {
+        handler.on_response(request, &mut response);
+    }
+    if request.method == Method::Head {
+        response.body.clear();
+    }
+    (response, content)
+}
+
+/// Gives each handler a chance to answer before falling back to the site's content.
+fn respond<H: ContentHandle>(request: &Request, site: &Site<H>) -> (Response, Option<H>) {
+    for handler in &site.handlers {
+        if let Some(response) = handler.handle(request) {
+            return (response, None);
         }
-        None => {
-            let handler: Option<H> = None;
-            write_response(buffed, "404", "Not Found", vec![], keep_alive, handler);
+    }
+
+    match request.method {
+        Method::Get => handle_get(request, false, site.content.as_ref()),
+        Method::Head => handle_get(request, true, site.content.as_ref()),
+        Method::Options => (handle_options(request, site.content.as_ref()), None),
+        Method::Other(_) => {
+            let response = Response::new(405, "Method Not Allowed")
+                .with_header("Allow", "OPTIONS, GET, HEAD")
+                .with_body("");
+            (response, None)
         }
     }
 }
 
-fn handle_options<H: ContentHandle>(url_op: Option<String>, buffed: &mut Write, manager: &ContentManager<H>) {
-    match url_op {
-        None => {
-            let headers: Vec<(&str, &str)> = vec![
-                ("Allow", "OPTIONS, GET, HEAD"),
-                ("Content-Length", "0")
-            ];
-            let handler: Option<H> = None;
-            write_response(buffed, "200", "OK", headers, false,handler);
-        }
-        Some(url) => {
-            match manager.find_content(&url, false) {
-                Some(_) => {
-                    let headers: Vec<(&str, &str)> = vec![
-                        ("Allow", "OPTIONS, GET, HEAD"),
-                        ("Content-Length", "0")
-                    ];
-                    let handler: Option<H> = None;
-                    write_response(buffed, "200", "OK", headers, false, handler);
-                }
-                None => {
-                    let handler: Option<H> = None;
-                    write_response(buffed, "404", "Not Found", vec![], false, handler);
+fn handle_get<H: ContentHandle>(request: &Request, suppress_entity: bool, manager: &dyn ContentManager<H>) -> (Response, Option<H>) {
+    match manager.find_content(request.path(), request.headers.accept_encoding_gzip()) {
+        Some(handle) => {
+            // If-None-Match, when sent, is the only condition looked at
+            let not_modified = match handle.etag().and_then(|etag| request.headers.if_none_match(etag)) {
+                Some(matched) => matched,
+                None => request.headers.if_modified_since().is_some_and(|dt| handle.is_mod_since(&dt))
+            };
+            if not_modified {
+                let mut response = Response::new(304, "Not Modified");
+                if let Some(etag) = handle.etag() {
+                    response.headers.append("ETag", etag);
                 }
+                return (response, None);
             }
+            let mut response = Response::new(200, "OK")
+                .with_header("Content-Length", &handle.content_length().to_string())
+                .with_header("Last-Modified", &handle.mod_time().to_rfc2822());
+            if let Some(content_type) = handle.content_type() {
+                response.headers.append("Content-Type", content_type);
+            }
+            if let Some(etag) = handle.etag() {
+                response.headers.append("ETag", etag);
+            }
+            if handle.is_gzipped() {
+                response.headers.append("Content-Encoding", "gzip");
+            }
+            (response, if suppress_entity { None } else { Some(handle) })
         }
+        None => (Response::new(404, "Not Found").with_body(""), None)
     }
 }
 
-fn write_response<H: ContentHandle>(buffed: &mut Write, code: &str, text: &str, headers: Vec<(&str, &str)>, keep_alive: bool, handler: Option<H>) {
-    write!(buffed, "HTTP/1.1 {} {}\n", code, text).expect("Error while writing to output\n");
-    if keep_alive {
-        write!(buffed, "Connection: keep-alive\n").expect("Error while writing to output\n");
+fn handle_options<H: ContentHandle>(request: &Request, manager: &dyn ContentManager<H>) -> Response {
+    let found = request.target == "*" || manager.find_content(request.path(), false).is_some();
+    if found {
+        Response::new(200, "OK")
+            .with_header("Allow", "OPTIONS, GET, HEAD")
+            .with_body("")
     } else {
-        write!(buffed, "Connection: close\n").expect("Error while writing to output\n");
+        Response::new(404, "Not Found").with_body("")
     }
-    write!(buffed, "Date: {}\n", Utc::now().to_rfc2822()).expect("Error while writing to output\n");
-    write!(buffed, "Server: rust-http2-server\n").expect("Error while writing to output\n");
-    for (name, value) in headers {
-        writeln!(buffed, "{}: {}", name, value).expect("Error while writing header\n");
-    }
-    write!(buffed, "\n").expect("Error while terminating the headers\n");
+}
+
+fn write_response<H: ContentHandle>(buffed: &mut dyn Write, response: &Response, keep_alive: bool, handler: Option<H>) -> io::Result<()> {
+    write_head(buffed, response, keep_alive)?;
     match handler {
-        Some(mut h) => h.write_to(buffed),
-        None => {}
+        Some(mut h) => h.write_to(buffed)?,
+        None => buffed.write_all(&response.body)?
     }
 
-    buffed.flush().expect("Error in flush");
+    buffed.flush()
+}
+
+/// Writes the status line and headers, and the blank line ending them.
+pub fn write_head(buffed: &mut dyn Write, response: &Response, keep_alive: bool) -> io::Result<()> {
+    writeln!(buffed, "HTTP/1.1 {} {}", response.status, response.reason)?;
+    if keep_alive {
+        writeln!(buffed, "Connection: keep-alive")?;
+    } else {
+        writeln!(buffed, "Connection: close")?;
+    }
+    writeln!(buffed, "Date: {}", Utc::now().to_rfc2822())?;
+    writeln!(buffed, "Server: rust-http2-server")?;
+    for (name, value) in &response.headers {
+        writeln!(buffed, "{}: {}", name, value)?;
+    }
+    writeln!(buffed)
 }
 
 #[cfg(test)]
@@ -129,4 +194,4 @@ mod tests {
     fn read_header_works() {
 
     }
-}
\ No newline at end of file
+}
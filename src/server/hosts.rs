@@ -0,0 +1,168 @@
+use std::ptr;
+use super::Site;
+use super::content_manager::ContentHandle;
+
+/// A name a virtual host answers to: either exact, or `*.example.com` for any subdomain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostName {
+    Exact(String),
+    /// The suffix including its leading dot, such as `.example.com`.
+    Wildcard(String)
+}
+
+impl HostName {
+    pub fn parse(name: &str) -> HostName {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if name.starts_with("*.") {
+            HostName::Wildcard(name[1..].to_string())
+        } else {
+            HostName::Exact(name)
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        match *self {
+            HostName::Exact(ref name) => name == host,
+            HostName::Wildcard(ref suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str())
+        }
+    }
+}
+
+/// Picks the site for each request by host name, falling back on a default site.
+pub struct VirtualHosts<H: ContentHandle> {
+    default: Site<H>,
+    sites: Vec<(Vec<HostName>, Site<H>)>
+}
+
+impl<H: ContentHandle> VirtualHosts<H> {
+    pub fn new(default: Site<H>) -> VirtualHosts<H> {
+        VirtualHosts { default, sites: vec![] }
+    }
+
+    pub fn add(&mut self, names: Vec<HostName>, site: Site<H>) {
+        self.sites.push((names, site));
+    }
+
+    /// Selects by the `Host` or `:authority` of the request, then by the TLS server name when the
+    /// request has neither. Exact names win over wildcards, and longer wildcards over shorter ones.
+    pub fn select(&self, authority: Option<&str>, server_name: Option<&str>) -> &Site<H> {
+        match authority.or(server_name) {
+            Some(host) => self.find(&normalize(host)).unwrap_or(&self.default),
+            None => &self.default
+        }
+    }
+
+    /// The site for a request on a TLS connection, which has to be the one the handshake chose by
+    /// server name, as that decided the certificate and whether clients had to show one. `None`
+    /// when the request is for another site.
+    pub fn select_tls(&self, authority: Option<&str>, server_name: Option<&str>) -> Option<&Site<H>> {
+        let site = self.select(authority, server_name);
+        if ptr::eq(site, self.select(None, server_name)) {
+            Some(site)
+        } else {
+            None
+        }
+    }
+
+    fn find(&self, host: &str) -> Option<&Site<H>> {
+        let exact = self.sites.iter().find(|(names, _)| {
+            names.iter().any(|name| match *name {
+                HostName::Exact(_) => name.matches(host),
+                HostName::Wildcard(_) => false
+            })
+        });
+        if let Some((_, site)) = exact {
+            return Some(site);
+        }
+
+        self.sites.iter()
+            .filter_map(|(names, site)| {
+                names.iter()
+                    .filter_map(|name| match *name {
+                        HostName::Wildcard(ref suffix) if name.matches(host) => Some(suffix.len()),
+                        _ => None
+                    })
+                    .max()
+                    .map(|len| (len, site))
+            })
+            .max_by_key(|&(len, _)| len)
+            .map(|(_, site)| site)
+    }
+}
+
+/// Lower cases the host and drops any port and trailing dot.
+pub fn normalize(authority: &str) -> String {
+    let host = if authority.starts_with('[') {
+        match authority.find(']') {
+            Some(end) => &authority[..end + 1],
+            None => authority
+        }
+    } else {
+        match authority.rfind(':') {
+            Some(index) => &authority[..index],
+            None => authority
+        }
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostName, VirtualHosts};
+    use server::Site;
+    use server::file_system::{FileHandle, FileSystemAdapter};
+    use std::path::Path;
+
+    fn site(root: &str) -> Site<FileHandle> {
+        Site { content: Box::new(FileSystemAdapter::new(Path::new(root))), handlers: vec![] }
+    }
+
+    fn hosts() -> VirtualHosts<FileHandle> {
+        let mut hosts = VirtualHosts::new(site("test_resources"));
+        hosts.add(vec![HostName::parse("*.example.com")], site("test_resources/example"));
+        hosts.add(vec![HostName::parse("www.example.com"), HostName::parse("example.com")], site("test_resources/www"));
+        hosts.add(vec![HostName::parse("*.api.example.com")], site("test_resources/api"));
+        hosts
+    }
+
+    fn serves_test_resources(site: &Site<FileHandle>) -> bool {
+        site.content.find_content("/index.html", false).is_some()
+    }
+
+    #[test]
+    fn exact_names_beat_wildcards() {
+        let hosts = hosts();
+        assert!(::std::ptr::eq(&hosts.sites[1].1, hosts.select(Some("WWW.Example.com:8080"), None)));
+        assert!(::std::ptr::eq(&hosts.sites[1].1, hosts.select(Some("example.com."), None)));
+    }
+
+    #[test]
+    fn longest_wildcard_wins() {
+        let hosts = hosts();
+        assert!(::std::ptr::eq(&hosts.sites[0].1, hosts.select(Some("blog.example.com"), None)));
+        assert!(::std::ptr::eq(&hosts.sites[2].1, hosts.select(Some("v1.api.example.com"), None)));
+    }
+
+    #[test]
+    fn unknown_and_missing_hosts_use_the_default() {
+        let hosts = hosts();
+        assert!(serves_test_resources(hosts.select(Some("other.org"), None)));
+        assert!(serves_test_resources(hosts.select(Some("[::1]:8080"), None)));
+        assert!(serves_test_resources(hosts.select(None, None)));
+    }
+
+    #[test]
+    fn server_name_is_used_without_a_host_header() {
+        let hosts = hosts();
+        assert!(::std::ptr::eq(&hosts.sites[0].1, hosts.select(None, Some("blog.example.com"))));
+    }
+
+    #[test]
+    fn tls_requests_stay_on_the_site_of_their_server_name() {
+        let hosts = hosts();
+        assert!(::std::ptr::eq(&hosts.sites[1].1, hosts.select_tls(Some("example.com:443"), Some("www.example.com")).unwrap()));
+        assert!(hosts.select_tls(Some("www.example.com"), Some("other.org")).is_none());
+        assert!(hosts.select_tls(Some("www.example.com"), None).is_none());
+        assert!(serves_test_resources(hosts.select_tls(Some("other.org"), None).unwrap()));
+    }
+}
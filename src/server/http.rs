@@ -1,121 +1,436 @@
 use std::result::Result;
-use std::collections::HashMap;
-use std::io::{Write, BufRead};
+use std::io;
+use std::io::{BufRead, ErrorKind, Read};
+use std::slice;
 use chrono::{FixedOffset,DateTime};
 
-#[derive(Debug)]
+/// Header fields in the order they were received, with case-insensitive lookup. Repeated fields
+/// are kept as separate entries rather than overwriting each other.
+#[derive(Debug, Clone, Default)]
 pub struct Headers {
-    headers: HashMap<String, String>
+    entries: Vec<(String, String)>
 }
 
 impl Headers {
+    pub fn new() -> Headers {
+        Headers { entries: vec![] }
+    }
+
+    /// The first value of the named header.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of the named header, in the order received.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a value, keeping any existing values of the same header.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Replaces all values of the header with a single value.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, (String, String)> {
+        self.entries.iter()
+    }
+
+    /// The comma separated elements of every value of a list header such as `Accept-Encoding`.
+    fn list_values(&self, name: &str) -> Vec<&str> {
+        self.get_all(name).into_iter()
+            .flat_map(|value| value.split(','))
+            .map(|element| element.trim())
+            .filter(|element| !element.is_empty())
+            .collect()
+    }
+
     pub fn accept_encoding_gzip(&self) -> bool {
-        match self.headers.get("Accept-Encoding") {
-            Some(encoding) => encoding.contains("gzip"),
-            None => false
-        }
+        self.list_values("Accept-Encoding").into_iter().any(|element| {
+            let mut params = element.split(';').map(|p| p.trim());
+            match params.next() {
+                Some(coding) if coding.eq_ignore_ascii_case("gzip") || coding == "*" => {
+                    !params.any(is_zero_quality)
+                },
+                _ => false
+            }
+        })
     }
 
     pub fn if_modified_since(&self) -> Option<DateTime<FixedOffset>> {
-        match self.headers.get("If-Modified-Since") {
-            Some(date_str) => parse_if_mod_by(date_str),
-            None => None
-        }
+        self.get("If-Modified-Since").and_then(parse_if_mod_by)
+    }
+
+    /// Whether If-None-Match names `etag`, or `*`, comparing weakly. `None` without the header.
+    pub fn if_none_match(&self, etag: &str) -> Option<bool> {
+        self.get("If-None-Match")?;
+        let etag = etag.trim_start_matches("W/");
+        Some(self.list_values("If-None-Match").into_iter()
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag))
     }
 
     pub fn connection_keep_alive(&self) -> bool {
-        match self.headers.get("Connection") {
-            Some(value) => value.contains("keep-alive"),
-            None => true
+        !self.list_values("Connection").into_iter().any(|token| token.eq_ignore_ascii_case("close"))
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.get("Host")
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.get("Content-Length").and_then(|value| value.trim().parse().ok())
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a (String, String);
+    type IntoIter = slice::Iter<'a, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+fn is_zero_quality(param: &str) -> bool {
+    match param.find('=') {
+        Some(index) if param[..index].trim().eq_ignore_ascii_case("q") => {
+            match param[index + 1..].trim().parse::<f32>() {
+                Ok(q) => q <= 0.0,
+                Err(_) => false
+            }
+        },
+        _ => false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Options,
+    Other(String)
+}
+
+impl Method {
+    fn parse(method: &str) -> Method {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "OPTIONS" => Method::Options,
+            _ => Method::Other(method.to_string())
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Options => "OPTIONS",
+            Method::Other(ref method) => method
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    Http2
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2.0"
+        }
+    }
+}
+
+/// A request as seen by the content handlers, independent of the protocol it arrived on.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// The request target: a path, or `*` for a server-wide `OPTIONS`.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    /// The verified certificate of a TLS client, when client authentication is on.
+    pub client_cert: Option<ClientCert>
+}
+
+impl Request {
+    /// The target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(index) => &self.target[..index],
+            None => &self.target
+        }
+    }
+
+    /// The `:authority` of an HTTP/2 request, or the `Host` header of an HTTP/1 one.
+    pub fn authority(&self) -> Option<&str> {
+        self.headers.get(":authority").or_else(|| self.headers.host())
+    }
+
+    /// Whether the connection stays open after this request. HTTP/1.0 clients have to ask for it.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http10 => self.headers.list_values("Connection").into_iter()
+                .any(|token| token.eq_ignore_ascii_case("keep-alive")),
+            _ => self.headers.connection_keep_alive()
         }
     }
 }
 
-pub enum Request {
-    EndRequests(),
-    Get(String, Headers),
-    Head(String, Headers),
-    Options(Option<String>, Headers)
+/// What a verified client certificate says about its holder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Such as `CN=billing,O=Example Corp`.
+    pub subject: String,
+    /// DNS names and IP addresses from the subject alternative names.
+    pub alt_names: Vec<String>
 }
 
+/// What the TLS handshake of a connection settled, which each of its requests is held to.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSession {
+    /// The server name the client asked for, which chose the certificate and how clients are verified.
+    pub server_name: Option<String>,
+    pub client_cert: Option<ClientCert>
+}
+
+/// The status and header fields of a response. Handlers can supply a small body of their own;
+/// file content is streamed from a `ContentHandle` instead.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub reason: &'static str,
+    pub headers: Headers,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    pub fn new(status: u16, reason: &'static str) -> Response {
+        Response { status, reason, headers: Headers::new(), body: vec![] }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Sets the body and its `Content-Length`.
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        let length = self.body.len().to_string();
+        self.headers.set("Content-Length", &length);
+        self
+    }
+}
+
+/// Upper bounds on the request head, so a client cannot make a worker buffer without end.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_request_line: usize,
+    pub max_header_bytes: usize,
+    pub max_headers: usize
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_header_bytes: 64 * 1024,
+            max_headers: 100
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct BadRequest {
-    pub code: &'static str,
+    pub code: u16,
     pub reason: &'static str
 }
 
-pub fn parse_request<S: BufRead + Write>(buffed: &mut S) -> Result<Request, BadRequest> {
+/// Reads the next HTTP/1 request from the stream. `Ok(None)` means the client closed the connection
+/// or let it sit idle past the read timeout.
+pub fn parse_request<S: BufRead>(buffed: &mut S, limits: &Limits) -> Result<Option<Request>, BadRequest> {
     let mut line_buff = String::new();
-    let request_line = match buffed.read_line(&mut line_buff) {
-        Ok(0) => return Ok(Request::EndRequests()),
-        Ok(_) => parse_request_line(&line_buff),
+    let (method, target, version) = match read_bounded_line(buffed, &mut line_buff, limits.max_request_line) {
+        Ok(0) => return Ok(None),
+        Ok(_) if !line_buff.ends_with('\n') => {
+            return Err(BadRequest { code: 414, reason: "URI Too Long" })
+        },
+        Ok(_) => parse_request_line(&line_buff)?,
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Ok(None),
         Err(e) => {
             eprintln!("Bad request line:{}", e);
             return Err(BadRequest {
-                code: "400",
+                code: 400,
                 reason: "Request line not understood",
             })
         }
     };
 
-    match request_line {
-        Ok((ref method, ref url)) if "GET".eq(method) => {
-            let headers = parse_headers(buffed);
-            return Ok(Request::Get(url.clone(), Headers { headers }));
-        }
-        Ok((ref method, ref url)) if "HEAD".eq(method) => {
-            let headers = parse_headers(buffed);
-            return Ok(Request::Head(url.clone(), Headers { headers }));
-        }
-        Ok((ref method, ref url)) if "OPTIONS".eq(method) && "*".eq(url) => {
-            let headers = parse_headers(buffed);
-            return Ok(Request::Options(None, Headers { headers }));
-        }
-        Ok((ref method, ref url)) if "OPTIONS".eq(method) => {
-            let headers = parse_headers(buffed);
-            return Ok(Request::Options(Some(url.clone()), Headers { headers }));
-        }
-        Ok((_, _)) => {
-            Err(BadRequest { code: "405", reason: "Method not supported"})
+    let headers = parse_headers(buffed, limits)?;
+    Ok(Some(Request { method: Method::parse(&method), target, version, headers, client_cert: None }))
+}
+
+/// Parses the request at the start of `input` once its whole head has arrived, returning it with
+/// the number of bytes it took. `Ok(None)` means more is needed.
+pub fn parse_buffered(input: &[u8], limits: &Limits) -> Result<Option<(Request, usize)>, BadRequest> {
+    match head_length(input) {
+        Some(length) => {
+            let mut head = &input[..length];
+            parse_request(&mut head, limits).map(|request| request.map(|request| (request, length)))
+        },
+        None if input.len() >= limits.max_request_line && !input[..limits.max_request_line].contains(&b'\n') => {
+            Err(BadRequest { code: 414, reason: "URI Too Long" })
+        },
+        None if input.len() > limits.max_request_line + limits.max_header_bytes => {
+            Err(BadRequest { code: 431, reason: "Request Header Fields Too Large" })
+        },
+        None => Ok(None)
+    }
+}
+
+/// Where the head ends: after the first line that `parse_headers` takes for the blank one.
+fn head_length(input: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    let mut request_line = true;
+    while let Some(end) = input[start..].iter().position(|&b| b == b'\n') {
+        let line = end + 1;
+        start += line;
+        if !request_line && line < 3 {
+            return Some(start);
         }
-        Err(bad_request) => Err(bad_request)
+        request_line = false;
     }
+    None
 }
 
-fn parse_request_line(input: &String) -> Result<(String, String), BadRequest> {
-    let mut parts = input.split(' ');
-    return match (parts.next(), parts.next()) {
-        (Some(method), Some(url)) => Ok((method.to_string(), url.to_string())),
-        (Some(method), None) => {
+fn parse_request_line(input: &str) -> Result<(String, String, Version), BadRequest> {
+    let mut parts = input.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(url), version) => {
+            let version = match version {
+                Some("HTTP/1.1") => Version::Http11,
+                Some("HTTP/1.0") | None => Version::Http10,
+                Some(_) => return Err(BadRequest { code: 505, reason: "HTTP Version Not Supported" })
+            };
+            Ok((method.to_string(), normalize_target(url)?, version))
+        },
+        (Some(method), None, _) => {
             eprintln!("No URL:method={}", method);
-            Err(BadRequest { code: "400", reason: "" })
+            Err(BadRequest { code: 400, reason: "Bad Request" })
         },
-        _ => Err(BadRequest { code: "400", reason: "" })
+        _ => Err(BadRequest { code: 400, reason: "Bad Request" })
     }
 }
 
-fn parse_headers(reader: &mut BufRead) -> HashMap<String, String> {
-    let mut headers = HashMap::new();
+/// The target with duplicate slashes merged and `.` and `..` segments resolved, so that every
+/// handler and the content see the same path. A `..` climbing above the root is refused.
+fn normalize_target(target: &str) -> Result<String, BadRequest> {
+    let bad_request = BadRequest { code: 400, reason: "Bad Request" };
+    if target == "*" {
+        return Ok(target.to_string());
+    }
+    if !target.starts_with('/') {
+        return Err(bad_request);
+    }
+    let (path, query) = match target.find('?') {
+        Some(index) => target.split_at(index),
+        None => (target, "")
+    };
+    let mut segments = vec![];
+    let mut directory = false;
+    for segment in path.split('/').skip(1) {
+        directory = true;
+        match segment {
+            "" | "." => {},
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(bad_request);
+                }
+            },
+            segment => {
+                segments.push(segment);
+                directory = false;
+            }
+        }
+    }
+    let mut normalized = String::with_capacity(target.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if directory || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized.push_str(query);
+    Ok(normalized)
+}
+
+fn read_bounded_line<R: BufRead + ?Sized>(reader: &mut R, line: &mut String, limit: usize) -> io::Result<usize> {
+    reader.take(limit as u64).read_line(line)
+}
+
+fn parse_headers(reader: &mut dyn BufRead, limits: &Limits) -> Result<Headers, BadRequest> {
+    let too_large = BadRequest { code: 431, reason: "Request Header Fields Too Large" };
+    let mut headers = Headers::new();
+    let mut remaining = limits.max_header_bytes;
     loop {
         let mut line = String::new();
-        match reader.read_line(&mut line) {
+        match read_bounded_line(reader, &mut line, remaining) {
+            Ok(0) if remaining == 0 => return Err(too_large),
             Ok(0) => break,
+            Ok(_) if !line.ends_with('\n') => return Err(too_large),
             Ok(_) if line.len() < 3 => break,
-            Ok(_) => {
-                match line.find(':') {
-                    Some(index) => {
-                        let (name, value) = line.split_at(index);
-                        let mut value_string = value.to_string();
-                        value_string.remove(0);
-
-                        headers.insert(name.to_string(), value_string.trim().to_string());
-                    },
-                    None => {}
+            Ok(read) => {
+                remaining -= read;
+                if headers.len() == limits.max_headers {
+                    return Err(too_large);
+                }
+                if let Some(index) = line.find(':') {
+                    let (name, value) = line.split_at(index);
+                    headers.append(name.trim(), value[1..].trim());
                 }
             },
-            Err(e) => print!("Error reading header line:{}", e)
+            Err(e) => {
+                eprintln!("Error reading header line:{}", e);
+                break
+            }
         }
     }
-    return headers;
+    Ok(headers)
 }
 
 fn parse_if_mod_by(date_str: &str) -> Option<DateTime<FixedOffset>> {
@@ -130,22 +445,38 @@ fn parse_if_mod_by(date_str: &str) -> Option<DateTime<FixedOffset>> {
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_request_line,parse_headers};
+    use super::{normalize_target, parse_buffered, parse_request_line, parse_headers, Limits, Version};
     use std::io::BufReader;
 
     #[test]
     fn it_works() {
-        let output = parse_request_line(&"GET / HTTP/1.1\r\n".to_string());
+        let output = parse_request_line("GET / HTTP/1.1\r\n");
         match output {
-            Ok((method, url)) => { assert_eq!(method, "GET"); assert_eq!(url, "/")},
-            _ => assert!(false)
+            Ok((method, url, version)) => {
+                assert_eq!(method, "GET");
+                assert_eq!(url, "/");
+                assert_eq!(version, Version::Http11)
+            },
+            Err(_) => panic!("Request line not parsed")
         }
     }
 
+    #[test]
+    fn targets_are_normalized() {
+        assert_eq!("/private/secret", normalize_target("//private//secret").unwrap());
+        assert_eq!("/private/secret", normalize_target("/./private/./secret").unwrap());
+        assert_eq!("/private/secret?a=/../b", normalize_target("/public/../private/secret?a=/../b").unwrap());
+        assert_eq!("/private/", normalize_target("/private/secret/..").unwrap());
+        assert_eq!("/", normalize_target("/a/..").unwrap());
+        assert_eq!("*", normalize_target("*").unwrap());
+        assert_eq!(400, normalize_target("/a/../../etc/passwd").unwrap_err().code);
+        assert_eq!(400, normalize_target("index.html").unwrap_err().code);
+    }
+
     #[test]
     fn parse_headers_handles_empty_header() {
         let mut input = BufReader::new("\r\n\r\n".as_bytes());
-        let output = parse_headers(&mut input);
+        let output = parse_headers(&mut input, &Limits::default()).unwrap();
 
         assert_eq!(0, output.len());
     }
@@ -153,19 +484,103 @@ mod tests {
     #[test]
     fn parse_headers_handles_single_header() {
         let mut input = BufReader::new("Dummy: test\r\n\r\n".as_bytes());
-        let output = parse_headers(&mut input);
+        let output = parse_headers(&mut input, &Limits::default()).unwrap();
 
         assert_eq!(1, output.len());
-        assert_eq!(output.get(&"Dummy".to_string()), Some(&"test".to_string()));
+        assert_eq!(output.get("Dummy"), Some("test"));
     }
 
     #[test]
     fn parse_headers_handles_2_headers() {
         let mut input = BufReader::new("Dummy: test\r\nDummy2: test2\r\n\r\n".as_bytes());
-        let output = parse_headers(&mut input);
+        let output = parse_headers(&mut input, &Limits::default()).unwrap();
 
         assert_eq!(2, output.len());
-        assert_eq!(output.get(&"Dummy".to_string()), Some(&"test".to_string()));
-        assert_eq!(output.get(&"Dummy2".to_string()), Some(&"test2".to_string()));
+        assert_eq!(output.get("Dummy"), Some("test"));
+        assert_eq!(output.get("Dummy2"), Some("test2"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn headers_are_case_insensitive() {
+        let mut input = BufReader::new("accept-encoding: gzip, deflate\r\n\r\n".as_bytes());
+        let output = parse_headers(&mut input, &Limits::default()).unwrap();
+
+        assert_eq!(output.get("Accept-Encoding"), Some("gzip, deflate"));
+        assert!(output.accept_encoding_gzip());
+    }
+
+    #[test]
+    fn repeated_headers_are_kept() {
+        let mut input = BufReader::new("Accept-Encoding: br\r\naccept-encoding: gzip\r\n\r\n".as_bytes());
+        let output = parse_headers(&mut input, &Limits::default()).unwrap();
+
+        assert_eq!(output.get_all("ACCEPT-ENCODING"), vec!["br", "gzip"]);
+        assert!(output.accept_encoding_gzip());
+    }
+
+    #[test]
+    fn gzip_with_zero_quality_is_refused() {
+        let mut input = BufReader::new("Accept-Encoding: gzip;q=0, identity\r\n\r\n".as_bytes());
+        let output = parse_headers(&mut input, &Limits::default()).unwrap();
+
+        assert!(!output.accept_encoding_gzip());
+    }
+
+    #[test]
+    fn connection_close_ends_keep_alive() {
+        let mut input = BufReader::new("connection: Close\r\n\r\n".as_bytes());
+        let output = parse_headers(&mut input, &Limits::default()).unwrap();
+
+        assert!(!output.connection_keep_alive());
+    }
+
+    #[test]
+    fn parse_headers_enforces_header_count() {
+        let mut input = BufReader::new("A: 1\r\nB: 2\r\nC: 3\r\n\r\n".as_bytes());
+        let limits = Limits { max_headers: 2, ..Limits::default() };
+
+        match parse_headers(&mut input, &limits) {
+            Err(bad_request) => assert_eq!(431, bad_request.code),
+            Ok(_) => panic!("Too many headers accepted")
+        }
+    }
+
+    #[test]
+    fn parse_headers_enforces_header_bytes() {
+        let mut input = BufReader::new("Dummy: a long value\r\n\r\n".as_bytes());
+        let limits = Limits { max_header_bytes: 10, ..Limits::default() };
+
+        match parse_headers(&mut input, &limits) {
+            Err(bad_request) => assert_eq!(431, bad_request.code),
+            Ok(_) => panic!("Oversized header accepted")
+        }
+    }
+
+    #[test]
+    fn parse_buffered_waits_for_the_whole_head() {
+        let limits = Limits::default();
+        assert!(parse_buffered(b"GET / HTTP/1.1\r\nHost: a", &limits).unwrap().is_none());
+
+        let input = b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nGET /b";
+        let (request, length) = parse_buffered(input, &limits).unwrap().unwrap();
+        assert_eq!(request.target, "/a");
+        assert_eq!(request.headers.get("Host"), Some("a"));
+        assert_eq!(&input[length..], b"GET /b");
+    }
+
+    #[test]
+    fn parse_buffered_enforces_limits_before_the_head_is_complete() {
+        let limits = Limits { max_request_line: 16, max_header_bytes: 32, ..Limits::default() };
+        match parse_buffered(&[b'a'; 16], &limits) {
+            Err(bad_request) => assert_eq!(414, bad_request.code),
+            _ => panic!("Overlong request line accepted")
+        }
+
+        let mut input = b"GET / HTTP/1.1\r\n".to_vec();
+        input.extend(vec![b'a'; 40]);
+        match parse_buffered(&input, &limits) {
+            Err(bad_request) => assert_eq!(431, bad_request.code),
+            _ => panic!("Oversized head accepted")
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::Duration;
+use super::content_manager::Handler;
+use super::hosts::normalize;
+use super::http::{Request, Response};
+use super::locations::redirect_reason;
+
+/// The status requests are redirected to HTTPS with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectStatus {
+    /// 301, which clients may turn into a GET.
+    MovedPermanently,
+    /// 308, which keeps the method and body.
+    PermanentRedirect
+}
+
+impl RedirectStatus {
+    fn code(self) -> u16 {
+        match self {
+            RedirectStatus::MovedPermanently => 301,
+            RedirectStatus::PermanentRedirect => 308
+        }
+    }
+}
+
+/// Sends requests on a plain HTTP listener to the same URL on the HTTPS listener, except for
+/// paths that have to stay reachable over HTTP.
+#[derive(Debug, Clone)]
+pub struct HttpsRedirect {
+    status: RedirectStatus,
+    except: Vec<String>,
+    /// The port of the HTTPS listener, left out of the URL when it is 443.
+    port: u16,
+    /// The host to send requests naming none to.
+    default_host: String
+}
+
+impl HttpsRedirect {
+    /// Redirects every path with 301 Moved Permanently.
+    pub fn new() -> HttpsRedirect {
+        HttpsRedirect { status: RedirectStatus::MovedPermanently, except: vec![], port: 443, default_host: "localhost".to_string() }
+    }
+
+    /// 308 Permanent Redirect keeps the method and body, where clients may turn a 301 into a GET.
+    pub fn status(mut self, status: RedirectStatus) -> HttpsRedirect {
+        self.status = status;
+        self
+    }
+
+    /// Serves paths starting with `prefix` over HTTP instead, such as `/.well-known/acme-challenge/`.
+    pub fn except(mut self, prefix: &str) -> HttpsRedirect {
+        self.except.push(prefix.to_string());
+        self
+    }
+
+    /// Points the redirects at the HTTPS listener once it is bound.
+    pub fn to(mut self, default_host: &str, port: u16) -> HttpsRedirect {
+        self.default_host = default_host.to_string();
+        self.port = port;
+        self
+    }
+
+    fn location(&self, request: &Request) -> String {
+        let host = request.authority().map(normalize).unwrap_or_else(|| self.default_host.clone());
+        if self.port == 443 {
+            format!("https://{}{}", host, request.target)
+        } else {
+            format!("https://{}:{}{}", host, self.port, request.target)
+        }
+    }
+}
+
+impl Default for HttpsRedirect {
+    fn default() -> HttpsRedirect {
+        HttpsRedirect::new()
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn handle(&self, request: &Request) -> Option<Response> {
+        if self.except.iter().any(|prefix| request.path().starts_with(prefix.as_str())) {
+            return None;
+        }
+        Some(Response::new(self.status.code(), redirect_reason(self.status.code()))
+            .with_header("Location", &self.location(request))
+            .with_body(""))
+    }
+}
+
+/// The `Strict-Transport-Security` header, telling browsers to use nothing but HTTPS for the host.
+#[derive(Debug, Clone)]
+pub struct Hsts {
+    max_age: Duration,
+    include_subdomains: bool,
+    preload: bool
+}
+
+impl Hsts {
+    pub fn new(max_age: Duration) -> Hsts {
+        Hsts { max_age, include_subdomains: false, preload: false }
+    }
+
+    /// Covers every subdomain of the host as well.
+    pub fn include_subdomains(mut self) -> Hsts {
+        self.include_subdomains = true;
+        self
+    }
+
+    /// Asks to be included in browsers' preload lists, which also needs a max-age of a year and
+    /// subdomains included.
+    pub fn preload(mut self) -> Hsts {
+        self.preload = true;
+        self
+    }
+
+    fn value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+impl Handler for Hsts {
+    fn on_response(&self, _request: &Request, response: &mut Response) {
+        response.headers.set("Strict-Transport-Security", &self.value());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hsts, HttpsRedirect, RedirectStatus};
+    use server::content_manager::Handler;
+    use server::http::{Headers, Method, Request, Response, Version};
+    use std::time::Duration;
+
+    fn request(target: &str, host: Option<&str>) -> Request {
+        let mut headers = Headers::new();
+        if let Some(host) = host {
+            headers.append("Host", host);
+        }
+        Request { method: Method::Get, target: target.to_string(), version: Version::Http11, headers, client_cert: None }
+    }
+
+    #[test]
+    fn redirects_to_the_https_port() {
+        let redirect = HttpsRedirect::new().status(RedirectStatus::PermanentRedirect).to("127.0.0.1", 8443);
+        let response = redirect.handle(&request("/a/b?c=d", Some("Example.com:8080"))).unwrap();
+        assert_eq!(308, response.status);
+        assert_eq!(Some("https://example.com:8443/a/b?c=d"), response.headers.get("Location"));
+
+        let response = HttpsRedirect::new().to("127.0.0.1", 443).handle(&request("/", None)).unwrap();
+        assert_eq!(Some("https://127.0.0.1/"), response.headers.get("Location"));
+    }
+
+    #[test]
+    fn excepted_paths_are_not_redirected() {
+        let redirect = HttpsRedirect::new().except("/.well-known/acme-challenge/");
+        assert!(redirect.handle(&request("/.well-known/acme-challenge/token", Some("example.com"))).is_none());
+        assert!(redirect.handle(&request("/.well-known/other", Some("example.com"))).is_some());
+    }
+
+    #[test]
+    fn hsts_lists_its_directives() {
+        let mut response = Response::new(200, "OK");
+        Hsts::new(Duration::from_secs(31536000)).include_subdomains().preload()
+            .on_response(&request("/", None), &mut response);
+        assert_eq!(Some("max-age=31536000; includeSubDomains; preload"), response.headers.get("Strict-Transport-Security"));
+    }
+}
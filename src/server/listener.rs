@@ -0,0 +1,432 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// A Unix domain socket to listen on, either a file or, on Linux, a name in the abstract namespace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixSocket {
+    name: UnixName,
+    mode: Option<u32>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum UnixName {
+    Path(PathBuf),
+    Abstract(String)
+}
+
+impl UnixSocket {
+    /// A socket file at `path`. A stale one left behind by a server that is gone is replaced.
+    pub fn path<P: AsRef<Path>>(path: P) -> UnixSocket {
+        UnixSocket { name: UnixName::Path(path.as_ref().to_path_buf()), mode: None }
+    }
+
+    /// A socket in the abstract namespace, which has no file and goes away with the server.
+    pub fn abstract_name(name: &str) -> UnixSocket {
+        UnixSocket { name: UnixName::Abstract(name.to_string()), mode: None }
+    }
+
+    /// Reads `unix:/path/to/socket`, or `unix:@name` for an abstract socket. `None` for anything
+    /// else, which is left to be read as a TCP address.
+    pub fn from_address(address: &str) -> Option<UnixSocket> {
+        let name = address.strip_prefix("unix:")?;
+        match name.strip_prefix('@') {
+            Some(name) => Some(UnixSocket::abstract_name(name)),
+            None => Some(UnixSocket::path(name))
+        }
+    }
+
+    /// Permission bits for the socket file, such as `0o660` to let a front end in the same group
+    /// connect. Abstract sockets have no file to set them on.
+    pub fn mode(mut self, mode: u32) -> UnixSocket {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// The socket file, `None` for an abstract socket.
+    pub fn file(&self) -> Option<&Path> {
+        match self.name {
+            UnixName::Path(ref path) => Some(path),
+            UnixName::Abstract(_) => None
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn bind(&self) -> io::Result<Listener> {
+        let listener = match self.name {
+            UnixName::Path(ref path) => {
+                remove_stale(path)?;
+                match self.mode {
+                    Some(mode) => {
+                        let listener = bind_with_mode(path, mode)?;
+                        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                        listener
+                    },
+                    None => UnixListener::bind(path)?
+                }
+            },
+            UnixName::Abstract(ref name) => {
+                if self.mode.is_some() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "an abstract socket has no file mode"));
+                }
+                bind_abstract(name)?
+            }
+        };
+        Ok(Listener::Unix(listener))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn bind(&self) -> io::Result<Listener> {
+        Err(io::Error::new(io::ErrorKind::Other, "Unix sockets are not supported on this platform"))
+    }
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /// The socket a listener opened elsewhere is bound to.
+    fn bound_to(listener: &UnixListener) -> io::Result<UnixSocket> {
+        let addr = listener.local_addr()?;
+        match addr.as_pathname() {
+            Some(path) => Ok(UnixSocket::path(path)),
+            None => abstract_name(&addr).map(|name| UnixSocket::abstract_name(&name))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "an unnamed socket cannot be listened on"))
+        }
+    }
+}
+
+impl fmt::Display for UnixSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            UnixName::Path(ref path) => write!(f, "unix:{}", path.display()),
+            UnixName::Abstract(ref name) => write!(f, "unix:@{}", name)
+        }
+    }
+}
+
+/// Removes a socket file nobody is accepting on any more. Anything that is not a socket, or a
+/// socket another server still answers on, is left alone and reported.
+#[cfg(unix)]
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if !metadata.file_type().is_socket() => {
+            Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file that is not a socket is in the way"))
+        },
+        Ok(_) => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on it")),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+            Err(e) => Err(e)
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e)
+    }
+}
+
+/// Serializes the changes `bind_with_mode` makes to the umask, which the whole process shares.
+#[cfg(unix)]
+static UMASK: Mutex<()> = Mutex::new(());
+
+/// Binds a socket file that never has more permissions than `mode`, not even between being
+/// created and having them set. The umask is narrowed for the bind and put back after.
+#[cfg(unix)]
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let _lock = UMASK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let previous = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+    listener
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_abstract(name: &str) -> io::Result<UnixListener> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    UnixListener::bind_addr(&addr)
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn bind_abstract(_name: &str) -> io::Result<UnixListener> {
+    Err(io::Error::new(io::ErrorKind::Other, "abstract sockets are only supported on Linux"))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn abstract_name(addr: &::std::os::unix::net::SocketAddr) -> Option<String> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+    addr.as_abstract_name().map(|name| String::from_utf8_lossy(name).into_owned())
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn abstract_name(_addr: &::std::os::unix::net::SocketAddr) -> Option<String> {
+    None
+}
+
+/// Whether a descriptor is closed in processes this one starts, instead of being inherited.
+/// Only calls `fcntl`, so it is safe between fork and exec.
+#[cfg(unix)]
+pub fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        let flags = if cloexec { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Waits up to `timeout` for `fd` to become readable.
+#[cfg(unix)]
+pub fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    wait_for(fd, libc::POLLIN, timeout)
+}
+
+/// Waits up to `timeout` for `fd` to take more to write, or to have failed.
+#[cfg(unix)]
+pub fn wait_writable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    wait_for(fd, libc::POLLOUT, timeout)
+}
+
+#[cfg(unix)]
+fn wait_for(fd: RawFd, events: libc::c_short, timeout: Duration) -> io::Result<bool> {
+    let millis = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+    let mut poll_fd = libc::pollfd { fd, events, revents: 0 };
+    match unsafe { libc::poll(&mut poll_fd, 1, millis) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(e) }
+        },
+        0 => Ok(false),
+        _ => Ok(true)
+    }
+}
+
+/// A bound socket of any kind a server accepts connections on.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener)
+}
+
+impl Listener {
+    pub fn accept(&self) -> io::Result<Connection> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => listener.accept().map(|(stream, _)| Connection::Unix(stream))
+        }
+    }
+
+    /// Takes over a listening socket opened by another process, such as the service manager.
+    /// Its address family decides whether it is a TCP or a Unix listener.
+    ///
+    /// # Safety
+    /// `fd` must be an open socket nothing else in this process owns.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Listener> {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        set_cloexec(fd, true)?;
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(TcpListener::from_raw_fd(fd))),
+            libc::AF_UNIX => Ok(Listener::Unix(UnixListener::from_raw_fd(fd))),
+            family => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("descriptor {} is not a TCP or Unix socket but family {}", fd, family)))
+        }
+    }
+
+    /// Waits up to `timeout` for a connection, so that the caller can look up now and then.
+    /// Expects the listener to be non-blocking, as another process sharing it may take the
+    /// connection first.
+    #[cfg(unix)]
+    pub fn accept_timeout(&self, timeout: Duration) -> io::Result<Option<Connection>> {
+        if !wait_readable(self.as_raw_fd(), timeout)? {
+            return Ok(None);
+        }
+        match self.accept() {
+            Ok(connection) => Ok(Some(connection)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn accept_timeout(&self, _timeout: Duration) -> io::Result<Option<Connection>> {
+        self.accept().map(Some)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => listener.set_nonblocking(nonblocking)
+        }
+    }
+
+    /// The TCP address or Unix socket the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<Result<SocketAddr, UnixSocket>> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.local_addr().map(Ok),
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => UnixSocket::bound_to(listener).map(Err)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Listener::Tcp(ref listener) => listener.as_raw_fd(),
+            Listener::Unix(ref listener) => listener.as_raw_fd()
+        }
+    }
+}
+
+/// An accepted connection, read and written the same whatever the listener it came from.
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl Connection {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(ref stream) => stream.set_read_timeout(timeout)
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(ref stream) => stream.set_write_timeout(timeout)
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Connection::Unix(ref stream) => stream.set_nonblocking(nonblocking)
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.try_clone().map(Connection::Tcp),
+            #[cfg(unix)]
+            Connection::Unix(ref stream) => stream.try_clone().map(Connection::Unix)
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Connection::Unix(ref stream) => stream.shutdown(how)
+        }
+    }
+
+    /// The client's address. Unix socket clients have none worth logging.
+    pub fn peer_addr(&self) -> io::Result<Option<SocketAddr>> {
+        match *self {
+            Connection::Tcp(ref stream) => stream.peer_addr().map(Some),
+            #[cfg(unix)]
+            Connection::Unix(_) => Ok(None)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Connection::Tcp(ref stream) => stream.as_raw_fd(),
+            Connection::Unix(ref stream) => stream.as_raw_fd()
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::UnixSocket;
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn reads_unix_addresses() {
+        assert_eq!(Some(UnixSocket::path("/run/http.sock")), UnixSocket::from_address("unix:/run/http.sock"));
+        assert_eq!(Some(UnixSocket::abstract_name("http")), UnixSocket::from_address("unix:@http"));
+        assert_eq!(None, UnixSocket::from_address("127.0.0.1"));
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced_but_live_ones_are_not() {
+        let path = env::temp_dir().join(format!("rust-http2-server-stale-{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        drop(UnixListener::bind(&path).unwrap());
+        let live = UnixSocket::path(&path).bind().unwrap();
+        assert!(UnixSocket::path(&path).bind().is_err());
+        drop(live);
+        fs::remove_file(&path).unwrap();
+
+        fs::write(&path, b"not a socket").unwrap();
+        assert!(UnixSocket::path(&path).bind().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
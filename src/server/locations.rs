@@ -0,0 +1,202 @@
+use base64;
+use openssl::memcmp;
+use openssl::sha::sha256;
+use super::content_manager::Handler;
+use super::http::{Request, Response};
+
+/// Rules applied to every request whose path starts with a prefix.
+#[derive(Debug, Clone)]
+pub struct Location {
+    prefix: String,
+    headers: Vec<(String, String)>,
+    cache_control: Option<String>,
+    auth: Option<BasicAuth>,
+    redirect: Option<Redirect>
+}
+
+#[derive(Debug, Clone)]
+struct BasicAuth {
+    realm: String,
+    /// User names and the SHA-256 digests of their passwords.
+    users: Vec<(String, [u8; 32])>
+}
+
+#[derive(Debug, Clone)]
+struct Redirect {
+    status: u16,
+    to: String
+}
+
+impl Location {
+    pub fn new(prefix: &str) -> Location {
+        Location {
+            prefix: prefix.to_string(),
+            headers: vec![],
+            cache_control: None,
+            auth: None,
+            redirect: None
+        }
+    }
+
+    /// Adds a header to every response under this location.
+    pub fn header(mut self, name: &str, value: &str) -> Location {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn cache_control(mut self, value: &str) -> Location {
+        self.cache_control = Some(value.to_string());
+        self
+    }
+
+    /// Requires HTTP basic authentication. Passwords are given as SHA-256 digests.
+    pub fn basic_auth(mut self, realm: &str, users: Vec<(String, [u8; 32])>) -> Location {
+        self.auth = Some(BasicAuth { realm: realm.to_string(), users });
+        self
+    }
+
+    /// Redirects to `to`, with the rest of the path after the prefix appended.
+    pub fn redirect(mut self, status: u16, to: &str) -> Location {
+        self.redirect = Some(Redirect { status, to: to.to_string() });
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+}
+
+/// Applies the rules of the longest location prefix matching each request.
+pub struct Locations {
+    locations: Vec<Location>
+}
+
+impl Locations {
+    pub fn new(mut locations: Vec<Location>) -> Locations {
+        locations.sort_by_key(|location| ::std::cmp::Reverse(location.prefix.len()));
+        Locations { locations }
+    }
+
+    /// Matches on the path as normalized by the parser, the same one the content is found by.
+    fn matching(&self, request: &Request) -> Option<&Location> {
+        let path = request.path();
+        self.locations.iter().find(|location| path.starts_with(&location.prefix))
+    }
+}
+
+impl Handler for Locations {
+    fn handle(&self, request: &Request) -> Option<Response> {
+        let location = self.matching(request)?;
+        if let Some(ref auth) = location.auth {
+            if !auth.authorized(request) {
+                let challenge = format!("Basic realm=\"{}\"", auth.realm);
+                return Some(Response::new(401, "Unauthorized")
+                    .with_header("WWW-Authenticate", &challenge)
+                    .with_body("Unauthorized"));
+            }
+        }
+        location.redirect.as_ref().map(|redirect| {
+            let target = format!("{}{}", redirect.to, &request.target[location.prefix.len()..]);
+            Response::new(redirect.status, redirect_reason(redirect.status))
+                .with_header("Location", &target)
+                .with_body("")
+        })
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Some(location) = self.matching(request) {
+            for (name, value) in &location.headers {
+                response.headers.set(name, value);
+            }
+            if let Some(ref cache_control) = location.cache_control {
+                response.headers.set("Cache-Control", cache_control);
+            }
+        }
+    }
+}
+
+impl BasicAuth {
+    fn authorized(&self, request: &Request) -> bool {
+        let credentials = request.headers.get("Authorization")
+            .and_then(|value| {
+                let mut parts = value.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(encoded)) if scheme.eq_ignore_ascii_case("Basic") => base64::decode(encoded.trim()).ok(),
+                    _ => None
+                }
+            })
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        match credentials {
+            Some(credentials) => match credentials.find(':') {
+                Some(index) => {
+                    let digest = sha256(&credentials.as_bytes()[index + 1..]);
+                    let user = &credentials[..index];
+                    self.users.iter().any(|(name, expected)| name == user && memcmp::eq(expected, &digest))
+                },
+                None => false
+            },
+            None => false
+        }
+    }
+}
+
+pub fn redirect_reason(status: u16) -> &'static str {
+    match status {
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        _ => "Redirect"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Location, Locations};
+    use server::content_manager::Handler;
+    use server::http::{Headers, Method, Request, Response, Version};
+    use openssl::sha::sha256;
+
+    fn request(target: &str, headers: Headers) -> Request {
+        Request { method: Method::Get, target: target.to_string(), version: Version::Http11, headers, client_cert: None }
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let locations = Locations::new(vec![
+            Location::new("/").cache_control("no-cache"),
+            Location::new("/static/").cache_control("max-age=3600")
+        ]);
+        let mut response = Response::new(200, "OK");
+        locations.on_response(&request("/static/site.css", Headers::new()), &mut response);
+
+        assert_eq!(Some("max-age=3600"), response.headers.get("Cache-Control"));
+    }
+
+    #[test]
+    fn redirect_keeps_rest_of_path() {
+        let locations = Locations::new(vec![Location::new("/old/").redirect(301, "/new/")]);
+        let response = locations.handle(&request("/old/page.html?x=1", Headers::new())).unwrap();
+
+        assert_eq!(301, response.status);
+        assert_eq!(Some("/new/page.html?x=1"), response.headers.get("Location"));
+    }
+
+    #[test]
+    fn basic_auth_checks_password_digest() {
+        let users = vec![("alice".to_string(), sha256(b"secret"))];
+        let locations = Locations::new(vec![Location::new("/private/").basic_auth("Private", users)]);
+
+        let response = locations.handle(&request("/private/", Headers::new())).unwrap();
+        assert_eq!(401, response.status);
+
+        let mut wrong = Headers::new();
+        wrong.append("Authorization", "Basic YWxpY2U6d3Jvbmc=");
+        assert_eq!(401, locations.handle(&request("/private/", wrong)).unwrap().status);
+
+        let mut right = Headers::new();
+        right.append("authorization", "Basic YWxpY2U6c2VjcmV0");
+        assert!(locations.handle(&request("/private/", right)).is_none());
+    }
+}
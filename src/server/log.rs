@@ -0,0 +1,108 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::Local;
+use super::http::{Request, Response};
+
+/// Where the access log is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogTarget {
+    Off,
+    Stdout,
+    File(PathBuf)
+}
+
+/// Writes one Common Log Format line per request.
+pub struct AccessLog {
+    target: LogTarget,
+    file: Mutex<Option<File>>,
+    /// Whether the file can be opened again by its path.
+    reopens: bool
+}
+
+impl AccessLog {
+    pub fn open(target: LogTarget) -> io::Result<AccessLog> {
+        let file = match target {
+            LogTarget::File(ref path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            _ => None
+        };
+        Ok(AccessLog { target, file: Mutex::new(file), reopens: true })
+    }
+
+    /// Keeps writing to the file already open, for after a chroot, where its path no longer leads
+    /// to it and could instead name a file inside the served root.
+    pub fn keep_open(mut self) -> AccessLog {
+        self.reopens = false;
+        self
+    }
+
+    pub fn target(&self) -> &LogTarget {
+        &self.target
+    }
+
+    /// Opens the log file again, so that lines go to a new file after the old one was rotated.
+    pub fn reopen(&self) -> io::Result<()> {
+        if let LogTarget::File(ref path) = self.target {
+            if !self.reopens {
+                return Err(io::Error::other("the access log cannot be reopened after a chroot"));
+            }
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            match self.file.lock() {
+                Ok(mut guard) => *guard = Some(file),
+                Err(poisoned) => *poisoned.into_inner() = Some(file)
+            }
+        }
+        Ok(())
+    }
+
+    pub fn log(&self, peer: Option<SocketAddr>, request: &Request, response: &Response) {
+        if self.target == LogTarget::Off {
+            return;
+        }
+        // The client certificate subject stands in for the user, with spaces escaped to keep
+        // the fields apart.
+        let user = request.client_cert.as_ref()
+            .map(|cert| cert.subject.replace(' ', "%20"))
+            .unwrap_or_else(|| "-".to_string());
+        let line = format!("{} - {} [{}] \"{} {} {}\" {} {}",
+                           peer.map(|addr| addr.ip().to_string()).unwrap_or_else(|| "-".to_string()),
+                           user,
+                           Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                           request.method.as_str(), request.target, request.version.as_str(),
+                           response.status,
+                           response.headers.content_length().map(|len| len.to_string()).unwrap_or_else(|| "-".to_string()));
+        match self.file.lock() {
+            Ok(mut guard) => match *guard {
+                Some(ref mut file) => {
+                    if let Err(e) = writeln!(file, "{}", line) {
+                        eprintln!("Error writing access log:{}", e);
+                    }
+                },
+                None => println!("{}", line)
+            },
+            Err(e) => eprintln!("Error locking access log:{}", e)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessLog, LogTarget};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn logs_kept_open_are_not_reopened_by_path() {
+        let path = env::temp_dir().join(format!("rust-http2-server-log-{}", ::std::process::id()));
+        let log = AccessLog::open(LogTarget::File(path.clone())).unwrap();
+        assert!(log.reopen().is_ok());
+        let log = log.keep_open();
+        fs::remove_file(&path).unwrap();
+        assert!(log.reopen().is_err());
+        assert!(!path.exists());
+    }
+}
@@ -1,55 +1,176 @@
-use std::net::{TcpListener};
-use std::path::Path;
+use std::net::SocketAddr;
+use std::io;
 use std::sync::Arc;
-use openssl::ssl::{SslAcceptor};
-use self::file_system::{FileSystemAdapter};
-use self::handlers::handle_client;
-use self::pool::ThreadPool;
-
-pub fn serve(listener: TcpListener, root: &Path) {
-    let fs_adapter = FileSystemAdapter::new(root);
-    let adapter_rc = Arc::new(fs_adapter);
-    let pool = ThreadPool::new(4);
-
-    // accept connections and process them in separate threads
-    for stream_ref in listener.incoming() {
-        match stream_ref {
-            Ok(stream) => {
-                let local_rc = adapter_rc.clone();
-                pool.execute(move || handle_client(stream, local_rc.as_ref()));
-            },
-            Err(e) => eprintln!("Error with stream:{}", e)
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread::spawn;
+use std::time::Duration;
+use self::content_manager::{ContentHandle, ContentManager, Handler};
+use self::control::{Control, Event};
+use self::handlers::{handle_client, reject_client};
+use self::hosts::VirtualHosts;
+use self::http::Limits;
+use self::listener::{Connection, Listener};
+use self::log::AccessLog;
+use self::pool::{Overload, ThreadPool};
+use tls::{session, ReloadableAcceptor};
+
+/// The content and the handlers in front of it that a listener serves.
+pub struct Site<H: ContentHandle> {
+    pub content: Box<dyn ContentManager<H> + Send + Sync>,
+    pub handlers: Vec<Arc<dyn Handler>>
+}
+
+/// Connection level settings shared by every worker.
+#[derive(Clone)]
+pub struct Options {
+    pub overload: Overload,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub limits: Limits,
+    pub log: Arc<AccessLog>,
+    pub control: Arc<Control>
+}
+
+/// How long an accept loop waits for a connection before looking whether the server is stopping.
+const ACCEPT_POLL: Duration = Duration::from_millis(200);
+/// How many rejected connections may wait for their 503 before more are closed unanswered.
+const REJECT_QUEUE: usize = 16;
+/// How long a rejected connection gets to send its request and take the answer.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Serves the connections of `listeners` with the workers of `pool`, and the other events sent
+/// with `sender`, until the server stops and every sender is gone.
+pub fn serve<H: ContentHandle + 'static>(listeners: Vec<Listener>, pool: ThreadPool, sender: Sender<Event<H>>, events: Receiver<Event<H>>,
+                                         hosts: VirtualHosts<H>, options: Options, acceptor: Option<Arc<ReloadableAcceptor>>) {
+    let mut hosts_rc = Arc::new(hosts);
+    // answers connections turned away when the pool is full, one at a time
+    let rejecter = ThreadPool::new(1, REJECT_QUEUE);
+    let mut options_rc = Arc::new(options);
+    let mut acceptor = acceptor;
+
+    // every listener accepts on its own thread and hands the connections over here, until the
+    // server stops and they have all given up
+    for listener in listeners {
+        let sender = sender.clone();
+        let control = options_rc.control.clone();
+        if let Err(e) = listener.set_nonblocking(true) {
+            eprintln!("Error setting up listener:{}", e);
         }
+        spawn(move || {
+            while !control.is_stopping() {
+                let accepted = match listener.accept_timeout(ACCEPT_POLL) {
+                    Ok(Some(connection)) => {
+                        let tracked = Control::track(&control, &connection);
+                        Ok((connection, tracked))
+                    },
+                    Ok(None) => continue,
+                    Err(e) => Err(e)
+                };
+                if sender.send(Event::Accepted(accepted)).is_err() {
+                    break;
+                }
+            }
+        });
     }
-}
+    drop(sender);
 
-pub fn serve_https(listener: TcpListener, root: &Path, acceptor: SslAcceptor) {
-    let fs_adapter = FileSystemAdapter::new(root);
-    let adapter_rc = Arc::new(fs_adapter);
-    let acceptor_rc = Arc::new(acceptor);
-    let pool = ThreadPool::new(4);
-
-    // accept connections and process them in separate threads
-    for stream_ref in listener.incoming() {
-        match stream_ref {
-            Ok(connection) => {
-                let local_rc = adapter_rc.clone();
-                let local_acceptor_rc = acceptor_rc.clone();
-                pool.execute(move || {
-                    match local_acceptor_rc.accept(connection) {
-                        Ok(stream) => handle_client(stream, local_rc.as_ref()),
-                        Err(e) => eprintln!("Accept failed:{}", e)
+    // process connections in separate threads
+    for event in events {
+        match event {
+            Event::Accepted(Ok((connection, tracked))) => {
+                let peer = match prepare_connection(&connection, &options_rc) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        eprintln!("Error preparing connection:{}", e);
+                        continue;
                     }
-
-                });
+                };
+                let local_options_rc = options_rc.clone();
+                let local_acceptor_rc = acceptor.as_ref().map(|acceptor| acceptor.current());
+                if let Overload::Reject(retry_after) = options_rc.overload {
+                    if pool.is_full() {
+                        pool.count_rejected();
+                        if let Err(e) = connection.set_read_timeout(Some(REJECT_TIMEOUT))
+                            .and_then(|_| connection.set_write_timeout(Some(REJECT_TIMEOUT))) {
+                            eprintln!("Error preparing connection:{}", e);
+                            continue;
+                        }
+                        rejecter.execute_shedding(move || {
+                            let _tracked = tracked;
+                            let rejected = match local_acceptor_rc {
+                                None => reject_client::<H, _>(connection, peer, retry_after, local_options_rc.as_ref()),
+                                Some(acceptor) => match acceptor.accept(connection) {
+                                    Ok(stream) => reject_client::<H, _>(stream, peer, retry_after, local_options_rc.as_ref()),
+                                    Err(e) => {
+                                        eprintln!("Accept failed:{}", e);
+                                        Ok(())
+                                    }
+                                }
+                            };
+                            if let Err(e) = rejected {
+                                eprintln!("Error rejecting client:{}", e);
+                            }
+                        });
+                        continue;
+                    }
+                }
+                let local_rc = hosts_rc.clone();
+                let job = move || {
+                    let _tracked = tracked;
+                    let served = match local_acceptor_rc {
+                        None => handle_client(connection, peer, None, local_rc.as_ref(), local_options_rc.as_ref()),
+                        Some(acceptor) => match acceptor.accept(connection) {
+                            Ok(stream) => {
+                                let tls = session(stream.ssl());
+                                handle_client(stream, peer, Some(tls), local_rc.as_ref(), local_options_rc.as_ref())
+                            },
+                            Err(e) => {
+                                eprintln!("Accept failed:{}", e);
+                                Ok(())
+                            }
+                        }
+                    };
+                    if let Err(e) = served {
+                        eprintln!("Error serving client:{}", e);
+                    }
+                };
+                match options_rc.overload {
+                    Overload::ShedOldest => pool.execute_shedding(job),
+                    _ => pool.execute(job)
+                }
             },
-            Err(e) => eprintln!("Error with stream:{}", e)
+            Event::Accepted(Err(e)) => eprintln!("Error with stream:{}", e),
+            Event::Reload(hosts, options, new_acceptor) => {
+                hosts_rc = Arc::new(hosts);
+                options_rc = Arc::new(options);
+                acceptor = new_acceptor;
+            },
+            Event::ReopenLogs => if let Err(e) = options_rc.log.reopen() {
+                eprintln!("Error reopening the access log:{}", e);
+            }
         }
     }
 }
 
-mod http;
-mod file_system;
-mod content_manager;
+fn prepare_connection(connection: &Connection, options: &Options) -> io::Result<Option<SocketAddr>> {
+    connection.set_nonblocking(false)?;
+    connection.set_read_timeout(options.read_timeout)?;
+    connection.set_write_timeout(options.write_timeout)?;
+    connection.peer_addr()
+}
+
+pub mod http;
+pub mod file_system;
+pub mod file_cache;
+pub mod file_map;
+pub mod hosts;
+pub mod https;
+pub mod listener;
+pub mod content_manager;
+pub mod control;
+#[cfg(unix)]
+pub mod event_loop;
+pub mod locations;
+pub mod log;
+pub mod pool;
 mod handlers;
-mod pool;
\ No newline at end of file
@@ -1,91 +1,303 @@
-use std::thread::{spawn, JoinHandle};
-use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::thread::{self, spawn, JoinHandle};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
 
 
-type Job = Box<FnBox + Send + 'static>;
+type Job = Box<dyn FnBox + Send + 'static>;
 
-enum Message {
-    NewJob(Job),
-    Terminate,
+/// What to do with a new request when every worker is busy and the queue is full. With a thread
+/// per connection, that is the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overload {
+    /// Waits until there is room, leaving connections to wait in the listen backlog with a thread
+    /// per connection, and requests to wait their turn otherwise.
+    Block,
+    /// Answers with 503 Service Unavailable, asking the client to come back after this long.
+    Reject(Duration),
+    /// Closes the connection whose request has waited longest to make room.
+    ShedOldest
 }
 
+impl Overload {
+    /// Reads `block`, `reject` or `shed-oldest`. Rejections ask clients to retry after five
+    /// seconds unless told otherwise.
+    pub fn parse(name: &str, retry_after: Option<Duration>) -> Option<Overload> {
+        match name {
+            "block" => Some(Overload::Block),
+            "reject" => Some(Overload::Reject(retry_after.unwrap_or(Duration::from_secs(5)))),
+            "shed-oldest" => Some(Overload::ShedOldest),
+            _ => None
+        }
+    }
+}
+
+/// A snapshot of the load on a server's workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolMetrics {
+    /// Workers running now, between the minimum and the maximum.
+    pub workers: usize,
+    pub min_workers: usize,
+    pub max_workers: usize,
+    /// The most workers that have run at once.
+    pub peak_workers: usize,
+    /// Workers answering a request, or serving a connection with a thread per connection.
+    pub busy: usize,
+    /// Requests, or connections, waiting for a worker.
+    pub queued: usize,
+    pub queue_capacity: usize,
+    /// The most that have waited at once.
+    pub peak_queued: usize,
+    /// Requests answered with 503 because the queue was full.
+    pub rejected: u64,
+    /// Waiting connections closed to make room for new requests.
+    pub shed: u64,
+    /// Jobs that panicked. Their workers carry on.
+    pub panics: u64
+}
+
+/// Workers taking jobs from a queue of bounded length. More are started, up to a maximum, when
+/// jobs wait with every worker busy, and those above the minimum stop after sitting idle for a
+/// while. A job that panics is logged and its worker goes on to the next one.
 pub struct ThreadPool {
-    threads: Vec<Worker>,
-    sender: Sender<Message>
+    shared: Arc<Shared>
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Every worker started, including replacements for ones that died.
+    workers: Mutex<Vec<Worker>>,
+    /// Signalled when a job is queued or the workers are to stop.
+    queued: Condvar,
+    /// Signalled when a worker takes a job off the queue.
+    taken: Condvar
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    terminating: bool,
+    /// How long a worker above the minimum waits for a job before it stops.
+    idle_timeout: Duration,
+    next_id: usize,
+    metrics: PoolMetrics
+}
+
+/// Reads the metrics of a pool from elsewhere.
+#[derive(Clone)]
+pub struct PoolMonitor {
+    shared: Arc<Shared>
 }
 
 struct Worker {
     id: usize,
-    handle: Option<JoinHandle<()>>
+    handle: JoinHandle<()>
+}
+
+/// Starts a replacement when dropped by a worker thread that is dying of a panic.
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>
 }
 
 impl ThreadPool {
-    pub fn new(num: usize) -> ThreadPool {
-        assert!(num > 0);
-        let mut threads = Vec::with_capacity(num);
-        let (tx, rx) = mpsc::channel();
-        let rx_rc = Arc::new(Mutex::new(rx));
-
-        for id in 0..num {
-            threads.push(Worker::new(id, rx_rc.clone()));
+    /// `num` workers, with up to `capacity` jobs waiting for them.
+    pub fn new(num: usize, capacity: usize) -> ThreadPool {
+        ThreadPool::elastic(num, num, capacity, Duration::from_secs(0))
+    }
+
+    /// `min` workers, growing to `max` while jobs wait, with up to `capacity` jobs waiting. The
+    /// extra workers stop after `idle_timeout` without a job.
+    pub fn elastic(min: usize, max: usize, capacity: usize, idle_timeout: Duration) -> ThreadPool {
+        assert!(min > 0);
+        assert!(max >= min);
+        assert!(capacity > 0);
+        let metrics = PoolMetrics {
+            workers: min,
+            min_workers: min,
+            max_workers: max,
+            peak_workers: min,
+            queue_capacity: capacity,
+            ..PoolMetrics::default()
+        };
+        let state = State { jobs: VecDeque::with_capacity(capacity), terminating: false, idle_timeout, next_id: min, metrics };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(state),
+            workers: Mutex::new(Vec::with_capacity(max)),
+            queued: Condvar::new(),
+            taken: Condvar::new()
+        });
+        for id in 0..min {
+            Worker::start(id, &shared);
         }
         ThreadPool {
-            threads,
-            sender: tx
+            shared
         }
     }
 
+    /// Queues `f`, waiting for room when the queue is full.
     pub fn execute<F>(&self, f: F)
         where F: FnOnce() + Send + 'static {
-        let job = Box::new(f);
-        self.sender.send(Message::NewJob(job)).unwrap();
+        let mut state = self.shared.lock();
+        while state.jobs.len() >= state.metrics.queue_capacity {
+            state = self.shared.taken.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        self.push(state, Box::new(f));
+    }
+
+    /// Queues `f`, dropping the job that has waited longest when the queue is full.
+    pub fn execute_shedding<F>(&self, f: F)
+        where F: FnOnce() + Send + 'static {
+        let mut state = self.shared.lock();
+        let shed = if state.jobs.len() >= state.metrics.queue_capacity {
+            state.metrics.shed += 1;
+            state.jobs.pop_front()
+        } else {
+            None
+        };
+        self.push(state, Box::new(f));
+        // dropped outside the lock, as dropping a job may take a while
+        drop(shed);
+    }
+
+    /// Whether a new job would have to wait for room.
+    pub fn is_full(&self) -> bool {
+        let state = self.shared.lock();
+        state.jobs.len() >= state.metrics.queue_capacity
+    }
+
+    /// Counts a job turned away because the queue was full.
+    pub fn count_rejected(&self) {
+        self.shared.lock().metrics.rejected += 1;
+    }
+
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor { shared: self.shared.clone() }
+    }
+
+    /// Queues `job`, starting another worker when there are more jobs than idle workers.
+    fn push(&self, mut state: MutexGuard<'_, State>, job: Job) {
+        state.jobs.push_back(job);
+        state.metrics.peak_queued = state.metrics.peak_queued.max(state.jobs.len());
+        let idle = state.metrics.workers - state.metrics.busy;
+        let grow = state.jobs.len() > idle && state.metrics.workers < state.metrics.max_workers;
+        let id = state.next_id;
+        if grow {
+            state.next_id += 1;
+            state.metrics.workers += 1;
+            state.metrics.peak_workers = state.metrics.peak_workers.max(state.metrics.workers);
+        }
+        self.shared.queued.notify_one();
+        drop(state);
+        if grow {
+            Worker::start(id, &self.shared);
+        }
     }
 }
 
 impl Drop for ThreadPool {
+    /// Lets the workers finish the queued jobs before they stop.
     fn drop(&mut self) {
-        for _ in &mut self.threads {
-            self.sender.send(Message::Terminate).unwrap();
-        }
+        self.shared.lock().terminating = true;
+        self.shared.queued.notify_all();
 
-        for worker in &mut self.threads {
-            println!("Shutting down worker {}", worker.id);
-            if let Some(handle) = worker.handle.take() {
-                match handle.join() {
-                    Ok(_) => {},
-                    Err(_) => eprintln!("Error in shutdown")
-                }
+        // a worker dying meanwhile has its replacement listed before it can be joined
+        loop {
+            let worker = self.shared.workers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).pop();
+            match worker {
+                Some(worker) => {
+                    if worker.handle.join().is_err() {
+                        eprintln!("Error in shutdown");
+                    }
+                },
+                None => break
             }
         }
     }
 }
 
+impl PoolMonitor {
+    pub fn metrics(&self) -> PoolMetrics {
+        let state = self.shared.lock();
+        PoolMetrics { queued: state.jobs.len(), ..state.metrics }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
 impl Worker {
-    fn new(id: usize, rx: Arc<Mutex<Receiver<Message>>>) -> Worker {
+    fn start(id: usize, shared: &Arc<Shared>) {
+        let sentinel = Sentinel { id, shared: shared.clone() };
         let handle = spawn(move || {
+            let shared = sentinel.shared.clone();
+            let mut state = shared.lock();
             loop {
-                match rx.lock() {
-                    Ok(lock) => match lock.recv() {
-                        Ok(Message::NewJob(job)) => job.call_box(),
-                        Ok(Message::Terminate) => {
-                            println!("Terminating worker {}", id);
-                            break
-                        },
-                        Err(e) => eprintln!("Error receiving job:{}", e)
+                match state.jobs.pop_front() {
+                    Some(job) => {
+                        state.metrics.busy += 1;
+                        shared.taken.notify_one();
+                        drop(state);
+                        let result = catch_unwind(AssertUnwindSafe(|| job.call_box()));
+                        if let Err(ref panic) = result {
+                            eprintln!("Worker {} recovered from a panic:{}", id, panic_message(panic));
+                        }
+                        state = shared.lock();
+                        state.metrics.busy -= 1;
+                        if result.is_err() {
+                            state.metrics.panics += 1;
+                        }
+                    },
+                    None if state.terminating => break,
+                    None if state.metrics.workers > state.metrics.min_workers => {
+                        let timeout = state.idle_timeout;
+                        let (waited, result) = shared.queued.wait_timeout(state, timeout)
+                            .unwrap_or_else(|poisoned| poisoned.into_inner());
+                        state = waited;
+                        if result.timed_out() && state.jobs.is_empty() && !state.terminating
+                            && state.metrics.workers > state.metrics.min_workers {
+                            state.metrics.workers -= 1;
+                            drop(state);
+                            // nobody is left to join this thread, so it goes without
+                            shared.workers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+                                .retain(|worker| worker.id != id);
+                            return;
+                        }
                     },
-                    Err(e) => eprintln!("Error receiving lock:{}", e)
+                    None => state = shared.queued.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner())
                 }
             }
+            drop(state);
+            drop(sentinel);
         });
-        Worker {
-            id,
-            handle: Some(handle)
+        shared.workers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(Worker { id, handle });
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let id = {
+                let mut state = self.shared.lock();
+                state.next_id += 1;
+                state.next_id - 1
+            };
+            eprintln!("Worker {} died, starting worker {}", self.id, id);
+            Worker::start(id, &self.shared);
         }
     }
 }
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map(|message| message.as_str()).unwrap_or("unknown cause")
+    }
+}
 trait FnBox {
     fn call_box(self: Box<Self>);
 }
@@ -94,4 +306,63 @@ impl<F: FnOnce()> FnBox for F {
     fn call_box(self: Box<F>) {
         (*self)()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThreadPool;
+    use std::sync::{mpsc, Arc, Barrier};
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A pool with its only worker held up until the returned barrier is waited on.
+    fn blocked_pool(capacity: usize) -> (ThreadPool, Arc<Barrier>) {
+        let pool = ThreadPool::new(1, capacity);
+        let barrier = Arc::new(Barrier::new(2));
+        let held = barrier.clone();
+        pool.execute(move || { held.wait(); });
+        while pool.monitor().metrics().busy == 0 {
+            ::std::thread::yield_now();
+        }
+        (pool, barrier)
+    }
+
+    #[test]
+    fn queue_is_bounded_and_sheds_the_oldest() {
+        let (pool, barrier) = blocked_pool(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+        for i in 0..3 {
+            let ran = ran.clone();
+            pool.execute_shedding(move || { ran.fetch_add(i, Ordering::SeqCst); });
+        }
+        assert!(pool.is_full());
+        let metrics = pool.monitor().metrics();
+        assert_eq!((1, 2, 2, 1), (metrics.busy, metrics.queued, metrics.peak_queued, metrics.shed));
+
+        barrier.wait();
+        drop(pool);
+        // the first job, adding 0, was shed
+        assert_eq!(1 + 2, ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn execute_waits_for_room() {
+        let (pool, barrier) = blocked_pool(1);
+        pool.execute(|| {});
+        assert!(pool.is_full());
+        let releaser = ::std::thread::spawn(move || barrier.wait());
+        pool.execute(|| {});
+        releaser.join().unwrap();
+        drop(pool);
+    }
+
+    #[test]
+    fn panicking_jobs_leave_the_worker_running() {
+        let pool = ThreadPool::new(1, 4);
+        pool.execute(|| panic!("job failed"));
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(1, pool.monitor().metrics().panics);
+    }
+}
@@ -0,0 +1,144 @@
+use std::env;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+use server::listener::Listener;
+
+/// The first file descriptor systemd passes, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the listening sockets systemd passed to this process, with the names given to them by
+/// `FileDescriptorName=`. Empty when there are none or they were meant for another process. The
+/// variables are cleared so that child processes do not take them too.
+pub fn listen_fds() -> io::Result<Vec<(String, Listener)>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    passed_fds(pid.as_deref(), count.as_deref(), names.as_deref(), process::id())?
+        .into_iter()
+        .map(|(name, fd)| unsafe { Listener::from_raw_fd(fd) }.map(|listener| (name, listener)))
+        .collect()
+}
+
+/// The descriptors and their names from the `LISTEN_*` variables, if they are for `own_pid`.
+fn passed_fds(pid: Option<&str>, count: Option<&str>, names: Option<&str>, own_pid: u32) -> io::Result<Vec<(String, RawFd)>> {
+    match (pid, count) {
+        (Some(pid), Some(count)) if pid.parse::<u32>().ok() == Some(own_pid) => {
+            let count = count.parse::<usize>()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid LISTEN_FDS: {}", count)))?;
+            let mut names: Vec<&str> = names.map(|names| names.split(':').collect()).unwrap_or_default();
+            names.resize(count, "unknown");
+            Ok(names.into_iter().zip(LISTEN_FDS_START..)
+                .map(|(name, fd)| (name.to_string(), fd))
+                .collect())
+        },
+        _ => Ok(vec![])
+    }
+}
+
+/// The service manager's socket, connected on first use; `None` when not run by one.
+static NOTIFY_SOCKET: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+
+/// Sends `state`, such as `READY=1`, to the service manager. `false` when not run by one.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match socket()? {
+        Some(datagram) => datagram.send(state.as_bytes()).map(|_| true),
+        None => Ok(false)
+    }
+}
+
+/// Connects to the service manager's socket before `notify` needs it, for when its path will
+/// no longer be reachable by then.
+pub fn connect() -> io::Result<()> {
+    socket().map(|_| ())
+}
+
+fn socket() -> io::Result<Option<&'static UnixDatagram>> {
+    if let Some(datagram) = NOTIFY_SOCKET.get() {
+        return Ok(datagram.as_ref());
+    }
+    let datagram = match env::var("NOTIFY_SOCKET") {
+        Ok(socket) => Some(connect_to(&socket)?),
+        Err(_) => None
+    };
+    Ok(NOTIFY_SOCKET.get_or_init(|| datagram).as_ref())
+}
+
+/// A socket starting with `@` is in the abstract namespace.
+fn connect_to(socket: &str) -> io::Result<UnixDatagram> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        Some(name) => connect_abstract(&datagram, name)?,
+        None => datagram.connect(socket)?
+    }
+    Ok(datagram)
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(datagram: &UnixDatagram, name: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = ::std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    datagram.connect_addr(&addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_datagram: &UnixDatagram, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "abstract sockets are only supported on Linux"))
+}
+
+/// Sends `WATCHDOG=1` at half the interval systemd expects it, when `WatchdogSec=` is set for
+/// this process.
+pub fn start_watchdog() {
+    let usec = env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok());
+    let for_us = match env::var("WATCHDOG_PID") {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(process::id()),
+        Err(_) => true
+    };
+    if let (Some(usec), true) = (usec, for_us) {
+        let interval = Duration::from_micros(usec / 2);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = notify("WATCHDOG=1") {
+                eprintln!("Error notifying the watchdog:{}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_to, passed_fds};
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn takes_fds_meant_for_this_process() {
+        let fds = passed_fds(Some("42"), Some("2"), Some("https:redirect"), 42).unwrap();
+        assert_eq!(vec![("https".to_string(), 3), ("redirect".to_string(), 4)], fds);
+        let fds = passed_fds(Some("42"), Some("2"), None, 42).unwrap();
+        assert_eq!(vec![("unknown".to_string(), 3), ("unknown".to_string(), 4)], fds);
+        assert!(passed_fds(Some("41"), Some("2"), None, 42).unwrap().is_empty());
+        assert!(passed_fds(None, None, None, 42).unwrap().is_empty());
+        assert!(passed_fds(Some("42"), Some("two"), None, 42).is_err());
+    }
+
+    #[test]
+    fn notifies_the_socket() {
+        let path = env::temp_dir().join(format!("rust-http2-server-notify-{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        connect_to(path.to_str().unwrap()).unwrap().send(b"READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(b"READY=1", &buf[..len]);
+        fs::remove_file(&path).unwrap();
+    }
+}
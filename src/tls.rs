@@ -0,0 +1,1075 @@
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::IpAddr;
+use std::os::raw::{c_char, c_int, c_long, c_ulong, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, TimeZone};
+use chrono::offset::Utc;
+use foreign_types::ForeignTypeRef;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::nid;
+use openssl::rand::rand_bytes;
+use openssl::ssl::{SslMethod, SslAcceptorBuilder, SslAcceptor, SslContext, SslContextBuilder, SslContextRef, SslOption, SslRef, SslVerifyMode,
+                   SSL_OP_NO_TICKET, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1, SSL_OP_NO_TLSV1_2,
+                   SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::x509::store::X509StoreBuilderRef;
+use openssl::ocsp::{OcspCertId, OcspResponse, CERT_STATUS_GOOD, RESPONSE_STATUS_SUCCESSFUL};
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use openssl::pkcs12::Pkcs12;
+use error::ServerError;
+use server::http::{ClientCert, TlsSession};
+
+/// Where to find the certificate and private key for HTTPS listeners, and how to run the
+/// protocol. A virtual host's own config only contributes its certificate and client auth.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    source: KeySource,
+    passphrase_path: Option<PathBuf>,
+    client_auth: Option<ClientAuth>,
+    ocsp_path: Option<PathBuf>,
+    protocol: Protocol
+}
+
+/// Mozilla's recommended server settings, as of the openssl crate in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsProfile {
+    /// Forward secret AEAD ciphers over TLS 1.2 and up.
+    Modern,
+    /// Also the older ciphers many clients still need.
+    Intermediate
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12,
+    Tls13
+}
+
+/// Protocol settings shared by every certificate of a server.
+#[derive(Debug, Clone)]
+struct Protocol {
+    profile: TlsProfile,
+    ciphers: Option<String>,
+    min_version: Option<TlsVersion>,
+    curves: Option<String>,
+    ticket_rotation: Option<Duration>,
+    session_cache: Option<(usize, Duration)>
+}
+
+/// Whether clients must present a certificate, or may connect without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuthMode {
+    Required,
+    Optional
+}
+
+/// Verification of client certificates against a CA bundle. A certificate that fails to verify
+/// ends the handshake in either mode.
+#[derive(Debug, Clone)]
+pub struct ClientAuth {
+    mode: ClientAuthMode,
+    ca_path: PathBuf,
+    crl_path: Option<PathBuf>
+}
+
+impl ClientAuth {
+    pub fn new<P: AsRef<Path>>(mode: ClientAuthMode, ca_path: P) -> ClientAuth {
+        ClientAuth { mode, ca_path: ca_path.as_ref().to_path_buf(), crl_path: None }
+    }
+
+    /// A PEM file of revocation lists from the CA issuing client certificates. Certificates it
+    /// revokes are refused, and so is every client certificate if it holds no list for their issuer.
+    pub fn crl_file<P: AsRef<Path>>(mut self, path: P) -> ClientAuth {
+        self.crl_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+enum KeySource {
+    /// A PEM certificate, optionally followed by its intermediates, and a PEM private key.
+    Pem { cert_path: PathBuf, private_key_path: PathBuf },
+    Pkcs12(PathBuf),
+    /// The same as `Pem`, held in memory rather than in files.
+    Memory { cert: Vec<u8>, private_key: Vec<u8> }
+}
+
+/// A private key with its certificate and the intermediates sent along with it.
+struct Identity {
+    pkey: PKey,
+    cert: X509,
+    chain: Vec<X509>
+}
+
+/// An OCSP response vouching for a certificate, and when it stops doing so.
+struct Staple {
+    der: Vec<u8>,
+    next_update: DateTime<Utc>
+}
+
+impl TlsConfig {
+    /// `cert_path` may be a full chain bundle: the certificate followed by its intermediates.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(cert_path: P, private_key_path: Q) -> TlsConfig {
+        TlsConfig {
+            source: KeySource::Pem {
+                cert_path: cert_path.as_ref().to_path_buf(),
+                private_key_path: private_key_path.as_ref().to_path_buf()
+            },
+            passphrase_path: None,
+            client_auth: None,
+            ocsp_path: None,
+            protocol: Protocol::default()
+        }
+    }
+
+    /// A PEM certificate, possibly followed by its intermediates, and an unencrypted PEM private
+    /// key held in memory. Reloading has no files to pick changes up from.
+    pub fn from_pem(cert: &[u8], private_key: &[u8]) -> TlsConfig {
+        TlsConfig {
+            source: KeySource::Memory { cert: cert.to_vec(), private_key: private_key.to_vec() },
+            passphrase_path: None,
+            client_auth: None,
+            ocsp_path: None,
+            protocol: Protocol::default()
+        }
+    }
+
+    /// A PKCS#12 archive holding the private key, certificate and intermediates.
+    pub fn pkcs12<P: AsRef<Path>>(path: P) -> TlsConfig {
+        TlsConfig {
+            source: KeySource::Pkcs12(path.as_ref().to_path_buf()),
+            passphrase_path: None,
+            client_auth: None,
+            ocsp_path: None,
+            protocol: Protocol::default()
+        }
+    }
+
+    /// A file holding the passphrase of an encrypted private key or PKCS#12 archive. A trailing
+    /// line break is not part of the passphrase.
+    pub fn passphrase_file<P: AsRef<Path>>(mut self, path: P) -> TlsConfig {
+        self.passphrase_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Asks clients for certificates and verifies them. On a virtual host's certificate it
+    /// applies to clients asking for that host by SNI.
+    pub fn client_auth(mut self, client_auth: ClientAuth) -> TlsConfig {
+        self.client_auth = Some(client_auth);
+        self
+    }
+
+    /// Staples the DER encoded OCSP response in `path`, usually kept next to the cert, to
+    /// handshakes of clients asking for it. The response must be current and for this cert, and
+    /// is no longer stapled once its next update time passes. Reloading picks up a fresh one.
+    pub fn ocsp_response<P: AsRef<Path>>(mut self, path: P) -> TlsConfig {
+        self.ocsp_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The cipher and protocol defaults to start from. Intermediate unless set.
+    pub fn profile(mut self, profile: TlsProfile) -> TlsConfig {
+        self.protocol.profile = profile;
+        self
+    }
+
+    /// An OpenSSL cipher list for TLS 1.2 and below, replacing the profile's.
+    pub fn ciphers(mut self, ciphers: &str) -> TlsConfig {
+        self.protocol.ciphers = Some(ciphers.to_string());
+        self
+    }
+
+    /// The oldest protocol version clients may use. The modern profile defaults to TLS 1.2.
+    pub fn min_version(mut self, version: TlsVersion) -> TlsConfig {
+        self.protocol.min_version = Some(version);
+        self
+    }
+
+    /// A colon separated list of key exchange curves in order of preference, such as
+    /// `X25519:P-256`.
+    pub fn curves(mut self, curves: &str) -> TlsConfig {
+        self.protocol.curves = Some(curves.to_string());
+        self
+    }
+
+    /// Lets clients resume sessions with tickets. New tickets get a new key every `rotation`, and
+    /// the key before it still opens tickets for one more rotation; clients holding an older
+    /// ticket go through a full handshake. The keys are kept in memory only.
+    pub fn session_tickets(mut self, rotation: Duration) -> TlsConfig {
+        self.protocol.ticket_rotation = Some(rotation);
+        self
+    }
+
+    /// Keeps up to `size` sessions on the server for `timeout`, for clients resuming by session
+    /// id. A size of zero turns the cache off.
+    pub fn session_cache(mut self, size: usize, timeout: Duration) -> TlsConfig {
+        self.protocol.session_cache = Some((size, timeout));
+        self
+    }
+
+    fn cert_path(&self) -> &Path {
+        match self.source {
+            KeySource::Pem { ref cert_path, .. } => cert_path,
+            KeySource::Pkcs12(ref path) => path,
+            KeySource::Memory { .. } => Path::new("in memory")
+        }
+    }
+
+    /// Every file this configuration is loaded from.
+    pub fn files(&self) -> Vec<&Path> {
+        let mut files = match self.source {
+            KeySource::Pem { ref cert_path, ref private_key_path } => vec![cert_path.as_path(), private_key_path.as_path()],
+            KeySource::Pkcs12(ref path) => vec![path.as_path()],
+            KeySource::Memory { .. } => vec![]
+        };
+        files.extend(self.passphrase_path.as_deref());
+        files.extend(self.ocsp_path.as_deref());
+        if let Some(ref client_auth) = self.client_auth {
+            files.push(&client_auth.ca_path);
+            files.extend(client_auth.crl_path.as_deref());
+        }
+        files
+    }
+}
+
+/// The names a virtual host answers to, and the certificate it brings if it has its own.
+#[derive(Clone, Copy)]
+pub struct HostTls<'a> {
+    pub names: &'a [String],
+    pub config: Option<&'a TlsConfig>
+}
+
+impl Default for Protocol {
+    fn default() -> Protocol {
+        Protocol {
+            profile: TlsProfile::Intermediate,
+            ciphers: None,
+            min_version: None,
+            curves: None,
+            ticket_rotation: None,
+            session_cache: None
+        }
+    }
+}
+
+/// The keys session tickets are sealed with: the current one, for new tickets, and the one it
+/// replaced, so tickets issued just before a rotation still resume.
+pub struct TicketKeys {
+    keys: RwLock<(TicketKey, Option<TicketKey>)>
+}
+
+#[derive(Clone, Copy)]
+struct TicketKey {
+    name: [u8; 16],
+    aes: [u8; 32],
+    hmac: [u8; 32]
+}
+
+impl TicketKey {
+    fn generate() -> Result<TicketKey, ServerError> {
+        let mut key = TicketKey { name: [0; 16], aes: [0; 32], hmac: [0; 32] };
+        rand_bytes(&mut key.name)
+            .and_then(|_| rand_bytes(&mut key.aes))
+            .and_then(|_| rand_bytes(&mut key.hmac))
+            .map_err(|e| ServerError::Tls(format!("Error generating a session ticket key:{}", e)))?;
+        Ok(key)
+    }
+}
+
+impl TicketKeys {
+    pub fn new() -> Result<TicketKeys, ServerError> {
+        Ok(TicketKeys { keys: RwLock::new((TicketKey::generate()?, None)) })
+    }
+
+    /// Seals new tickets with a fresh key, keeping the current one to open the tickets it sealed
+    /// and dropping the one before.
+    pub fn rotate(&self) -> Result<(), ServerError> {
+        let key = TicketKey::generate()?;
+        let mut keys = self.keys.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *keys = (key, Some(keys.0));
+        Ok(())
+    }
+
+    fn current(&self) -> TicketKey {
+        self.keys.read().unwrap_or_else(|poisoned| poisoned.into_inner()).0
+    }
+
+    /// The key named `name`, and whether it is the current one.
+    fn find(&self, name: &[u8]) -> Option<(TicketKey, bool)> {
+        let keys = self.keys.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if keys.0.name == name {
+            Some((keys.0, true))
+        } else {
+            keys.1.filter(|previous| previous.name == name).map(|previous| (previous, false))
+        }
+    }
+}
+
+/// The acceptor new connections are handed to. Reloading rebuilds it from the files and swaps
+/// it in; connections already accepted keep the one they started with. The session ticket keys
+/// carry over from one acceptor to the next, including when `replace` swaps in other files.
+pub struct ReloadableAcceptor {
+    sources: Mutex<Sources>,
+    tickets: Option<Arc<TicketKeys>>,
+    current: RwLock<Arc<SslAcceptor>>
+}
+
+/// The files an acceptor is built from.
+struct Sources {
+    config: TlsConfig,
+    hosts: Vec<(Vec<String>, Option<TlsConfig>)>,
+    /// Modification times of the certificate and key files when they were last looked at.
+    modified: Vec<Option<SystemTime>>
+}
+
+impl ReloadableAcceptor {
+    pub fn new(config: TlsConfig, hosts: Vec<(Vec<String>, Option<TlsConfig>)>) -> Result<ReloadableAcceptor, ServerError> {
+        let modified = modification_times(&config, &hosts);
+        let tickets = match config.protocol.ticket_rotation {
+            Some(_) => Some(Arc::new(TicketKeys::new()?)),
+            None => None
+        };
+        let acceptor = build_acceptor(&config, &hosts, tickets.as_ref())?;
+        Ok(ReloadableAcceptor {
+            sources: Mutex::new(Sources { config, hosts, modified }),
+            tickets,
+            current: RwLock::new(Arc::new(acceptor))
+        })
+    }
+
+    pub fn current(&self) -> Arc<SslAcceptor> {
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Rebuilds the acceptor from the files. On failure the current one stays in service.
+    pub fn reload(&self) -> Result<(), ServerError> {
+        let sources = self.sources();
+        self.build(&sources.config, &sources.hosts)
+    }
+
+    /// Reloads if any certificate or key file changed since the last look. Files that fail to
+    /// load are not retried until they change again.
+    pub fn reload_if_changed(&self) -> Result<bool, ServerError> {
+        let mut sources = self.sources();
+        let modified = modification_times(&sources.config, &sources.hosts);
+        if sources.modified == modified {
+            return Ok(false);
+        }
+        sources.modified = modified;
+        self.build(&sources.config, &sources.hosts).map(|_| true)
+    }
+
+    /// Builds the acceptor from other files and keeps them for later reloads. The ticket keys
+    /// and their rotation stay as they were, so sessions still resume; tickets can be turned
+    /// off but not on. On failure nothing changes.
+    pub fn replace(&self, config: TlsConfig, hosts: Vec<(Vec<String>, Option<TlsConfig>)>) -> Result<(), ServerError> {
+        if config.protocol.ticket_rotation.is_some() && self.tickets.is_none() {
+            return Err(ServerError::Tls("Session tickets cannot be turned on by a reload".to_string()));
+        }
+        let mut sources = self.sources();
+        self.build(&config, &hosts)?;
+        let modified = modification_times(&config, &hosts);
+        *sources = Sources { config, hosts, modified };
+        Ok(())
+    }
+
+    /// Replaces the session ticket key on the configured rotation until the acceptor is dropped.
+    /// Only the keys in memory change; nothing is read from the files.
+    pub fn rotate_tickets(acceptor: &Arc<ReloadableAcceptor>) {
+        let (interval, tickets) = match (acceptor.sources().config.protocol.ticket_rotation, acceptor.tickets.as_ref()) {
+            (Some(interval), Some(tickets)) => (interval, Arc::downgrade(tickets)),
+            _ => return
+        };
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match tickets.upgrade() {
+                Some(tickets) => if let Err(e) = tickets.rotate() {
+                    eprintln!("Keeping the current session ticket key:{}", e);
+                },
+                None => break
+            }
+        });
+    }
+
+    /// Checks the files every `interval` until the acceptor is dropped.
+    pub fn watch(acceptor: &Arc<ReloadableAcceptor>, interval: Duration) {
+        let acceptor: Weak<ReloadableAcceptor> = Arc::downgrade(acceptor);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match acceptor.upgrade() {
+                Some(acceptor) => match acceptor.reload_if_changed() {
+                    Ok(true) => println!("Reloaded TLS certificates"),
+                    Ok(false) => {},
+                    Err(e) => eprintln!("Keeping the current TLS certificates:{}", e)
+                },
+                None => break
+            }
+        });
+    }
+
+    /// Builds an acceptor serving `config` and swaps it in, sealing tickets with the keys only
+    /// when `config` has them on.
+    fn build(&self, config: &TlsConfig, hosts: &[(Vec<String>, Option<TlsConfig>)]) -> Result<(), ServerError> {
+        let tickets = self.tickets.as_ref().filter(|_| config.protocol.ticket_rotation.is_some());
+        let acceptor = Arc::new(build_acceptor(config, hosts, tickets)?);
+        match self.current.write() {
+            Ok(mut current) => *current = acceptor,
+            Err(poisoned) => *poisoned.into_inner() = acceptor
+        }
+        Ok(())
+    }
+
+    fn sources(&self) -> MutexGuard<'_, Sources> {
+        self.sources.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn build_acceptor(config: &TlsConfig, hosts: &[(Vec<String>, Option<TlsConfig>)], tickets: Option<&Arc<TicketKeys>>) -> Result<SslAcceptor, ServerError> {
+    let hosts: Vec<HostTls> = hosts.iter()
+        .map(|(names, config)| HostTls { names, config: config.as_ref() })
+        .collect();
+    acceptor_with_tickets(config, &hosts, tickets)
+}
+
+fn modification_times(config: &TlsConfig, hosts: &[(Vec<String>, Option<TlsConfig>)]) -> Vec<Option<SystemTime>> {
+    Some(config).into_iter()
+        .chain(hosts.iter().filter_map(|(_, config)| config.as_ref()))
+        .flat_map(|config| config.files())
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+/// A certificate offered to clients asking for one of the names it was issued for.
+struct Certificate {
+    names: Vec<String>,
+    context: SslContext,
+    verify_mode: SslVerifyMode
+}
+
+/// Builds an acceptor serving `config` by default and each host's own certificate to clients
+/// asking for it by SNI. Fails if some host name is not covered by any certificate.
+pub fn create_acceptor(config: &TlsConfig, hosts: &[HostTls]) -> Result<SslAcceptor, ServerError> {
+    let tickets = match config.protocol.ticket_rotation {
+        Some(_) => Some(Arc::new(TicketKeys::new()?)),
+        None => None
+    };
+    acceptor_with_tickets(config, hosts, tickets.as_ref())
+}
+
+/// Seals session tickets with `tickets` when they are enabled.
+fn acceptor_with_tickets(config: &TlsConfig, hosts: &[HostTls], tickets: Option<&Arc<TicketKeys>>) -> Result<SslAcceptor, ServerError> {
+    let identity = load_identity(config)?;
+    let staple = load_staple(config, &identity)?;
+    let default_names = certificate_names(&identity.cert);
+
+    let mut certificates = vec![];
+    for host in hosts {
+        if let Some(host_config) = host.config {
+            let host_identity = load_identity(host_config)?;
+            let names = certificate_names(&host_identity.cert);
+            if let Some(name) = uncovered(host.names, &names) {
+                return Err(ServerError::Tls(format!("Host {} is not covered by the cert {}", name, host_config.cert_path().display())));
+            }
+            let host_staple = load_staple(host_config, &host_identity)?;
+            let context = host_context(host_identity, host_staple, host_config.client_auth.as_ref(), tickets)?;
+            certificates.push(Certificate { names, context, verify_mode: verify_mode(host_config.client_auth.as_ref()) });
+        }
+    }
+    for host in hosts.iter().filter(|host| host.config.is_none()) {
+        let name = uncovered(host.names, &default_names)
+            .filter(|name| certificates.iter().all(|certificate| uncovered(&[name.to_string()], &certificate.names).is_some()));
+        if let Some(name) = name {
+            return Err(ServerError::Tls(format!("Host {} is not covered by any cert", name)));
+        }
+    }
+
+    let mut builder = match config.protocol.profile {
+        TlsProfile::Modern => SslAcceptorBuilder::mozilla_modern_raw(SslMethod::tls()),
+        TlsProfile::Intermediate => SslAcceptorBuilder::mozilla_intermediate_raw(SslMethod::tls())
+    }.and_then(|mut builder| {
+        {
+            let context = builder.builder_mut();
+            context.set_private_key(&identity.pkey)?;
+            context.set_certificate(&identity.cert)?;
+            for cert in identity.chain {
+                context.add_extra_chain_cert(cert)?;
+            }
+        }
+        Ok(builder)
+    }).map_err(|e| ServerError::Tls(format!("Error building acceptor:{}", e)))?;
+    configure_protocol(builder.builder_mut(), &config.protocol)?;
+    enable_ktls(builder.builder_mut());
+    if let Some(staple) = staple {
+        configure_stapling(builder.builder_mut(), staple)?;
+    }
+    if let Some(ref client_auth) = config.client_auth {
+        configure_client_auth(builder.builder_mut(), client_auth)?;
+    }
+    if let Some(tickets) = tickets {
+        configure_tickets(builder.builder_mut(), tickets)?;
+    }
+    if !certificates.is_empty() {
+        builder.builder_mut().set_servername_callback(move |ssl| {
+            let selected = ssl.servername()
+                .map(|name| name.trim_end_matches('.').to_ascii_lowercase())
+                .and_then(|name| select(&certificates, &name));
+            if let Some(certificate) = selected {
+                // Keeps the default certificate if the switch fails.
+                if ssl.set_ssl_context(&certificate.context).is_ok() {
+                    ssl.set_verify(certificate.verify_mode);
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(builder.build())
+}
+
+/// A context carrying only a host's key, certificates, OCSP response and client CAs. The
+/// handshake keeps every other setting of the acceptor when switching to it.
+fn host_context(identity: Identity, staple: Option<Staple>, client_auth: Option<&ClientAuth>, tickets: Option<&Arc<TicketKeys>>) -> Result<SslContext, ServerError> {
+    let mut builder = SslContextBuilder::new(SslMethod::tls())
+        .and_then(|mut builder| {
+            builder.set_certificate(&identity.cert)?;
+            builder.set_private_key(&identity.pkey)?;
+            for cert in identity.chain {
+                builder.add_extra_chain_cert(cert)?;
+            }
+            Ok(builder)
+        })
+        .map_err(|e| ServerError::Tls(format!("Error building host context:{}", e)))?;
+    if let Some(staple) = staple {
+        configure_stapling(&mut builder, staple)?;
+    }
+    if let Some(client_auth) = client_auth {
+        configure_client_auth(&mut builder, client_auth)?;
+    }
+    if let Some(tickets) = tickets {
+        configure_tickets(&mut builder, tickets)?;
+    }
+    Ok(builder.build())
+}
+
+fn configure_protocol(builder: &mut SslContextBuilder, protocol: &Protocol) -> Result<(), ServerError> {
+    if let Some(ref ciphers) = protocol.ciphers {
+        builder.set_cipher_list(ciphers).map_err(|e| ServerError::Tls(format!("Invalid cipher list {}:{}", ciphers, e)))?;
+    }
+
+    let min_version = match (protocol.min_version, protocol.profile) {
+        (Some(version), _) => version,
+        (None, TlsProfile::Modern) => TlsVersion::Tls12,
+        (None, TlsProfile::Intermediate) => TlsVersion::Tls10
+    };
+    let mut disabled = SslOption::empty();
+    if min_version > TlsVersion::Tls10 {
+        disabled |= SSL_OP_NO_TLSV1;
+    }
+    if min_version > TlsVersion::Tls11 {
+        disabled |= SSL_OP_NO_TLSV1_1;
+    }
+    if min_version > TlsVersion::Tls12 {
+        disabled |= SSL_OP_NO_TLSV1_2;
+    }
+    builder.set_options(disabled);
+
+    if let Some(ref curves) = protocol.curves {
+        let list = CString::new(curves.as_str()).map_err(|_| ServerError::Tls(format!("Invalid curve list {}", curves)))?;
+        let set = unsafe { SSL_CTX_ctrl(builder.as_ptr() as *mut c_void, SSL_CTRL_SET_CURVES_LIST, 0, list.as_ptr() as *mut c_void) };
+        if set != 1 {
+            return Err(ServerError::Tls(format!("Invalid curve list {}", curves)));
+        }
+    }
+
+    if protocol.ticket_rotation.is_some() {
+        builder.clear_options(SSL_OP_NO_TICKET);
+    }
+    if let Some((size, timeout)) = protocol.session_cache {
+        unsafe {
+            let context = builder.as_ptr() as *mut c_void;
+            if size == 0 {
+                SSL_CTX_ctrl(context, SSL_CTRL_SET_SESS_CACHE_MODE, SSL_SESS_CACHE_OFF, ptr::null_mut());
+            } else {
+                SSL_CTX_ctrl(context, SSL_CTRL_SET_SESS_CACHE_MODE, SSL_SESS_CACHE_SERVER, ptr::null_mut());
+                SSL_CTX_ctrl(context, SSL_CTRL_SET_SESS_CACHE_SIZE, size as c_long, ptr::null_mut());
+                SSL_CTX_set_timeout(context, timeout.as_secs() as c_long);
+            }
+        }
+        builder.set_session_id_context(b"rust-http2-server")
+            .map_err(|e| ServerError::Tls(format!("Error setting session id context:{}", e)))?;
+    }
+    Ok(())
+}
+
+/// Staples the response to handshakes until it expires.
+fn configure_stapling(builder: &mut SslContextBuilder, staple: Staple) -> Result<(), ServerError> {
+    builder.set_status_callback(move |ssl| {
+        if Utc::now() < staple.next_update {
+            ssl.set_ocsp_status(&staple.der)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }).map_err(|e| ServerError::Tls(format!("Error enabling OCSP stapling:{}", e)))
+}
+
+static TICKET_KEYS: OnceLock<Option<Index<SslContext, Arc<TicketKeys>>>> = OnceLock::new();
+
+/// Where a context keeps the ticket keys its callback seals and opens tickets with.
+fn ticket_keys_index() -> Option<Index<SslContext, Arc<TicketKeys>>> {
+    *TICKET_KEYS.get_or_init(|| SslContext::new_ex_index().ok())
+}
+
+/// Has tickets sealed with `tickets` rather than OpenSSL's own key. Host contexts carry the keys
+/// too, as the handshake may have switched to one by the time a ticket is opened.
+fn configure_tickets(builder: &mut SslContextBuilder, tickets: &Arc<TicketKeys>) -> Result<(), ServerError> {
+    let index = ticket_keys_index().ok_or_else(|| ServerError::Tls("Error setting up session ticket keys".to_string()))?;
+    builder.set_ex_data(index, tickets.clone());
+    let set = unsafe {
+        SSL_CTX_callback_ctrl(builder.as_ptr() as *mut c_void, SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB, ticket_key_callback)
+    };
+    if set != 1 {
+        return Err(ServerError::Tls("Error setting up session ticket keys".to_string()));
+    }
+    Ok(())
+}
+
+/// Sets up `cipher` and `hmac` to seal a new ticket with the current key, or to open one with the
+/// key named in it. A ticket sealed with the previous key is opened and renewed, and one sealed
+/// with an older key is refused, so the client goes through a full handshake.
+unsafe extern "C" fn ticket_key_callback(ssl: *mut c_void, name: *mut u8, iv: *mut u8, cipher: *mut c_void, hmac: *mut c_void, encrypt: c_int) -> c_int {
+    let context = SslContextRef::from_ptr(SSL_get_SSL_CTX(ssl) as *mut _);
+    let tickets = match ticket_keys_index().and_then(|index| context.ex_data(index)) {
+        Some(tickets) => tickets,
+        None => return 0
+    };
+    if encrypt == 1 {
+        let key = tickets.current();
+        if rand_bytes(slice::from_raw_parts_mut(iv, TICKET_IV_LEN)).is_err() {
+            return -1;
+        }
+        ptr::copy_nonoverlapping(key.name.as_ptr(), name, key.name.len());
+        if EVP_EncryptInit_ex(cipher, EVP_aes_256_cbc(), ptr::null_mut(), key.aes.as_ptr(), iv) != 1
+            || HMAC_Init_ex(hmac, key.hmac.as_ptr() as *const c_void, key.hmac.len() as c_int, EVP_sha256(), ptr::null_mut()) != 1 {
+            return -1;
+        }
+        1
+    } else {
+        let (key, current) = match tickets.find(slice::from_raw_parts(name, TICKET_NAME_LEN)) {
+            Some(found) => found,
+            None => return 0
+        };
+        if HMAC_Init_ex(hmac, key.hmac.as_ptr() as *const c_void, key.hmac.len() as c_int, EVP_sha256(), ptr::null_mut()) != 1
+            || EVP_DecryptInit_ex(cipher, EVP_aes_256_cbc(), ptr::null_mut(), key.aes.as_ptr(), iv) != 1 {
+            return -1;
+        }
+        if current { 1 } else { 2 }
+    }
+}
+
+fn verify_mode(client_auth: Option<&ClientAuth>) -> SslVerifyMode {
+    match client_auth.map(|client_auth| client_auth.mode) {
+        Some(ClientAuthMode::Required) => SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT,
+        Some(ClientAuthMode::Optional) => SSL_VERIFY_PEER,
+        None => SSL_VERIFY_NONE
+    }
+}
+
+fn configure_client_auth(builder: &mut SslContextBuilder, client_auth: &ClientAuth) -> Result<(), ServerError> {
+    builder.set_ca_file(&client_auth.ca_path)
+        .map_err(|e| ServerError::Tls(format!("Error loading client CA {}:{}", client_auth.ca_path.display(), e)))?;
+    if let Some(ref crl_path) = client_auth.crl_path {
+        load_crl(builder.cert_store_mut(), crl_path)?;
+    }
+    // Resuming a session with a verified client needs an id for this server.
+    builder.set_session_id_context(b"rust-http2-server")
+        .map_err(|e| ServerError::Tls(format!("Error setting session id context:{}", e)))?;
+    builder.set_verify(verify_mode(Some(client_auth)));
+    Ok(())
+}
+
+/// Has OpenSSL hand the record layer to the kernel where it can, so that files can be sent to
+/// TLS clients with sendfile. Without kernel support, connections carry on as before.
+fn enable_ktls(builder: &mut SslContextBuilder) {
+    if unsafe { OpenSSL_version_num() } >= OPENSSL_3 {
+        unsafe {
+            SSL_CTX_set_options(builder.as_ptr() as *mut c_void, SSL_OP_ENABLE_KTLS);
+        }
+    }
+}
+
+/// Whether the kernel encrypts what is written to the connection's socket, so it can be written
+/// to directly.
+pub fn ktls_send(ssl: &SslRef) -> bool {
+    unsafe {
+        let bio = SSL_get_wbio(ssl.as_ptr() as *const c_void);
+        !bio.is_null() && BIO_ctrl(bio, BIO_CTRL_GET_KTLS_SEND, 0, ptr::null_mut()) == 1
+    }
+}
+
+/// Seconds of clock difference with the OCSP responder tolerated in its update times.
+const OCSP_CLOCK_SKEW: u32 = 300;
+const X509_FILETYPE_PEM: c_int = 1;
+const X509_V_FLAG_CRL_CHECK: c_ulong = 0x4;
+const SSL_CTRL_SET_SESS_CACHE_SIZE: c_int = 42;
+const SSL_CTRL_SET_SESS_CACHE_MODE: c_int = 44;
+const SSL_CTRL_SET_CURVES_LIST: c_int = 92;
+const SSL_SESS_CACHE_OFF: c_long = 0;
+const SSL_SESS_CACHE_SERVER: c_long = 2;
+const OPENSSL_3: c_ulong = 0x3000_0000;
+const SSL_OP_ENABLE_KTLS: u64 = 1 << 3;
+const BIO_CTRL_GET_KTLS_SEND: c_int = 73;
+const SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB: c_int = 72;
+const TICKET_NAME_LEN: usize = 16;
+const TICKET_IV_LEN: usize = 16;
+
+type TicketKeyCallback = unsafe extern "C" fn(*mut c_void, *mut u8, *mut u8, *mut c_void, *mut c_void, c_int) -> c_int;
+
+// The openssl crate has no bindings for revocation lists, curve lists, the session cache, ticket
+// keys or kTLS.
+extern "C" {
+    fn OpenSSL_version_num() -> c_ulong;
+    fn SSL_CTX_set_options(context: *mut c_void, options: u64) -> u64;
+    fn SSL_get_wbio(ssl: *const c_void) -> *mut c_void;
+    fn BIO_ctrl(bio: *mut c_void, command: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_CTX_ctrl(context: *mut c_void, command: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_CTX_set_timeout(context: *mut c_void, timeout: c_long) -> c_long;
+    fn SSL_CTX_callback_ctrl(context: *mut c_void, command: c_int, callback: TicketKeyCallback) -> c_long;
+    fn SSL_get_SSL_CTX(ssl: *mut c_void) -> *mut c_void;
+    fn EVP_aes_256_cbc() -> *const c_void;
+    fn EVP_sha256() -> *const c_void;
+    fn EVP_EncryptInit_ex(cipher: *mut c_void, kind: *const c_void, engine: *mut c_void, key: *const u8, iv: *const u8) -> c_int;
+    fn EVP_DecryptInit_ex(cipher: *mut c_void, kind: *const c_void, engine: *mut c_void, key: *const u8, iv: *const u8) -> c_int;
+    fn HMAC_Init_ex(hmac: *mut c_void, key: *const c_void, len: c_int, digest: *const c_void, engine: *mut c_void) -> c_int;
+    fn X509_LOOKUP_file() -> *mut c_void;
+    fn X509_STORE_add_lookup(store: *mut c_void, method: *mut c_void) -> *mut c_void;
+    fn X509_load_crl_file(lookup: *mut c_void, file: *const c_char, file_type: c_int) -> c_int;
+    fn X509_STORE_set_flags(store: *mut c_void, flags: c_ulong) -> c_int;
+}
+
+/// Adds the revocation lists in `path` to `store` and checks client certificates against them.
+fn load_crl(store: &mut X509StoreBuilderRef, path: &Path) -> Result<(), ServerError> {
+    let error = || ServerError::Tls(format!("Error loading CRL {}", path.display()));
+    let file = path.to_str().and_then(|path| CString::new(path).ok()).ok_or_else(error)?;
+    let loaded = unsafe {
+        let store = store.as_ptr() as *mut c_void;
+        let lookup = X509_STORE_add_lookup(store, X509_LOOKUP_file());
+        !lookup.is_null()
+            && X509_load_crl_file(lookup, file.as_ptr(), X509_FILETYPE_PEM) > 0
+            && X509_STORE_set_flags(store, X509_V_FLAG_CRL_CHECK) == 1
+    };
+    if loaded {
+        Ok(())
+    } else {
+        Err(error())
+    }
+}
+
+/// The server name and client certificate of a finished handshake.
+pub fn session(ssl: &SslRef) -> TlsSession {
+    TlsSession {
+        server_name: ssl.servername().map(|name| name.to_string()),
+        client_cert: client_cert(ssl)
+    }
+}
+
+/// The verified certificate the client presented, if any.
+fn client_cert(ssl: &SslRef) -> Option<ClientCert> {
+    if ssl.verify_result().is_some() {
+        return None;
+    }
+    let cert = ssl.peer_certificate()?;
+    let fields = [
+        ("CN", nid::COMMONNAME), ("OU", nid::ORGANIZATIONALUNITNAME), ("O", nid::ORGANIZATIONNAME),
+        ("L", nid::LOCALITYNAME), ("ST", nid::STATEORPROVINCENAME), ("C", nid::COUNTRYNAME)
+    ];
+    let subject: Vec<String> = fields.iter()
+        .flat_map(|&(label, nid)| {
+            cert.subject_name().entries_by_nid(nid)
+                .filter_map(|entry| entry.data().as_utf8().ok())
+                .map(move |value| format!("{}={}", label, &*value))
+                .collect::<Vec<String>>()
+        })
+        .collect();
+    let alt_names = cert.subject_alt_names()
+        .map(|names| names.iter().filter_map(|name| {
+            name.dnsname().map(|dns| dns.to_string()).or_else(|| name.ipaddress().and_then(ip_address))
+        }).collect())
+        .unwrap_or_default();
+    Some(ClientCert { subject: subject.join(","), alt_names })
+}
+
+fn ip_address(octets: &[u8]) -> Option<String> {
+    match octets.len() {
+        4 => Some(IpAddr::from([octets[0], octets[1], octets[2], octets[3]]).to_string()),
+        16 => {
+            let mut v6 = [0u8; 16];
+            v6.copy_from_slice(octets);
+            Some(IpAddr::from(v6).to_string())
+        },
+        _ => None
+    }
+}
+
+/// Exact names win over wildcards.
+fn select<'a>(certificates: &'a [Certificate], server_name: &str) -> Option<&'a Certificate> {
+    certificates.iter()
+        .find(|certificate| certificate.names.iter().any(|name| name == server_name))
+        .or_else(|| certificates.iter().find(|certificate| {
+            certificate.names.iter().any(|name| name.starts_with("*.") && name_matches(name, server_name))
+        }))
+}
+
+/// The DNS names a certificate was issued for, or its common name if it lists none.
+fn certificate_names(cert: &X509) -> Vec<String> {
+    let mut names: Vec<String> = cert.subject_alt_names()
+        .map(|alt_names| alt_names.iter().filter_map(|name| name.dnsname()).map(|name| name.to_ascii_lowercase()).collect())
+        .unwrap_or_default();
+    if names.is_empty() {
+        names = cert.subject_name().entries_by_nid(nid::COMMONNAME)
+            .filter_map(|entry| entry.data().as_utf8().ok())
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+    }
+    names
+}
+
+/// The first host name no certificate name matches. A wildcard host name needs the same
+/// wildcard in the certificate.
+fn uncovered<'a>(host_names: &'a [String], cert_names: &[String]) -> Option<&'a str> {
+    host_names.iter()
+        .map(|name| name.as_str())
+        .find(|host_name| {
+            let host_name = host_name.trim_end_matches('.').to_ascii_lowercase();
+            !cert_names.iter().any(|cert_name| *cert_name == host_name || name_matches(cert_name, &host_name))
+        })
+}
+
+/// A certificate wildcard stands for exactly one label, so `*.example.com` matches
+/// `www.example.com` but neither `example.com` nor `a.b.example.com`.
+fn name_matches(cert_name: &str, host: &str) -> bool {
+    match cert_name.strip_prefix('*') {
+        Some(suffix) if suffix.starts_with('.') => {
+            host.len() > suffix.len()
+                && host.ends_with(suffix)
+                && !host[..host.len() - suffix.len()].contains('.')
+        },
+        _ => cert_name == host
+    }
+}
+
+/// Loads the key and certificates, and checks that the key belongs to the certificate.
+fn load_identity(config: &TlsConfig) -> Result<Identity, ServerError> {
+    let passphrase = match config.passphrase_path {
+        Some(ref path) => {
+            let buff = read_file(path)
+                .map_err(|e| ServerError::Tls(format!("Error reading passphrase {}:{}", path.display(), e)))?;
+            let passphrase = String::from_utf8(buff)
+                .map_err(|_| ServerError::Tls(format!("Passphrase in {} is not UTF-8", path.display())))?;
+            Some(passphrase.trim_end_matches(['\r', '\n']).to_string())
+        },
+        None => None
+    };
+
+    let identity = match config.source {
+        KeySource::Pem { ref cert_path, ref private_key_path } => {
+            let pkey_buff = read_file(private_key_path)
+                .map_err(|e| ServerError::Tls(format!("Error reading private key {}:{}", private_key_path.display(), e)))?;
+            let cert_buff = read_file(cert_path)
+                .map_err(|e| ServerError::Tls(format!("Error reading cert {}:{}", cert_path.display(), e)))?;
+            pem_identity(&cert_buff, cert_path, &pkey_buff, private_key_path, passphrase.as_deref())?
+        },
+        KeySource::Memory { ref cert, ref private_key } => {
+            pem_identity(cert, config.cert_path(), private_key, Path::new("in memory"), passphrase.as_deref())?
+        },
+        KeySource::Pkcs12(ref path) => {
+            let buff = read_file(path)
+                .map_err(|e| ServerError::Tls(format!("Error reading PKCS#12 archive {}:{}", path.display(), e)))?;
+            let parsed = Pkcs12::from_der(&buff)
+                .and_then(|archive| archive.parse(passphrase.as_deref().unwrap_or("")))
+                .map_err(|e| ServerError::Tls(format!("Error extracting PKCS#12 archive {}:{}", path.display(), e)))?;
+            Identity { pkey: parsed.pkey, cert: parsed.cert, chain: parsed.chain.into_iter().collect() }
+        }
+    };
+
+    let matches = identity.cert.public_key().map(|public| public.public_eq(&identity.pkey)).unwrap_or(false);
+    if !matches {
+        return Err(ServerError::Tls(format!("Private key does not match the cert {}", config.cert_path().display())));
+    }
+    Ok(identity)
+}
+
+/// Loads the OCSP response for the identity's certificate, refusing one that is unsuccessful,
+/// about another certificate, not good or expired.
+fn load_staple(config: &TlsConfig, identity: &Identity) -> Result<Option<Staple>, ServerError> {
+    let path = match config.ocsp_path {
+        Some(ref path) => path,
+        None => return Ok(None)
+    };
+    let error = |message: &str| ServerError::Tls(format!("OCSP response {} {}", path.display(), message));
+    let der = read_file(path).map_err(|e| error(&format!("could not be read:{}", e)))?;
+    let response = OcspResponse::from_der(&der).map_err(|e| error(&format!("could not be parsed:{}", e)))?;
+    if response.status() != RESPONSE_STATUS_SUCCESSFUL {
+        return Err(error("is not successful"));
+    }
+    let basic = response.basic().map_err(|e| error(&format!("has no basic response:{}", e)))?;
+
+    // The issuer has to be among the intermediates to tell which certificate a response is about.
+    let ids = identity.chain.iter()
+        .filter_map(|issuer| OcspCertId::from_cert(MessageDigest::sha1(), &identity.cert, issuer).ok())
+        .collect::<Vec<_>>();
+    let status = ids.iter().filter_map(|id| basic.find_status(id)).next()
+        .ok_or_else(|| error(&format!("is not for the cert {}", config.cert_path().display())))?;
+    if status.status != CERT_STATUS_GOOD {
+        return Err(error("does not report the cert as good"));
+    }
+    if status.next_update.as_ptr().is_null() {
+        return Err(error("has no next update time"));
+    }
+    status.check_validity(OCSP_CLOCK_SKEW, None).map_err(|_| error("has expired"))?;
+    let next_update = Utc.datetime_from_str(&status.next_update.to_string(), "%b %e %H:%M:%S %Y GMT")
+        .map_err(|e| error(&format!("has an unreadable next update time:{}", e)))?;
+    Ok(Some(Staple { der, next_update }))
+}
+
+/// `cert_path` and `private_key_path` only name the PEM data in errors.
+fn pem_identity(cert_buff: &[u8], cert_path: &Path, pkey_buff: &[u8], private_key_path: &Path, passphrase: Option<&str>) -> Result<Identity, ServerError> {
+    let pkey = match passphrase {
+        Some(passphrase) => PKey::private_key_from_pem_passphrase(pkey_buff, passphrase.as_bytes()),
+        // Without a passphrase an encrypted key fails to load rather than prompting on the terminal.
+        None => PKey::private_key_from_pem_callback(pkey_buff, |_| Ok(0))
+    }.map_err(|e| ServerError::Tls(format!("Error extracting private key {}:{}", private_key_path.display(), e)))?;
+
+    let mut certs = X509::stack_from_pem(cert_buff)
+        .map_err(|e| ServerError::Tls(format!("Error extracting cert {}:{}", cert_path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(ServerError::Tls(format!("No cert found in {}", cert_path.display())));
+    }
+    let cert = certs.remove(0);
+    Ok(Identity { pkey, cert, chain: certs })
+}
+
+fn read_file(path: &Path) -> ::std::io::Result<Vec<u8>> {
+    let mut buff: Vec<u8> = vec![];
+    File::open(path)?.read_to_end(&mut buff)?;
+    Ok(buff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_acceptor, load_identity, load_staple, name_matches, uncovered, HostTls, ReloadableAcceptor, TicketKeys, TlsConfig};
+    use std::sync::Arc;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn wildcards_match_one_label() {
+        assert!(name_matches("*.example.com", "www.example.com"));
+        assert!(!name_matches("*.example.com", "example.com"));
+        assert!(!name_matches("*.example.com", "a.b.example.com"));
+        assert!(name_matches("example.com", "example.com"));
+    }
+
+    #[test]
+    fn wildcard_hosts_need_wildcard_certs() {
+        let cert_names = names(&["example.com", "*.example.com"]);
+        assert_eq!(None, uncovered(&names(&["*.example.com", "WWW.example.com."]), &cert_names));
+        assert_eq!(Some("*.api.example.com"), uncovered(&names(&["*.api.example.com"]), &cert_names));
+    }
+
+    #[test]
+    fn uncovered_hosts_are_reported() {
+        let config = TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key");
+        let host_names = names(&["www.example.com"]);
+        let error = create_acceptor(&config, &[HostTls { names: &host_names, config: None }]).err().unwrap();
+        assert_eq!("TLS configuration error:Host www.example.com is not covered by any cert", error.to_string());
+
+        let example = TlsConfig::new("test_resources/tls/example.pem", "test_resources/tls/example.key");
+        assert!(create_acceptor(&config, &[HostTls { names: &host_names, config: Some(&example) }]).is_ok());
+    }
+
+    #[test]
+    fn unchanged_files_are_not_reloaded() {
+        let config = TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key");
+        let acceptor = ReloadableAcceptor::new(config, vec![]).unwrap();
+        let before = acceptor.current();
+
+        assert!(!acceptor.reload_if_changed().unwrap());
+        assert!(Arc::ptr_eq(&before, &acceptor.current()));
+        acceptor.reload().unwrap();
+        assert!(!Arc::ptr_eq(&before, &acceptor.current()));
+    }
+
+    #[test]
+    fn ticket_keys_open_tickets_for_one_more_rotation() {
+        let tickets = TicketKeys::new().ok().unwrap();
+        let first = tickets.current();
+        tickets.rotate().ok().unwrap();
+        let second = tickets.current();
+        assert!(first.name != second.name);
+        assert!(tickets.find(&second.name).unwrap().1);
+        assert!(!tickets.find(&first.name).unwrap().1);
+
+        tickets.rotate().ok().unwrap();
+        assert!(tickets.find(&first.name).is_none());
+        assert!(!tickets.find(&second.name).unwrap().1);
+    }
+
+    #[test]
+    fn full_chain_bundles_include_intermediates() {
+        let config = TlsConfig::new("test_resources/tls/chain.pem", "test_resources/tls/chain.key")
+            .passphrase_file("test_resources/tls/chain.pass");
+        let identity = load_identity(&config).ok().unwrap();
+        assert_eq!(1, identity.chain.len());
+
+        let archive = TlsConfig::pkcs12("test_resources/tls/chain.p12").passphrase_file("test_resources/tls/chain.pass");
+        let identity = load_identity(&archive).ok().unwrap();
+        assert_eq!(1, identity.chain.len());
+    }
+
+    #[test]
+    fn encrypted_keys_need_their_passphrase() {
+        let config = TlsConfig::new("test_resources/tls/chain.pem", "test_resources/tls/chain.key");
+        assert!(load_identity(&config).is_err());
+        assert!(load_identity(&TlsConfig::pkcs12("test_resources/tls/chain.p12")).is_err());
+    }
+
+    #[test]
+    fn keys_must_match_the_cert() {
+        let config = TlsConfig::new("test_resources/tls/example.pem", "test_resources/tls/localhost.key");
+        let error = load_identity(&config).err().unwrap();
+        assert_eq!("TLS configuration error:Private key does not match the cert test_resources/tls/example.pem", error.to_string());
+    }
+
+    #[test]
+    fn ocsp_responses_must_be_current_and_for_the_cert() {
+        let config = TlsConfig::new("test_resources/tls/chain.pem", "test_resources/tls/chain.key")
+            .passphrase_file("test_resources/tls/chain.pass");
+        let identity = load_identity(&config).ok().unwrap();
+
+        let good = config.clone().ocsp_response("test_resources/tls/chain.ocsp");
+        assert!(load_staple(&good, &identity).ok().unwrap().is_some());
+
+        let expired = config.clone().ocsp_response("test_resources/tls/chain-expired.ocsp");
+        let error = load_staple(&expired, &identity).err().unwrap();
+        assert_eq!("TLS configuration error:OCSP response test_resources/tls/chain-expired.ocsp has expired", error.to_string());
+
+        let other = config.ocsp_response("test_resources/tls/other.ocsp");
+        let error = load_staple(&other, &identity).err().unwrap();
+        assert_eq!("TLS configuration error:OCSP response test_resources/tls/other.ocsp is not for the cert test_resources/tls/chain.pem", error.to_string());
+    }
+}
@@ -0,0 +1,123 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::OnceLock;
+use std::time::Duration;
+use libc;
+use server::listener::{set_cloexec, wait_readable, Listener};
+
+/// The listening sockets handed over, as `name=fd` pairs separated by commas.
+const FDS_VAR: &str = "RUST_HTTPS_SERVER_FDS";
+/// The pipe the new process writes to once it is serving.
+const READY_VAR: &str = "RUST_HTTPS_SERVER_READY_FD";
+
+/// Where this program was started from. Once a new binary is renamed into place, the running
+/// one only reads as deleted, so the path is taken before that can happen.
+static EXECUTABLE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Notes where this program was started from, for upgrades to start whatever is there then.
+pub fn remember_executable() {
+    EXECUTABLE.get_or_init(|| env::current_exe().ok());
+}
+
+/// Takes the listening sockets handed over by the process upgrading to this one. Empty when
+/// this process was not started for an upgrade.
+pub fn inherited_listeners() -> io::Result<Vec<(String, Listener)>> {
+    let fds = env::var(FDS_VAR).ok();
+    env::remove_var(FDS_VAR);
+    match fds {
+        Some(fds) => parse_fds(&fds)?.into_iter()
+            .map(|(name, fd)| unsafe { Listener::from_raw_fd(fd) }.map(|listener| (name, listener)))
+            .collect(),
+        None => Ok(vec![])
+    }
+}
+
+fn parse_fds(value: &str) -> io::Result<Vec<(String, RawFd)>> {
+    value.split(',').filter(|pair| !pair.is_empty()).map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next().and_then(|fd| fd.parse::<RawFd>().ok())) {
+            (Some(name), Some(fd)) => Ok((name.to_string(), fd)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}: {}", FDS_VAR, value)))
+        }
+    }).collect()
+}
+
+fn format_fds(fds: &[(String, RawFd)]) -> String {
+    fds.iter().map(|&(ref name, fd)| format!("{}={}", name, fd)).collect::<Vec<String>>().join(",")
+}
+
+/// Tells the process upgrading to this one that it can stop, if there is one.
+pub fn notify_ready() -> io::Result<()> {
+    let fd = env::var(READY_VAR).ok().and_then(|fd| fd.parse::<RawFd>().ok());
+    env::remove_var(READY_VAR);
+    match fd {
+        Some(fd) => unsafe { File::from_raw_fd(fd) }.write_all(b"1"),
+        None => Ok(())
+    }
+}
+
+/// Starts a new copy of this program with the same arguments, handing it `fds`, and waits up
+/// to `timeout` for it to be serving. A copy that does not get there in time is killed.
+pub fn spawn(fds: &[(String, RawFd)], timeout: Duration) -> io::Result<Child> {
+    let (mut ready, ready_to_write) = pipe()?;
+    let mut inherited: Vec<RawFd> = fds.iter().map(|&(_, fd)| fd).collect();
+    inherited.push(ready_to_write.as_raw_fd());
+    let executable = EXECUTABLE.get_or_init(|| env::current_exe().ok()).clone()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the path of this program is not known"))?;
+    let mut command = Command::new(executable);
+    command.args(env::args_os().skip(1))
+        .env(FDS_VAR, format_fds(fds))
+        .env(READY_VAR, ready_to_write.as_raw_fd().to_string())
+        // the new process is made the one systemd watches, so it keeps the watchdog fed
+        .env_remove("WATCHDOG_PID");
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherited {
+                set_cloexec(fd, false)?;
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    drop(ready_to_write);
+
+    let mut byte = [0];
+    let started = wait_readable(ready.as_raw_fd(), timeout)? && ready.read(&mut byte)? == 1;
+    if started {
+        Ok(child)
+    } else {
+        let _ = child.kill();
+        let _ = child.wait();
+        Err(io::Error::new(io::ErrorKind::TimedOut, "the new process did not start serving"))
+    }
+}
+
+/// Both ends are closed on exec; the one handed over is opened up in the new process only.
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    set_cloexec(fds[0], true)?;
+    set_cloexec(fds[1], true)?;
+    Ok((read, write))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_fds, parse_fds};
+
+    #[test]
+    fn fds_survive_the_environment() {
+        let fds = vec![("content".to_string(), 5), ("content".to_string(), 6), ("redirect".to_string(), 9)];
+        assert_eq!(fds, parse_fds(&format_fds(&fds)).unwrap());
+        assert!(parse_fds("content=x").is_err());
+        assert!(parse_fds("").unwrap().is_empty());
+    }
+}
@@ -1,19 +1,35 @@
 extern crate rust_https_server;
 extern crate reqwest;
 extern crate hyper;
+extern crate openssl;
 
-use rust_https_server::start_server;
+use rust_https_server::{ClientAuth, ClientAuthMode, DevCertificates, Handler, Hsts, HttpsRedirect, Limits, Location, LogTarget, Overload, RedirectStatus, Request, Response, ServerBuilder, ServerError, ServerHandle, TlsConfig, TlsVersion, UnixSocket, VirtualHost};
 use reqwest::{Client, StatusCode, Method};
 use hyper::header::Allow;
-use std::io::Read;
+use openssl::nid;
+use openssl::ssl::{Ssl, SslConnectorBuilder, SslContext, SslMethod, SslSession, SSL_OP_NO_TICKET, SSL_VERIFY_NONE, STATUS_TYPE_OCSP};
+use openssl::x509::{X509, X509_FILETYPE_PEM};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::os::raw::{c_long, c_void};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+fn start_server() -> ServerHandle {
+    ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap()
+}
 
 #[test]
 fn downloads_get_request() {
-    let handle = start_server("test_resources", "127.0.0.1", "0", None).unwrap();
+    let handle = start_server();
 
     let client = Client::new().unwrap();
-    let url = format!("http://127.0.0.1:{}/index.html", handle.port);
+    let url = format!("http://127.0.0.1:{}/index.html", handle.port().unwrap());
     let mut request = client.get(&url).unwrap();
     match request.send() {
         Ok(response) => assert_eq!(StatusCode::Ok, response.status()),
@@ -23,10 +39,10 @@ fn downloads_get_request() {
 
 #[test]
 fn returns_404_on_unknown_file() {
-    let handle = start_server("test_resources", "127.0.0.1", "0", None).unwrap();
+    let handle = start_server();
 
     let client = Client::new().unwrap();
-    let url = format!("http://127.0.0.1:{}/not-index.html", handle.port);
+    let url = format!("http://127.0.0.1:{}/not-index.html", handle.port().unwrap());
     let mut request = client.get(&url).unwrap();
     match request.send() {
         Ok(response) => assert_eq!(StatusCode::NotFound, response.status()),
@@ -36,10 +52,10 @@ fn returns_404_on_unknown_file() {
 
 #[test]
 fn head_returns_get_request_headers() {
-    let handle = start_server("test_resources", "127.0.0.1", "0", None).unwrap();
+    let handle = start_server();
 
     let client = Client::new().unwrap();
-    let url = format!("http://127.0.0.1:{}/index.html", handle.port);
+    let url = format!("http://127.0.0.1:{}/index.html", handle.port().unwrap());
     let mut request = client.head(&url).unwrap();
     match request.send() {
         Ok(mut response) => {
@@ -53,16 +69,1148 @@ fn head_returns_get_request_headers() {
 
 #[test]
 fn options_includes_allow_header() {
-    let handle = start_server("test_resources", "127.0.0.1", "0", None).unwrap();
+    let handle = start_server();
 
     let client = Client::new().unwrap();
-    let url = format!("http://127.0.0.1:{}/index.html", handle.port);
+    let url = format!("http://127.0.0.1:{}/index.html", handle.port().unwrap());
     let mut request = client.request(Method::Options, &url).unwrap();
     match request.send() {
         Ok(response) => {
             assert_eq!(StatusCode::Ok, response.status());
-            assert_eq!(true, response.headers().has::<Allow>())
+            assert!(response.headers().has::<Allow>())
         },
         Err(e) => panic!("Request error{}", e)
     }
 }
+
+#[test]
+fn lowercase_accept_encoding_serves_gzip() {
+    let handle = start_server();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nhost: localhost\r\naccept-encoding: gzip\r\nconnection: close\r\n\r\n").unwrap();
+    let mut bytes = vec![];
+    stream.read_to_end(&mut bytes).unwrap();
+    let response = String::from_utf8_lossy(&bytes);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Encoding: gzip"));
+}
+
+struct Hello;
+
+impl Handler for Hello {
+    fn handle(&self, request: &Request) -> Option<Response> {
+        if request.target == "/hello" {
+            Some(Response::new(200, "OK").with_body("hello"))
+        } else {
+            None
+        }
+    }
+
+    fn on_response(&self, _request: &Request, response: &mut Response) {
+        response.headers.append("X-Greeting", "hello");
+    }
+}
+
+#[test]
+fn handlers_answer_before_root_directory() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .handler(Hello)
+        .start()
+        .unwrap();
+
+    let client = Client::new().unwrap();
+    let mut request = client.get(&format!("http://127.0.0.1:{}/hello", handle.port().unwrap())).unwrap();
+    let mut response = request.send().unwrap();
+    let mut body = String::new();
+    response.read_to_string(&mut body).unwrap();
+    assert_eq!(StatusCode::Ok, response.status());
+    assert_eq!("hello", body);
+
+    let mut request = client.get(&format!("http://127.0.0.1:{}/index.html", handle.port().unwrap())).unwrap();
+    let response = request.send().unwrap();
+    assert_eq!(StatusCode::Ok, response.status());
+    assert_eq!(Some("hello"), response.headers().get_raw("X-Greeting").and_then(|raw| raw.one())
+        .map(|value| ::std::str::from_utf8(value).unwrap()));
+}
+
+#[test]
+fn oversized_headers_are_rejected() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .limits(Limits { max_headers: 1, ..Limits::default() })
+        .start()
+        .unwrap();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nX-One: 1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 431"));
+}
+
+#[test]
+fn missing_root_is_reported() {
+    match ServerBuilder::new("no_such_directory").listen("127.0.0.1:0".parse().unwrap()).start() {
+        Err(ServerError::RootNotFound(_)) => {},
+        _ => panic!("Expected RootNotFound")
+    }
+}
+
+fn get_with_host(port: u16, host: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn virtual_hosts_are_selected_by_host_header() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .virtual_host(VirtualHost::new("test_resources/vhost").name("vhost.test").name("*.vhost.test"))
+        .start()
+        .unwrap();
+
+    assert!(get_with_host(handle.port().unwrap(), "vhost.test").contains("<h1>Virtual host</h1>"));
+    assert!(get_with_host(handle.port().unwrap(), "www.VHOST.test:80").contains("<h1>Virtual host</h1>"));
+    assert!(get_with_host(handle.port().unwrap(), "localhost").contains("<h1>Test</h1>"));
+}
+
+fn certificate_for(port: u16, server_name: &str) -> String {
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stream = builder.build().connect(server_name, stream).unwrap();
+    let cert = stream.ssl().peer_certificate().unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", server_name).unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    let common_name = cert.subject_name().entries_by_nid(nid::COMMONNAME).next().unwrap();
+    common_name.data().as_utf8().unwrap().to_string()
+}
+
+#[test]
+fn certificates_are_selected_by_server_name() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key"))
+        .virtual_host(VirtualHost::new("test_resources/vhost")
+            .name("example.com")
+            .name("*.example.com")
+            .tls(TlsConfig::new("test_resources/tls/example.pem", "test_resources/tls/example.key")))
+        .start()
+        .unwrap();
+
+    assert_eq!("example.com", certificate_for(handle.port().unwrap(), "www.example.com"));
+    assert_eq!("localhost", certificate_for(handle.port().unwrap(), "localhost"));
+    assert_eq!("localhost", certificate_for(handle.port().unwrap(), "other.test"));
+}
+
+#[test]
+fn hosts_without_a_certificate_are_reported() {
+    let result = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key"))
+        .virtual_host(VirtualHost::new("test_resources/vhost").name("vhost.test"))
+        .start();
+    match result {
+        Err(ServerError::Tls(message)) => assert!(message.contains("vhost.test")),
+        _ => panic!("Expected a TLS error")
+    }
+}
+
+#[test]
+fn zero_sizes_are_reported() {
+    let builder = || ServerBuilder::new("test_resources").listen("127.0.0.1:0".parse().unwrap());
+    let builders = [
+        ("threads", builder().threads(0)),
+        ("queue_size", builder().queue_size(0)),
+        ("file_cache", builder().file_cache(0))
+    ];
+    for (name, builder) in builders {
+        match builder.start() {
+            Err(ServerError::Io(e)) => assert_eq!(format!("{} must be at least 1", name), e.to_string()),
+            _ => panic!("Expected an error for {}", name)
+        }
+    }
+}
+
+#[test]
+fn certificates_are_reloaded_from_their_files() {
+    let dir = env::temp_dir().join(format!("rust-http2-server-reload-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::copy("test_resources/tls/localhost.pem", &cert).unwrap();
+    fs::copy("test_resources/tls/localhost.key", &key).unwrap();
+
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new(&cert, &key))
+        .start()
+        .unwrap();
+    assert_eq!("localhost", certificate_for(handle.port().unwrap(), "localhost"));
+
+    fs::write(&cert, "not a certificate").unwrap();
+    assert!(handle.reload_tls().is_err());
+    assert_eq!("localhost", certificate_for(handle.port().unwrap(), "localhost"));
+
+    fs::copy("test_resources/tls/example.pem", &cert).unwrap();
+    fs::copy("test_resources/tls/example.key", &key).unwrap();
+    handle.reload_tls().unwrap();
+    assert_eq!("example.com", certificate_for(handle.port().unwrap(), "localhost"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn verified_get(port: u16, server_name: &str) -> String {
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_ca_file("test_resources/tls/ca.pem").unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stream = builder.build().connect(server_name, stream).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", server_name).unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[test]
+fn intermediates_are_sent_to_clients() {
+    let bundle = TlsConfig::new("test_resources/tls/chain.pem", "test_resources/tls/chain.key")
+        .passphrase_file("test_resources/tls/chain.pass");
+    let archive = TlsConfig::pkcs12("test_resources/tls/chain.p12")
+        .passphrase_file("test_resources/tls/chain.pass");
+    for config in [bundle, archive] {
+        let handle = ServerBuilder::new("test_resources")
+            .listen("127.0.0.1:0".parse().unwrap())
+            .tls(config)
+            .start()
+            .unwrap();
+        assert!(verified_get(handle.port().unwrap(), "chain.test").starts_with("HTTP/1.1 200"));
+    }
+}
+
+/// Connects offering `session` to resume, and returns whether it was, with the session to
+/// offer next time.
+fn resume(port: u16, session: Option<&SslSession>) -> (bool, SslSession) {
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+    builder.builder_mut().clear_options(SSL_OP_NO_TICKET);
+    let mut config = builder.build().configure().unwrap();
+    if let Some(session) = session {
+        unsafe { config.ssl_mut().set_session(session).unwrap() };
+    }
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stream = config.connect("localhost", stream).unwrap();
+    // A session not shut down cleanly can no longer be resumed, so the connection is kept
+    // alive and closed by the client.
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = [0; 4096];
+    assert!(stream.read(&mut response).unwrap() > 0);
+    let session = stream.ssl().session().unwrap().to_owned();
+    let _ = stream.shutdown();
+    (stream.ssl().session_reused(), session)
+}
+
+#[test]
+fn tickets_resume_sessions_across_one_key_rotation() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key")
+            .session_tickets(Duration::from_secs(1)))
+        .start()
+        .unwrap();
+
+    // Clients use a TLS 1.3 session once, so each resumption offers the latest.
+    let (_, first) = resume(handle.port().unwrap(), None);
+    std::thread::sleep(Duration::from_millis(1500));
+    let (resumed, second) = resume(handle.port().unwrap(), Some(&first));
+    assert!(resumed);
+    std::thread::sleep(Duration::from_millis(2000));
+    assert!(!resume(handle.port().unwrap(), Some(&second)).0);
+}
+
+#[test]
+fn reloads_keep_the_session_ticket_keys() {
+    let builder = || ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key")
+            .session_tickets(Duration::from_secs(3600)));
+    let handle = builder().start().unwrap();
+
+    let (_, session) = resume(handle.port().unwrap(), None);
+    handle.reload(builder()).unwrap();
+    assert!(resume(handle.port().unwrap(), Some(&session)).0);
+}
+
+struct WhoAmI;
+
+impl Handler for WhoAmI {
+    fn handle(&self, request: &Request) -> Option<Response> {
+        let body = match request.client_cert {
+            Some(ref cert) => format!("{} {}", cert.subject, cert.alt_names.join(",")),
+            None => "anonymous".to_string()
+        };
+        Some(Response::new(200, "OK").with_body(body))
+    }
+}
+
+fn start_mutual_tls(mode: ClientAuthMode) -> ServerHandle {
+    let client_auth = ClientAuth::new(mode, "test_resources/tls/client-ca.pem")
+        .crl_file("test_resources/tls/client-ca.crl");
+    ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key").client_auth(client_auth))
+        .handler(WhoAmI)
+        .start()
+        .unwrap()
+}
+
+/// The response, or an empty string if the server refused the client.
+fn get_as_client(port: u16, client: Option<&str>) -> String {
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+    if let Some(name) = client {
+        let cert = format!("test_resources/tls/{}.pem", name);
+        let key = format!("test_resources/tls/{}.key", name);
+        builder.builder_mut().set_certificate_file(&cert, X509_FILETYPE_PEM).unwrap();
+        builder.builder_mut().set_private_key_file(&key, X509_FILETYPE_PEM).unwrap();
+    }
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stream = match builder.build().connect("localhost", stream) {
+        Ok(stream) => stream,
+        Err(_) => return String::new()
+    };
+    let _ = write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[test]
+fn client_certificates_are_verified() {
+    let handle = start_mutual_tls(ClientAuthMode::Required);
+
+    let response = get_as_client(handle.port().unwrap(), Some("client"));
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("CN=client.internal,O=Test Services client.internal"));
+    assert_eq!("", get_as_client(handle.port().unwrap(), Some("revoked")));
+    assert_eq!("", get_as_client(handle.port().unwrap(), None));
+}
+
+#[test]
+fn client_certificates_can_be_optional() {
+    let handle = start_mutual_tls(ClientAuthMode::Optional);
+
+    assert!(get_as_client(handle.port().unwrap(), None).ends_with("anonymous"));
+    assert!(get_as_client(handle.port().unwrap(), Some("client")).ends_with("client.internal"));
+    assert_eq!("", get_as_client(handle.port().unwrap(), Some("revoked")));
+}
+
+extern "C" {
+    fn SSL_CTX_ctrl(ctx: *mut c_void, cmd: i32, larg: c_long, parg: *mut c_void) -> c_long;
+}
+
+const SSL_CTRL_SET_MAX_PROTO_VERSION: i32 = 124;
+const TLS1_2_VERSION: c_long = 0x0303;
+
+/// Connects with TLS 1.3 turned off and returns the negotiated cipher, if the handshake succeeds.
+fn tls12_cipher(port: u16, ciphers: &str) -> Option<String> {
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+    unsafe {
+        SSL_CTX_ctrl(builder.builder_mut().as_ptr() as *mut c_void, SSL_CTRL_SET_MAX_PROTO_VERSION, TLS1_2_VERSION, ::std::ptr::null_mut());
+    }
+    builder.builder_mut().set_cipher_list(ciphers).unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stream = builder.build().connect("localhost", stream).ok()?;
+    let cipher = stream.ssl().current_cipher().map(|cipher| cipher.name().to_string());
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    cipher
+}
+
+#[test]
+fn cipher_suites_are_restricted() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key")
+            .ciphers("ECDHE-RSA-AES128-GCM-SHA256"))
+        .start()
+        .unwrap();
+
+    let offered = "ECDHE-RSA-AES256-GCM-SHA384:ECDHE-RSA-AES128-GCM-SHA256";
+    assert_eq!(Some("ECDHE-RSA-AES128-GCM-SHA256".to_string()), tls12_cipher(handle.port().unwrap(), offered));
+    assert_eq!(None, tls12_cipher(handle.port().unwrap(), "ECDHE-RSA-AES256-GCM-SHA384"));
+}
+
+#[test]
+fn older_protocol_versions_are_refused() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key")
+            .min_version(TlsVersion::Tls13))
+        .start()
+        .unwrap();
+
+    assert_eq!(None, tls12_cipher(handle.port().unwrap(), "ECDHE-RSA-AES128-GCM-SHA256"));
+}
+
+/// The OCSP response stapled to a handshake asking for one.
+fn stapled_response(port: u16) -> Option<Vec<u8>> {
+    let stapled = Arc::new(Mutex::new(None));
+    let mut context = SslContext::builder(SslMethod::tls()).unwrap();
+    context.set_verify(SSL_VERIFY_NONE);
+    let seen = stapled.clone();
+    context.set_status_callback(move |ssl| {
+        *seen.lock().unwrap() = ssl.ocsp_status().map(|response| response.to_vec());
+        Ok(true)
+    }).unwrap();
+    let mut ssl = Ssl::new(&context.build()).unwrap();
+    ssl.set_hostname("chain.test").unwrap();
+    ssl.set_status_type(STATUS_TYPE_OCSP).unwrap();
+    let mut stream = ssl.connect(TcpStream::connect(("127.0.0.1", port)).unwrap()).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: chain.test\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    let stapled = stapled.lock().unwrap().clone();
+    stapled
+}
+
+#[test]
+fn ocsp_responses_are_stapled() {
+    let config = TlsConfig::new("test_resources/tls/chain.pem", "test_resources/tls/chain.key")
+        .passphrase_file("test_resources/tls/chain.pass");
+    let stapling = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(config.clone().ocsp_response("test_resources/tls/chain.ocsp"))
+        .start()
+        .unwrap();
+    let plain = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(config)
+        .start()
+        .unwrap();
+
+    assert_eq!(Some(fs::read("test_resources/tls/chain.ocsp").unwrap()), stapled_response(stapling.port().unwrap()));
+    assert_eq!(None, stapled_response(plain.port().unwrap()));
+}
+
+fn plain_get(port: u16, target: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n", target, port).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn unverified_get(port: u16) -> String {
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut stream = builder.build().connect("localhost", stream).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[test]
+fn plain_http_is_redirected_to_https() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key"))
+        .redirect_http("127.0.0.1:0".parse().unwrap(), HttpsRedirect::new().status(RedirectStatus::PermanentRedirect).except("/multi"))
+        .hsts(Hsts::new(Duration::from_secs(86400)).include_subdomains())
+        .start()
+        .unwrap();
+    let redirect_port = handle.redirect_addr.unwrap().port();
+
+    let redirected = plain_get(redirect_port, "/index.html?x=1");
+    assert!(redirected.starts_with("HTTP/1.1 308 Permanent Redirect\n"));
+    assert!(redirected.contains(&format!("Location: https://localhost:{}/index.html?x=1\n", handle.port().unwrap())));
+    assert!(!redirected.contains("Strict-Transport-Security"));
+
+    let excepted = plain_get(redirect_port, "/multi.html");
+    assert!(excepted.starts_with("HTTP/1.1 200 OK\n"));
+    assert!(!excepted.contains("Strict-Transport-Security"));
+
+    let secure = unverified_get(handle.port().unwrap());
+    assert!(secure.starts_with("HTTP/1.1 200 OK\n"));
+    assert!(secure.contains("Strict-Transport-Security: max-age=86400; includeSubDomains\n"));
+}
+
+#[test]
+fn development_certificates_verify_against_their_ca() {
+    let certificates = DevCertificates::generate(&["localhost", "127.0.0.1"]).unwrap();
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(certificates.tls_config())
+        .start()
+        .unwrap();
+
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().cert_store_mut().add_cert(X509::from_pem(&certificates.ca_cert).unwrap()).unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+    let mut stream = builder.build().connect("localhost", stream).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\n"));
+}
+
+#[test]
+fn listens_on_every_address() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .also_listen("[::1]:0".parse().unwrap())
+        .ipv6_only(true)
+        .start()
+        .unwrap();
+
+    assert_eq!(2, handle.addrs.len());
+    assert_eq!("127.0.0.1".parse::<IpAddr>().unwrap(), handle.addrs[0].ip());
+    assert_eq!("::1".parse::<IpAddr>().unwrap(), handle.addrs[1].ip());
+    assert_eq!(handle.addrs[0].port(), handle.port().unwrap());
+    for addr in &handle.addrs {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\n"));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn serves_on_a_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    let path = env::temp_dir().join(format!("rust-http2-server-test-{}.sock", std::process::id()));
+    let handle = ServerBuilder::new("test_resources")
+        .listen_unix(UnixSocket::path(&path).mode(0o600))
+        .start()
+        .unwrap();
+
+    assert!(handle.addrs.is_empty());
+    assert_eq!(None, handle.port());
+    assert_eq!(0o600, fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+    let mut stream = UnixStream::connect(&path).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\n"));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn shutdown_stops_accepting_and_cuts_off_idle_connections() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .thread_per_connection()
+        .start()
+        .unwrap();
+    let port = handle.port().unwrap();
+
+    let mut idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(idle, "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut buf = [0; 16];
+    idle.read_exact(&mut buf).unwrap();
+    assert_eq!(b"HTTP/1.1 200 OK\n", &buf);
+
+    assert!(!handle.shutdown(Duration::from_millis(300)));
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn shutdown_closes_idle_connections_at_once() {
+    let handle = start_server();
+    let port = handle.port().unwrap();
+
+    let mut idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(idle, "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut buf = [0; 16];
+    idle.read_exact(&mut buf).unwrap();
+    assert_eq!(b"HTTP/1.1 200 OK\n", &buf);
+
+    assert!(handle.shutdown(Duration::from_secs(5)));
+    let mut rest = vec![];
+    idle.read_to_end(&mut rest).unwrap();
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn shutdown_lets_requests_finish() {
+    let handle = start_server();
+    let port = handle.port().unwrap();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(handle.shutdown(Duration::from_secs(5)));
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn reload_serves_new_connections_from_the_new_root() {
+    let handle = start_server();
+    let port = handle.port().unwrap();
+    assert!(!plain_get(port, "/index.html").contains("Virtual host"));
+
+    handle.reload(ServerBuilder::new("test_resources/vhost")).unwrap();
+    assert!(plain_get(port, "/index.html").contains("Virtual host"));
+    let tls = ServerBuilder::new("test_resources").tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key"));
+    assert!(handle.reload(tls).is_err());
+    assert!(plain_get(port, "/index.html").contains("Virtual host"));
+}
+
+#[test]
+fn reopened_logs_go_to_the_new_file() {
+    let dir = env::temp_dir().join(format!("rust-http2-server-reopen-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("access.log");
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .access_log(LogTarget::File(log.clone()))
+        .start()
+        .unwrap();
+    let port = handle.port().unwrap();
+    plain_get(port, "/index.html");
+    fs::rename(&log, dir.join("access.log.1")).unwrap();
+
+    handle.reopen_logs();
+    plain_get(port, "/multi.html");
+    let mut logged = String::new();
+    for _ in 0..50 {
+        logged = fs::read_to_string(&log).unwrap_or_default();
+        if !logged.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(logged.contains("/multi.html"));
+    assert!(!logged.contains("/index.html"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A server with a thread per connection, its one worker held up by an idle connection and one
+/// more connection waiting.
+fn overloaded_server(overload: Overload) -> (ServerHandle, TcpStream, TcpStream) {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .thread_per_connection()
+        .threads(1)
+        .max_threads(1)
+        .queue_size(1)
+        .overload(overload)
+        .start()
+        .unwrap();
+    let busy = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+    while handle.pool_metrics().busy == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let waiting = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+    while handle.pool_metrics().queued == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    (handle, busy, waiting)
+}
+
+#[test]
+fn full_queue_rejects_with_retry_after() {
+    let (handle, busy, waiting) = overloaded_server(Overload::Reject(Duration::from_secs(3)));
+    let response = plain_get(handle.port().unwrap(), "/index.html");
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert!(response.contains("Retry-After: 3"));
+
+    let metrics = handle.pool_metrics();
+    assert_eq!((1, 1, 1, 1), (metrics.workers, metrics.busy, metrics.queued, metrics.rejected));
+    drop((busy, waiting));
+}
+
+#[test]
+fn full_queue_sheds_the_oldest_connection() {
+    let (handle, busy, mut waiting) = overloaded_server(Overload::ShedOldest);
+    let mut newest = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(0, waiting.read(&mut buf).unwrap());
+    assert_eq!(1, handle.pool_metrics().shed);
+
+    drop(busy);
+    write!(newest, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    newest.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+}
+
+struct Panicky;
+
+impl Handler for Panicky {
+    fn handle(&self, request: &Request) -> Option<Response> {
+        if request.target == "/panic" {
+            panic!("handler failed");
+        }
+        None
+    }
+}
+
+#[test]
+fn panicking_handlers_do_not_take_workers_down() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .threads(1)
+        .max_threads(1)
+        .handler(Panicky)
+        .start()
+        .unwrap();
+    for _ in 0..3 {
+        assert_eq!("", plain_get(handle.port().unwrap(), "/panic"));
+    }
+    assert!(plain_get(handle.port().unwrap(), "/index.html").starts_with("HTTP/1.1 200"));
+    assert_eq!(3, handle.pool_metrics().panics);
+}
+
+#[test]
+fn clients_going_away_do_not_take_workers_down() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .threads(1)
+        .max_threads(1)
+        .start()
+        .unwrap();
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+        write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+    }
+    assert!(plain_get(handle.port().unwrap(), "/index.html").starts_with("HTTP/1.1 200"));
+    assert_eq!(0, handle.pool_metrics().panics);
+}
+
+#[test]
+fn workers_grow_for_idle_keep_alive_connections_and_shrink_back() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .thread_per_connection()
+        .threads(1)
+        .max_threads(3)
+        .idle_timeout(Duration::from_millis(200))
+        .start()
+        .unwrap();
+    let idle: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap()).collect();
+    assert!(plain_get(handle.port().unwrap(), "/index.html").starts_with("HTTP/1.1 200"));
+    let metrics = handle.pool_metrics();
+    assert_eq!((3, 3, 1, 3), (metrics.workers, metrics.peak_workers, metrics.min_workers, metrics.max_workers));
+
+    drop(idle);
+    let mut workers = handle.pool_metrics().workers;
+    for _ in 0..100 {
+        if workers == 1 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        workers = handle.pool_metrics().workers;
+    }
+    assert_eq!(1, workers);
+}
+
+#[test]
+fn idle_keep_alive_connections_hold_no_workers() {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .threads(1)
+        .max_threads(1)
+        .start()
+        .unwrap();
+    let mut idle: Vec<TcpStream> = (0..100).map(|_| TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap()).collect();
+    write!(idle[0], "GET /index.html HTTP/1.1\r\nHo").unwrap();
+    for _ in 0..3 {
+        assert!(plain_get(handle.port().unwrap(), "/index.html").starts_with("HTTP/1.1 200"));
+    }
+    assert_eq!(1, handle.pool_metrics().workers);
+    drop(idle);
+}
+
+#[test]
+fn pipelined_requests_arriving_in_pieces_are_answered_in_order() {
+    let handle = start_server();
+    let mut stream = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+    let requests = "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\nGET /multi.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    for piece in requests.as_bytes().chunks(7) {
+        stream.write_all(piece).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+    }
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert_eq!(2, response.matches("HTTP/1.1 200 OK").count());
+    assert!(response.find("<h1>Test</h1>").unwrap() < response.find("<h1>Success</h1>").unwrap());
+}
+
+/// Holds up requests for `/slow` for as long as its lock is held.
+struct Gate(Arc<Mutex<()>>);
+
+impl Handler for Gate {
+    fn handle(&self, request: &Request) -> Option<Response> {
+        if request.target == "/slow" {
+            drop(self.0.lock().unwrap());
+        }
+        None
+    }
+}
+
+/// A server with its one worker held up answering a request and one more request waiting, and
+/// the connections they came on.
+fn busy_server(overload: Overload, gate: &Arc<Mutex<()>>) -> (ServerHandle, TcpStream, TcpStream) {
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .threads(1)
+        .max_threads(1)
+        .queue_size(1)
+        .overload(overload)
+        .handler(Gate(gate.clone()))
+        .start()
+        .unwrap();
+    let slow = || {
+        let mut stream = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+        write!(stream, "GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        stream
+    };
+    let answering = slow();
+    while handle.pool_metrics().busy == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let waiting = slow();
+    while handle.pool_metrics().queued == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    (handle, answering, waiting)
+}
+
+#[test]
+fn busy_workers_turn_requests_away_with_retry_after() {
+    let gate = Arc::new(Mutex::new(()));
+    let closed = gate.lock().unwrap();
+    let (handle, mut answering, mut waiting) = busy_server(Overload::Reject(Duration::from_secs(3)), &gate);
+    let response = plain_get(handle.port().unwrap(), "/index.html");
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert!(response.contains("Retry-After: 3"));
+    assert_eq!(1, handle.pool_metrics().rejected);
+
+    drop(closed);
+    for stream in [&mut answering, &mut waiting].iter_mut() {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+}
+
+#[test]
+fn busy_workers_shed_the_oldest_waiting_request() {
+    let gate = Arc::new(Mutex::new(()));
+    let closed = gate.lock().unwrap();
+    let (handle, mut answering, mut waiting) = busy_server(Overload::ShedOldest, &gate);
+    let mut newest = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+    write!(newest, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    waiting.read_to_string(&mut response).unwrap();
+    assert_eq!("", response);
+    assert_eq!(1, handle.pool_metrics().shed);
+
+    drop(closed);
+    answering.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 404"));
+    let mut response = String::new();
+    newest.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+}
+
+#[test]
+fn large_bodies_are_sent_in_full() {
+    let dir = env::temp_dir().join(format!("rust-http2-server-large-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let body: Vec<u8> = (0..3 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("large.bin"), &body).unwrap();
+    let request = "GET /large.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+    let plain = ServerBuilder::new(&dir)
+        .listen("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap();
+    let mut stream = TcpStream::connect(("127.0.0.1", plain.port().unwrap())).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.ends_with(&body));
+
+    let tls = ServerBuilder::new(&dir)
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key"))
+        .start()
+        .unwrap();
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+    let stream = TcpStream::connect(("127.0.0.1", tls.port().unwrap())).unwrap();
+    let mut stream = builder.build().connect("localhost", stream).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    assert!(response.ends_with(&body));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn request_bodies_are_never_taken_for_the_next_request() {
+    let threaded = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .thread_per_connection()
+        .start()
+        .unwrap();
+    let event_loop = start_server();
+    for port in [threaded.port().unwrap(), event_loop.port().unwrap()] {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let smuggled = "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        write!(stream, "POST /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", smuggled.len(), smuggled).unwrap();
+        write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response);
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+        assert_eq!(1, response.matches("HTTP/1.1 ").count(), "{}", response);
+    }
+}
+
+fn get_with_headers(port: u16, target: &str, headers: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n", target, headers).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn cached_files_are_tagged_and_replaced_once_changed() {
+    let dir = env::temp_dir().join(format!("rust-http2-server-cache-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("style.css"), "body {}").unwrap();
+    let handle = ServerBuilder::new(&dir)
+        .listen("127.0.0.1:0".parse().unwrap())
+        .file_cache(1024 * 1024)
+        .start()
+        .unwrap();
+
+    let response = get_with_headers(handle.port().unwrap(), "/style.css", "");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.lines().any(|line| line == "Content-Type: text/css; charset=utf-8"));
+    assert!(response.ends_with("\nbody {}"));
+    let etag = response.lines().find(|line| line.starts_with("ETag: ")).unwrap()[6..].to_string();
+    let response = get_with_headers(handle.port().unwrap(), "/style.css", &format!("If-None-Match: {}\r\n", etag));
+    assert!(response.starts_with("HTTP/1.1 304"));
+
+    fs::write(dir.join("style.css"), "body { color: red }").unwrap();
+    std::thread::sleep(Duration::from_millis(1100));
+    let response = get_with_headers(handle.port().unwrap(), "/style.css", &format!("If-None-Match: {}\r\n", etag));
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("\nbody { color: red }"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn mapped_files_are_sent_in_full_to_concurrent_clients() {
+    let dir = env::temp_dir().join(format!("rust-http2-server-mapped-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let body: Vec<u8> = (0..3 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("large.bin"), &body).unwrap();
+    let request = "GET /large.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+    let threaded = ServerBuilder::new(&dir)
+        .listen("127.0.0.1:0".parse().unwrap())
+        .mmap_threshold(1024 * 1024)
+        .thread_per_connection()
+        .start()
+        .unwrap();
+    let plain = ServerBuilder::new(&dir)
+        .listen("127.0.0.1:0".parse().unwrap())
+        .mmap_threshold(1024 * 1024)
+        .start()
+        .unwrap();
+    let clients: Vec<_> = [threaded.port().unwrap(), plain.port().unwrap()].iter().cycle().take(6).map(|&port| {
+        std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = vec![];
+            stream.read_to_end(&mut response).unwrap();
+            response
+        })
+    }).collect();
+    for client in clients {
+        assert!(client.join().unwrap().ends_with(&body));
+    }
+
+    let tls = ServerBuilder::new(&dir)
+        .listen("127.0.0.1:0".parse().unwrap())
+        .mmap_threshold(1024 * 1024)
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key"))
+        .start()
+        .unwrap();
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+    let stream = TcpStream::connect(("127.0.0.1", tls.port().unwrap())).unwrap();
+    let mut stream = builder.build().connect("localhost", stream).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    assert!(response.ends_with(&body));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn protected_paths_cannot_be_reached_by_another_spelling() {
+    let users = vec![("alice".to_string(), openssl::sha::sha256(b"secret"))];
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .location(Location::new("/vhost/").basic_auth("Private", users))
+        .start()
+        .unwrap();
+
+    for target in &["/vhost/index.html", "//vhost/index.html", "/./vhost/index.html", "/tls/../vhost/index.html"] {
+        let response = get_with_headers(handle.port().unwrap(), target, "");
+        assert!(response.starts_with("HTTP/1.1 401"), "{} answered {}", target, response);
+    }
+    let response = get_with_headers(handle.port().unwrap(), "/../test_resources/index.html", "");
+    assert!(response.starts_with("HTTP/1.1 400"));
+}
+
+#[test]
+fn requests_for_another_host_than_the_server_name_are_misdirected() {
+    let client_auth = ClientAuth::new(ClientAuthMode::Required, "test_resources/tls/client-ca.pem");
+    let handle = ServerBuilder::new("test_resources")
+        .listen("127.0.0.1:0".parse().unwrap())
+        .tls(TlsConfig::new("test_resources/tls/localhost.pem", "test_resources/tls/localhost.key"))
+        .virtual_host(VirtualHost::new("test_resources/vhost")
+            .name("example.com")
+            .tls(TlsConfig::new("test_resources/tls/example.pem", "test_resources/tls/example.key").client_auth(client_auth)))
+        .start()
+        .unwrap();
+
+    let get = |host: &str| {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+        builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+        let stream = TcpStream::connect(("127.0.0.1", handle.port().unwrap())).unwrap();
+        let mut stream = builder.build().connect("localhost", stream).unwrap();
+        write!(stream, "GET /index.html HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host).unwrap();
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).to_string()
+    };
+    assert!(get("localhost").starts_with("HTTP/1.1 200"));
+    assert!(get("example.com").starts_with("HTTP/1.1 421"));
+}
+
+fn serves(port: u16) -> bool {
+    TcpStream::connect(("127.0.0.1", port)).ok().is_some_and(|mut stream| {
+        let _ = write!(stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response.starts_with("HTTP/1.1 200")
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn upgrades_start_the_binary_renamed_into_place() {
+    use std::process::{Command, Stdio};
+
+    let dir = env::temp_dir().join(format!("rust-http2-server-upgrade-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let binary = dir.join("server");
+    fs::copy(env!("CARGO_BIN_EXE_rust_https_server"), &binary).unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let output = dir.join("output.txt");
+    let mut old = Command::new(&binary)
+        .args(["-a", "127.0.0.1", "-p", &port.to_string(), "test_resources"])
+        .stdout(Stdio::from(fs::File::create(&output).unwrap()))
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    while !serves(port) && started.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // a deploy: the new binary is written next to the old one and renamed over it
+    fs::copy(env!("CARGO_BIN_EXE_rust_https_server"), dir.join("server.new")).unwrap();
+    fs::rename(dir.join("server.new"), &binary).unwrap();
+    assert!(Command::new("kill").args(["-USR2", &old.id().to_string()]).status().unwrap().success());
+    let stopped = Instant::now();
+    while old.try_wait().unwrap().is_none() && stopped.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let upgraded = old.try_wait().unwrap().is_some();
+    if !upgraded {
+        let _ = old.kill();
+    }
+    let printed = fs::read_to_string(&output).unwrap();
+    let new_pid = printed.lines()
+        .find_map(|line| line.strip_prefix("process ").and_then(|rest| rest.split(' ').next()).map(str::to_string));
+    let served = serves(port);
+    if let Some(ref pid) = new_pid {
+        let _ = Command::new("kill").arg(pid).status();
+    }
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(upgraded, "{}", printed);
+    assert!(new_pid.is_some(), "{}", printed);
+    assert!(served);
+}
+
+#[cfg(unix)]
+#[test]
+fn reloads_keep_the_development_certificate() {
+    use std::process::{Command, Stdio};
+
+    let certificate = |port: u16| {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+        builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+        let stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+        let stream = builder.build().connect("localhost", stream).ok()?;
+        stream.ssl().peer_certificate().map(|cert| cert.to_der().unwrap())
+    };
+    let dir = env::temp_dir().join(format!("rust-http2-server-dev-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let output = dir.join("output.txt");
+    let mut server = Command::new(env!("CARGO_BIN_EXE_rust_https_server"))
+        .args(["-a", "127.0.0.1", "-p", &port.to_string(), "--dev-tls", "test_resources"])
+        .stdout(Stdio::from(fs::File::create(&output).unwrap()))
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    let mut before = None;
+    while before.is_none() && started.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(50));
+        before = certificate(port);
+    }
+
+    assert!(Command::new("kill").args(["-HUP", &server.id().to_string()]).status().unwrap().success());
+    let reloaded = Instant::now();
+    while !fs::read_to_string(&output).unwrap().contains("reloading") && reloaded.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    std::thread::sleep(Duration::from_millis(500));
+    let after = certificate(port);
+    let _ = server.kill();
+    let _ = server.wait();
+    let printed = fs::read_to_string(&output).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(before.is_some(), "{}", printed);
+    assert_eq!(before, after, "{}", printed);
+}